@@ -12,6 +12,7 @@ use std::time::{Duration, Instant};
 use tempfile::NamedTempFile;
 
 use futures::sync::mpsc::unbounded;
+use librespot_core::cache::{PartialFile, ReadSeek};
 use librespot_core::channel::{Channel, ChannelData, ChannelError, ChannelHeaders};
 use librespot_core::session::Session;
 use librespot_core::spotify_id::FileId;
@@ -84,12 +85,12 @@ const MAX_PREFETCH_REQUESTS: usize = 4;
 // pre-fetch request is only sent if less than MAX_PREFETCH_REQUESTS are pending.
 
 pub enum AudioFile {
-    Cached(fs::File),
+    Cached(Box<dyn ReadSeek>, usize),
     Streaming(AudioFileStreaming),
 }
 
 pub enum AudioFileOpen {
-    Cached(Option<fs::File>),
+    Cached(Option<(Box<dyn ReadSeek>, usize)>),
     Streaming(AudioFileOpenStreaming),
 }
 
@@ -102,6 +103,7 @@ pub struct AudioFileOpenStreaming {
     file_id: FileId,
     complete_tx: Option<oneshot::Sender<NamedTempFile>>,
     streaming_data_rate: usize,
+    partial: Option<PartialFile>,
 }
 
 enum StreamLoaderCommand {
@@ -161,6 +163,45 @@ impl StreamLoaderController {
         }
     }
 
+    // The amount of data downloaded ahead of the current read position.
+    pub fn buffered_bytes_ahead(&self) -> usize {
+        if let Some(ref shared) = self.stream_shared {
+            let read_position = shared.read_position.load(atomic::Ordering::Relaxed);
+            let download_status = shared.download_status.lock().unwrap();
+            download_status
+                .downloaded
+                .contained_length_from_value(read_position)
+        } else {
+            self.len()
+        }
+    }
+
+    // The playback time covered by the data downloaded ahead of the current read position,
+    // or None for cached files, which are always fully available.
+    pub fn buffered_ms_ahead(&self) -> Option<usize> {
+        self.stream_shared
+            .as_ref()
+            .map(|shared| 1000 * self.buffered_bytes_ahead() / shared.stream_data_rate)
+    }
+
+    // The measured download rate in bytes per second.
+    pub fn download_rate(&self) -> usize {
+        if let Some(ref shared) = self.stream_shared {
+            shared.download_rate.load(atomic::Ordering::Relaxed)
+        } else {
+            0
+        }
+    }
+
+    // The number of times reading in stream mode had to wait for data to be downloaded.
+    pub fn underrun_count(&self) -> usize {
+        if let Some(ref shared) = self.stream_shared {
+            shared.underrun_count.load(atomic::Ordering::Relaxed)
+        } else {
+            0
+        }
+    }
+
     fn send_stream_loader_command(&mut self, command: StreamLoaderCommand) {
         if let Some(ref mut channel) = self.channel_tx {
             // ignore the error in case the channel has been closed already.
@@ -285,6 +326,8 @@ struct AudioFileShared {
     download_strategy: Mutex<DownloadStrategy>,
     number_of_open_requests: AtomicUsize,
     ping_time_ms: AtomicUsize,
+    download_rate: AtomicUsize,
+    underrun_count: AtomicUsize,
     read_position: AtomicUsize,
 }
 
@@ -302,10 +345,23 @@ impl AudioFileOpenStreaming {
             download_strategy: Mutex::new(DownloadStrategy::RandomAccess()), // start with random access mode until someone tells us otherwise
             number_of_open_requests: AtomicUsize::new(0),
             ping_time_ms: AtomicUsize::new(0),
+            download_rate: AtomicUsize::new(0),
+            underrun_count: AtomicUsize::new(0),
             read_position: AtomicUsize::new(0),
         });
 
         let mut write_file = NamedTempFile::new().unwrap();
+
+        if let Some(partial) = self.partial.take() {
+            if let Some(downloaded) = resume_partial(partial, size, write_file.as_file_mut()) {
+                debug!(
+                    "Resuming download of {}, downloaded ranges: {}",
+                    self.file_id, downloaded
+                );
+                shared.download_status.lock().unwrap().downloaded = downloaded;
+            }
+        }
+
         write_file.as_file().set_len(size as u64).unwrap();
         write_file.seek(SeekFrom::Start(0)).unwrap();
 
@@ -342,6 +398,36 @@ impl AudioFileOpenStreaming {
     }
 }
 
+// Copies a partial download into the file and returns the ranges of it which are downloaded,
+// if it was a download of a file of the same size.
+fn resume_partial(
+    partial: PartialFile,
+    size: usize,
+    write_file: &mut fs::File,
+) -> Option<RangeSet> {
+    let (mut partial_file, ranges) = partial;
+    let partial_size = partial_file.seek(SeekFrom::End(0)).ok()?;
+    if partial_size != size as u64 {
+        return None;
+    }
+    partial_file.seek(SeekFrom::Start(0)).ok()?;
+    io::copy(&mut partial_file, write_file).ok()?;
+
+    let mut downloaded = RangeSet::new();
+    for (start, length) in ranges {
+        downloaded.add_range(&Range::new(start, length));
+    }
+    Some(downloaded)
+}
+
+// The downloaded ranges as (offset, length) pairs, to be saved with a partial download
+fn range_pairs(ranges: &RangeSet) -> Vec<(usize, usize)> {
+    ranges
+        .iter()
+        .map(|range| (range.start, range.length))
+        .collect()
+}
+
 impl Future for AudioFileOpen {
     type Item = AudioFile;
     type Error = ChannelError;
@@ -353,8 +439,8 @@ impl Future for AudioFileOpen {
                 Ok(Async::Ready(AudioFile::Streaming(file)))
             }
             AudioFileOpen::Cached(ref mut file) => {
-                let file = file.take().unwrap();
-                Ok(Async::Ready(AudioFile::Cached(file)))
+                let (file, size) = file.take().unwrap();
+                Ok(Async::Ready(AudioFile::Cached(file, size)))
             }
         }
     }
@@ -387,9 +473,16 @@ impl AudioFile {
     ) -> AudioFileOpen {
         let cache = session.cache().cloned();
 
-        if let Some(file) = cache.as_ref().and_then(|cache| cache.file(file_id)) {
+        if let Some(mut file) = cache.as_ref().and_then(|cache| cache.file(file_id)) {
             debug!("File {} already in cache", file_id);
-            return AudioFileOpen::Cached(Some(file));
+            let size = file.seek(SeekFrom::End(0)).unwrap();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            return AudioFileOpen::Cached(Some((file, size as usize)));
+        }
+
+        let partial = cache.as_ref().and_then(|cache| cache.partial_file(file_id));
+        if partial.is_some() {
+            debug!("Partial download of file {} found in cache", file_id);
         }
 
         debug!("Downloading file {}", file_id);
@@ -422,6 +515,7 @@ impl AudioFile {
 
             complete_tx: Some(complete_tx),
             streaming_data_rate: bytes_per_second,
+            partial,
         };
 
         let session_ = session.clone();
@@ -435,7 +529,13 @@ impl AudioFile {
                         debug!("File {} complete", file_id);
                     }
                 })
-                .or_else(|oneshot::Canceled| Ok(()))
+                .or_else(move |oneshot::Canceled| {
+                    // the download failed or was abandoned
+                    if let Some(cache) = cache {
+                        cache.forget_file_track(file_id);
+                    }
+                    Ok(())
+                })
         });
 
         return AudioFileOpen::Streaming(open);
@@ -450,11 +550,11 @@ impl AudioFile {
                     file_size: stream.shared.file_size,
                 };
             }
-            AudioFile::Cached(ref file) => {
+            AudioFile::Cached(_, size) => {
                 return StreamLoaderController {
                     channel_tx: None,
                     stream_shared: None,
-                    file_size: file.metadata().unwrap().len() as usize,
+                    file_size: *size,
                 };
             }
         }
@@ -901,6 +1001,24 @@ impl AudioFileFetch {
         }
     }
 
+    fn save_partial(&mut self) {
+        // keep what was downloaded so far, so the download can be resumed next time
+        let mut output = match self.output.take() {
+            Some(output) => output,
+            None => return,
+        };
+
+        if let Some(cache) = self.session.cache() {
+            let ranges = range_pairs(&self.shared.download_status.lock().unwrap().downloaded);
+
+            if !ranges.is_empty() {
+                debug!("Saving partial download of {}", self.shared.file_id);
+                output.seek(SeekFrom::Start(0)).unwrap();
+                cache.save_partial_file(self.shared.file_id, &mut output, &ranges);
+            }
+        }
+    }
+
     fn finish(&mut self) {
         let mut output = self.output.take().unwrap();
         let complete_tx = self.complete_tx.take().unwrap();
@@ -915,9 +1033,15 @@ impl Future for AudioFileFetch {
     type Error = ();
 
     fn poll(&mut self) -> Poll<(), ()> {
+        let download_rate = self.session.channel().get_download_rate_estimate();
+        self.shared
+            .download_rate
+            .store(download_rate, atomic::Ordering::Relaxed);
+
         match self.poll_stream_loader_command_rx() {
             Ok(Async::NotReady) => (),
             Ok(Async::Ready(_)) => {
+                self.save_partial();
                 return Ok(Async::Ready(()));
             }
             Err(()) => unreachable!(),
@@ -950,7 +1074,6 @@ impl Future for AudioFileFetch {
 
                 let ping_time_seconds =
                     0.001 * self.shared.ping_time_ms.load(atomic::Ordering::Relaxed) as f64;
-                let download_rate = self.session.channel().get_download_rate_estimate();
 
                 let desired_pending_bytes = max(
                     (PREFETCH_THRESHOLD_FACTOR
@@ -1023,6 +1146,9 @@ impl Read for AudioFileStreaming {
         while !download_status.downloaded.contains(offset) {
             if let DownloadStrategy::Streaming() = *self.shared.download_strategy.lock().unwrap() {
                 if !download_message_printed {
+                    self.shared
+                        .underrun_count
+                        .fetch_add(1, atomic::Ordering::Relaxed);
                     debug!("Stream waiting for download of file position {}. Downloaded ranges: {}. Pending ranges: {}", offset, download_status.downloaded, download_status.requested.minus(&download_status.downloaded));
                     download_message_printed = true;
                 }
@@ -1076,7 +1202,7 @@ impl Seek for AudioFileStreaming {
 impl Read for AudioFile {
     fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
         match *self {
-            AudioFile::Cached(ref mut file) => file.read(output),
+            AudioFile::Cached(ref mut file, _) => file.read(output),
             AudioFile::Streaming(ref mut file) => file.read(output),
         }
     }
@@ -1085,8 +1211,11 @@ impl Read for AudioFile {
 impl Seek for AudioFile {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
         match *self {
-            AudioFile::Cached(ref mut file) => file.seek(pos),
+            AudioFile::Cached(ref mut file, _) => file.seek(pos),
             AudioFile::Streaming(ref mut file) => file.seek(pos),
         }
     }
 }
+
+#[cfg(test)]
+mod tests;
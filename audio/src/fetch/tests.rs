@@ -0,0 +1,86 @@
+use std::sync::Arc;
+
+use super::*;
+use librespot_core::cache::Cache;
+use librespot_core::cache_storage::MemoryStorage;
+
+const FILE_SIZE: usize = 1000;
+
+fn file_id() -> FileId {
+    FileId([1; 20])
+}
+
+// A download of which the given ranges arrived, the rest of the file is zeroed
+fn partial_download(ranges: &[(usize, usize)]) -> (Vec<u8>, RangeSet) {
+    let mut data = vec![0u8; FILE_SIZE];
+    let mut downloaded = RangeSet::new();
+    for &(start, length) in ranges {
+        for (offset, byte) in data.iter_mut().enumerate().skip(start).take(length) {
+            *byte = (offset % 251) as u8 + 1;
+        }
+
+        downloaded.add_range(&Range::new(start, length));
+    }
+    (data, downloaded)
+}
+
+fn resume(cache: &Cache, size: usize) -> Option<(Vec<u8>, RangeSet)> {
+    let partial = cache.partial_file(file_id())?;
+    let mut write_file = tempfile::tempfile().unwrap();
+    let downloaded = resume_partial(partial, size, &mut write_file)?;
+
+    let mut data = Vec::new();
+    write_file.seek(SeekFrom::Start(0)).unwrap();
+    write_file.read_to_end(&mut data).unwrap();
+    Some((data, downloaded))
+}
+
+#[test]
+fn partial_download_resumes_with_downloaded_ranges() {
+    let cache = Cache::with_storage(Arc::new(MemoryStorage::new()), true);
+    let (data, downloaded) = partial_download(&[(0, 100), (300, 250)]);
+
+    cache.save_partial_file(file_id(), &mut &data[..], &range_pairs(&downloaded));
+
+    let (resumed_data, resumed) = resume(&cache, FILE_SIZE).unwrap();
+    assert_eq!(resumed_data, data);
+    assert_eq!(range_pairs(&resumed), vec![(0, 100), (300, 250)]);
+    assert!(resumed.contains(99));
+    assert!(!resumed.contains(100));
+    assert!(resumed.contains(549));
+    assert!(!resumed.contains(550));
+}
+
+#[test]
+fn partial_download_is_replaced_by_later_save() {
+    let cache = Cache::with_storage(Arc::new(MemoryStorage::new()), true);
+    let (data, downloaded) = partial_download(&[(0, 100)]);
+    cache.save_partial_file(file_id(), &mut &data[..], &range_pairs(&downloaded));
+
+    let (data, downloaded) = partial_download(&[(0, 100), (500, 500)]);
+    cache.save_partial_file(file_id(), &mut &data[..], &range_pairs(&downloaded));
+
+    let (resumed_data, resumed) = resume(&cache, FILE_SIZE).unwrap();
+    assert_eq!(resumed_data, data);
+    assert_eq!(range_pairs(&resumed), vec![(0, 100), (500, 500)]);
+}
+
+#[test]
+fn partial_download_of_other_size_is_not_resumed() {
+    let cache = Cache::with_storage(Arc::new(MemoryStorage::new()), true);
+    let (data, downloaded) = partial_download(&[(0, 100)]);
+    cache.save_partial_file(file_id(), &mut &data[..], &range_pairs(&downloaded));
+
+    assert!(resume(&cache, FILE_SIZE + 1).is_none());
+}
+
+#[test]
+fn completed_download_removes_partial_download() {
+    let cache = Cache::with_storage(Arc::new(MemoryStorage::new()), true);
+    let (data, downloaded) = partial_download(&[(0, 100)]);
+    cache.save_partial_file(file_id(), &mut &data[..], &range_pairs(&downloaded));
+
+    cache.save_file(file_id(), &mut &data[..]);
+
+    assert!(cache.partial_file(file_id()).is_none());
+}
@@ -29,6 +29,7 @@ pub use fetch::{
     READ_AHEAD_BEFORE_PLAYBACK_ROUNDTRIPS, READ_AHEAD_BEFORE_PLAYBACK_SECONDS,
     READ_AHEAD_DURING_PLAYBACK_ROUNDTRIPS, READ_AHEAD_DURING_PLAYBACK_SECONDS,
 };
+pub use range_set::Range;
 
 #[cfg(not(any(feature = "with-tremor", feature = "with-vorbis")))]
 pub use crate::lewton_decoder::{VorbisDecoder, VorbisError, VorbisPacket};
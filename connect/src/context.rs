@@ -1,7 +1,74 @@
+use futures::Future;
+use serde;
+use serde::de::DeserializeOwned;
+use serde_json;
+
 use crate::protocol::spirc::TrackRef;
+use librespot_core::mercury::MercuryError;
+use librespot_core::session::Session;
 use librespot_core::spotify_id::SpotifyId;
 
-use serde;
+pub type ContextFuture<T> = Box<dyn Future<Item = T, Error = MercuryError>>;
+
+// Resolves the tracks of a playlist, album or other context uri
+pub fn resolve_context(session: &Session, uri: &str) -> ContextFuture<ResolvedContext> {
+    resolve(session, format!("hm://context-resolve/v1/{}", uri))
+}
+
+// Resolves the first page of tracks of a radio station
+pub fn resolve_station(session: &Session, uri: &str) -> ContextFuture<StationContext> {
+    resolve(session, format!("hm://radio-apollo/v3/stations/{}", uri))
+}
+
+// Resolves the page following a station or an earlier page
+pub fn resolve_next_page(session: &Session, next_page_url: &str) -> ContextFuture<StationContext> {
+    resolve(session, next_page_url.to_owned())
+}
+
+// Resolves a further page of tracks of a playlist, album or other context
+pub fn resolve_page(session: &Session, page_url: &str) -> ContextFuture<PageContext> {
+    resolve(session, page_url.to_owned())
+}
+
+// Resolves the station uri used to continue playback after the given context ends
+pub fn resolve_autoplay_uri(session: &Session, uri: &str) -> ContextFuture<String> {
+    let query_uri = format!("hm://autoplay-enabled/query?uri={}", uri);
+    Box::new(session.mercury().get(query_uri).and_then(|response| {
+        if response.status_code != 200 {
+            warn!("No autoplay_uri found");
+            return Err(MercuryError);
+        }
+        response
+            .payload
+            .first()
+            .and_then(|data| String::from_utf8(data.clone()).ok())
+            .ok_or_else(|| {
+                warn!("Invalid autoplay uri");
+                MercuryError
+            })
+    }))
+}
+
+fn resolve<T>(session: &Session, uri: String) -> ContextFuture<T>
+where
+    T: DeserializeOwned + 'static,
+{
+    Box::new(
+        session
+            .mercury()
+            .get(uri.clone())
+            .and_then(move |response| {
+                let data = response.payload.first().ok_or_else(|| {
+                    error!("Empty payload on context uri <{}>", uri);
+                    MercuryError
+                })?;
+                serde_json::from_slice::<T>(data).map_err(|err| {
+                    error!("Unable to parse context <{}>: {}", uri, err);
+                    MercuryError
+                })
+            }),
+    )
+}
 
 #[derive(Deserialize, Debug)]
 pub struct StationContext {
@@ -20,20 +87,60 @@ pub struct StationContext {
     // pub title: String,
     // #[serde(rename = "titleUri")]
     // pub title_uri: String,
-    // pub related_artists: Vec<ArtistContext>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct PageContext {
+    #[serde(default)]
     pub uri: String,
-    pub next_page_url: String,
-    #[serde(deserialize_with = "deserialize_protobuf_TrackRef")]
+    #[serde(default)]
+    pub next_page_url: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_protobuf_TrackRef_uri")]
     pub tracks: Vec<TrackRef>,
     // Not required for core functionality
     // pub url: String,
     // // pub restrictions:
 }
 
+// Response of hm://context-resolve for a playlist, album or other context uri
+#[derive(Deserialize, Debug)]
+pub struct ResolvedContext {
+    pub uri: String,
+    pub pages: Vec<ResolvedContextPage>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ResolvedContextPage {
+    #[serde(default, deserialize_with = "deserialize_protobuf_TrackRef_uri")]
+    pub tracks: Vec<TrackRef>,
+    // set instead of the tracks for pages which are not loaded yet
+    #[serde(default)]
+    pub page_url: Option<String>,
+    #[serde(default)]
+    pub next_page_url: Option<String>,
+}
+
+impl ResolvedContext {
+    // The tracks of the loaded pages and the url of the first page which still has to be fetched
+    pub fn into_tracks(self) -> (Vec<TrackRef>, Option<String>) {
+        let mut tracks = Vec::new();
+        let mut next_page_url = None;
+        for page in self.pages {
+            if page.tracks.is_empty() && page.page_url.is_some() {
+                return (tracks, page.page_url);
+            }
+            tracks.extend(page.tracks);
+            next_page_url = page.next_page_url;
+        }
+        (tracks, next_page_url.filter(|url| !url.is_empty()))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TrackUriContext {
+    pub uri: String,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct TrackContext {
     #[serde(rename = "original_gid")]
@@ -43,39 +150,47 @@ pub struct TrackContext {
     // Not required for core functionality
     // pub album_uri: String,
     // pub artist_uri: String,
-    // pub metadata: MetadataContext,
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-pub struct ArtistContext {
-    artist_name: String,
-    artist_uri: String,
-    image_uri: String,
-}
+#[allow(non_snake_case)]
+fn deserialize_protobuf_TrackRef<'d, D>(de: D) -> Result<Vec<TrackRef>, D::Error>
+where
+    D: serde::Deserializer<'d>,
+{
+    let v: Vec<TrackContext> = serde::Deserialize::deserialize(de)?;
+    let track_vec = v
+        .iter()
+        .map(|v| {
+            let mut t = TrackRef::new();
+            // Spirc resolves the gid from the uri if it is missing
+            if let Ok(id) = SpotifyId::from_base62(&v.gid) {
+                t.set_gid(id.to_raw().to_vec());
+            }
+            t.set_uri(v.uri.to_owned());
 
-#[derive(Deserialize, Debug)]
-pub struct MetadataContext {
-    album_title: String,
-    artist_name: String,
-    artist_uri: String,
-    image_url: String,
-    title: String,
-    uid: String,
+            t
+        })
+        .collect::<Vec<TrackRef>>();
+
+    Ok(track_vec)
 }
 
 #[allow(non_snake_case)]
-fn deserialize_protobuf_TrackRef<'d, D>(de: D) -> Result<Vec<TrackRef>, D::Error>
+fn deserialize_protobuf_TrackRef_uri<'d, D>(de: D) -> Result<Vec<TrackRef>, D::Error>
 where
     D: serde::Deserializer<'d>,
 {
-    let v: Vec<TrackContext> = serde::Deserialize::deserialize(de)?;
+    let v: Vec<TrackUriContext> = serde::Deserialize::deserialize(de)?;
     let track_vec = v
         .iter()
         .map(|v| {
             let mut t = TrackRef::new();
-            //  This has got to be the most round about way of doing this.
-            t.set_gid(SpotifyId::from_base62(&v.gid).unwrap().to_raw().to_vec());
+            // Spirc resolves the gid from the uri if it is missing
+            if v.uri.starts_with("spotify:track:") {
+                if let Ok(id) = SpotifyId::from_base62(&v.uri["spotify:track:".len()..]) {
+                    t.set_gid(id.to_raw().to_vec());
+                }
+            }
             t.set_uri(v.uri.to_owned());
 
             t
@@ -0,0 +1,803 @@
+// Synchronized playback of several instances. The leader runs the Connect device and
+// announces what its player does, the followers play the same tracks at the same position
+// using their own session.
+//
+// Leader and followers prove to each other that they know the secret of the group when a
+// follower connects. The connection itself is neither encrypted nor signed, so the secret
+// keeps out devices on the network which don't know it, but not someone who is able to
+// intercept the connection. Followers which lose the connection stop playback and connect
+// to the leader again.
+use base64;
+use futures::Stream;
+use hmac::{Hmac, Mac};
+use rand;
+use serde_json;
+use sha1::Sha1;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::playback::player::{Player, PlayerEvent, PlayerEventChannel, SharedPosition};
+use librespot_core::spotify_id::SpotifyId;
+
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+// How often followers measure the clock offset to the leader.
+
+const POSITION_INTERVAL: Duration = Duration::from_secs(1);
+// How often the leader announces its position while playing.
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+// Time leader and follower have to authenticate each other.
+
+const MAXIMUM_HANDSHAKE_MESSAGE_LENGTH: usize = 1024;
+// Handshake messages are short, longer lines are not read from an unauthenticated peer.
+
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+// How long followers wait before connecting to the leader again after losing the connection.
+
+const MAXIMUM_DRIFT_MS: i64 = 40;
+// Followers seek when their position differs more than this from the leader's position.
+
+const CORRECTION_INTERVAL_MS: i64 = 3000;
+// Seeks take a while to take effect, followers seek at most once in this time.
+
+type HmacSha1 = Hmac<Sha1>;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum GroupMessage {
+    // Sent by the leader to a follower which connected, to be answered with Auth. The
+    // follower then sends its own challenge, which the leader answers.
+    Challenge {
+        nonce: String,
+    },
+    Auth {
+        mac: String,
+    },
+    // The leader plays track_uri, which was at position_ms at leader time timestamp_ms.
+    // Repeated while playing.
+    Play {
+        track_uri: String,
+        position_ms: u32,
+        timestamp_ms: i64,
+    },
+    Pause {
+        track_uri: String,
+        position_ms: u32,
+    },
+    Stop,
+    Ping {
+        sent_at_ms: i64,
+    },
+    Pong {
+        sent_at_ms: i64,
+        leader_time_ms: i64,
+    },
+}
+
+fn epoch_ms(time: SystemTime) -> i64 {
+    let dur = match time.duration_since(UNIX_EPOCH) {
+        Ok(dur) => dur,
+        Err(err) => err.duration(),
+    };
+    (dur.as_secs() as i64 * 1000) + (dur.subsec_nanos() as i64 / 1_000_000)
+}
+
+fn now_ms() -> i64 {
+    epoch_ms(SystemTime::now())
+}
+
+// Leader and followers prove that they know the secret of the group by signing a nonce of
+// the other side, so the secret itself is never sent.
+fn group_mac(secret: &str, nonce: &str) -> HmacSha1 {
+    let mut mac = HmacSha1::new_varkey(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.input(nonce.as_bytes());
+    mac
+}
+
+fn sign_nonce(secret: &str, nonce: &str) -> String {
+    base64::encode(&group_mac(secret, nonce).result().code())
+}
+
+fn verify_nonce(secret: &str, nonce: &str, signature: &str) -> bool {
+    match base64::decode(signature) {
+        Ok(code) => group_mac(secret, nonce).verify(&code).is_ok(),
+        Err(_) => false,
+    }
+}
+
+fn new_nonce() -> String {
+    base64::encode(&rand::random::<[u8; 16]>())
+}
+
+fn write_message(stream: &mut TcpStream, message: &GroupMessage) -> io::Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    stream.write_all(&line)
+}
+
+fn read_messages(reader: BufReader<TcpStream>) -> impl Iterator<Item = GroupMessage> {
+    reader
+        .lines()
+        .take_while(|line| line.is_ok())
+        .filter_map(|line| line.ok())
+        .filter_map(|line| match serde_json::from_str(&line) {
+            Ok(message) => Some(message),
+            Err(err) => {
+                warn!("Invalid group message {:?}: {}", line, err);
+                None
+            }
+        })
+}
+
+// Reads a single message, giving up if the other side doesn't send it by the deadline or
+// sends more than a handshake message.
+fn read_handshake(
+    stream: &TcpStream,
+    reader: &mut BufReader<TcpStream>,
+    deadline: Instant,
+) -> io::Result<GroupMessage> {
+    let mut line = Vec::new();
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "handshake timed out",
+            ));
+        }
+        stream.set_read_timeout(Some(deadline - now))?;
+
+        let (complete, used) = {
+            let available = match reader.fill_buf() {
+                Ok(available) => available,
+                // a read timeout is reported as WouldBlock on some platforms
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "handshake timed out",
+                    ))
+                }
+                Err(err) => return Err(err),
+            };
+            if available.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed",
+                ));
+            }
+            match available.iter().position(|byte| *byte == b'\n') {
+                Some(end) => {
+                    line.extend_from_slice(&available[..end]);
+                    (true, end + 1)
+                }
+                None => {
+                    line.extend_from_slice(available);
+                    (false, available.len())
+                }
+            }
+        };
+        reader.consume(used);
+
+        if line.len() > MAXIMUM_HANDSHAKE_MESSAGE_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "handshake message too long",
+            ));
+        }
+        if complete {
+            break;
+        }
+    }
+    stream.set_read_timeout(None)?;
+    serde_json::from_slice(&line).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+fn leader_handshake(
+    stream: &mut TcpStream,
+    reader: &mut BufReader<TcpStream>,
+    secret: &str,
+    deadline: Instant,
+) -> io::Result<()> {
+    let nonce = new_nonce();
+    let challenge = GroupMessage::Challenge {
+        nonce: nonce.clone(),
+    };
+    write_message(stream, &challenge)?;
+    match read_handshake(stream, reader, deadline)? {
+        GroupMessage::Auth { ref mac } if verify_nonce(secret, &nonce, mac) => (),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "follower does not know the group secret",
+            ))
+        }
+    }
+    match read_handshake(stream, reader, deadline)? {
+        GroupMessage::Challenge { ref nonce } => {
+            let auth = GroupMessage::Auth {
+                mac: sign_nonce(secret, nonce),
+            };
+            write_message(stream, &auth)
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no challenge from the group follower",
+        )),
+    }
+}
+
+fn follower_handshake(
+    stream: &mut TcpStream,
+    reader: &mut BufReader<TcpStream>,
+    secret: &str,
+    deadline: Instant,
+) -> io::Result<()> {
+    let auth = match read_handshake(stream, reader, deadline)? {
+        GroupMessage::Challenge { ref nonce } => GroupMessage::Auth {
+            mac: sign_nonce(secret, nonce),
+        },
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "no challenge from the group leader",
+            ))
+        }
+    };
+    let nonce = new_nonce();
+    let challenge = GroupMessage::Challenge {
+        nonce: nonce.clone(),
+    };
+    write_message(stream, &auth)?;
+    write_message(stream, &challenge)?;
+    match read_handshake(stream, reader, deadline)? {
+        GroupMessage::Auth { ref mac } if verify_nonce(secret, &nonce, mac) => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "group leader does not know the group secret",
+        )),
+    }
+}
+
+// Connects and authenticates to the leader. Returns the reader as well, as it may already
+// hold the first messages of the leader.
+fn join_leader(
+    leader: &[SocketAddr],
+    secret: &str,
+) -> io::Result<(TcpStream, BufReader<TcpStream>)> {
+    let mut stream = TcpStream::connect(leader)?;
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    follower_handshake(
+        &mut stream,
+        &mut reader,
+        secret,
+        Instant::now() + HANDSHAKE_TIMEOUT,
+    )?;
+    Ok((stream, reader))
+}
+
+// Passes the messages of the leader on until the connection is lost, and pings the leader
+// to measure the clock offset.
+fn receive_from_leader(
+    stream: &TcpStream,
+    reader: BufReader<TcpStream>,
+    inputs: Sender<FollowerInput>,
+) -> io::Result<()> {
+    let mut pinger = stream.try_clone()?;
+    thread::spawn(move || {
+        for message in read_messages(reader) {
+            if inputs.send(FollowerInput::Leader(message)).is_err() {
+                break;
+            }
+        }
+        warn!("Connection to group leader closed");
+        let _ = inputs.send(FollowerInput::Disconnected);
+    });
+
+    thread::spawn(move || loop {
+        let ping = GroupMessage::Ping {
+            sent_at_ms: now_ms(),
+        };
+        if write_message(&mut pinger, &ping).is_err() {
+            break;
+        }
+        thread::sleep(PING_INTERVAL);
+    });
+    Ok(())
+}
+
+// The position of the player as announcement to the followers, if it is playing the given
+// track.
+fn measured_play(track_id: SpotifyId, position: &SharedPosition) -> Option<GroupMessage> {
+    let position = (*position.lock().unwrap())?;
+    if position.track_id != track_id {
+        return None;
+    }
+    Some(GroupMessage::Play {
+        track_uri: track_id.to_uri(),
+        position_ms: position.position_ms,
+        timestamp_ms: epoch_ms(position.measured_at),
+    })
+}
+
+pub struct GroupLeader {
+    addr: SocketAddr,
+    followers: Arc<Mutex<Vec<Sender<GroupMessage>>>>,
+    // sent to followers when they join
+    current: Arc<Mutex<GroupMessage>>,
+}
+
+impl GroupLeader {
+    // Only followers which know the secret get to join.
+    pub fn bind<A: ToSocketAddrs>(addr: A, secret: String) -> io::Result<GroupLeader> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        info!("Waiting for group followers on {}", addr);
+
+        let leader = GroupLeader {
+            addr,
+            followers: Arc::new(Mutex::new(Vec::new())),
+            current: Arc::new(Mutex::new(GroupMessage::Stop)),
+        };
+
+        let followers = leader.followers.clone();
+        let current = leader.current.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let followers = followers.clone();
+                        let current = current.clone();
+                        let secret = secret.clone();
+                        // the handshake must not hold up other followers
+                        thread::spawn(move || {
+                            let peer = stream.peer_addr();
+                            if let Err(err) =
+                                Self::add_follower(stream, &secret, &followers, &current)
+                            {
+                                match peer {
+                                    Ok(peer) => {
+                                        warn!("Unable to add group follower {}: {}", peer, err)
+                                    }
+                                    Err(_) => warn!("Unable to add group follower: {}", err),
+                                }
+                            }
+                        });
+                    }
+                    Err(err) => warn!("Unable to accept group follower: {}", err),
+                }
+            }
+        });
+
+        Ok(leader)
+    }
+
+    fn add_follower(
+        mut stream: TcpStream,
+        secret: &str,
+        followers: &Arc<Mutex<Vec<Sender<GroupMessage>>>>,
+        current: &Arc<Mutex<GroupMessage>>,
+    ) -> io::Result<()> {
+        let peer = stream.peer_addr()?;
+        stream.set_nodelay(true)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        leader_handshake(
+            &mut stream,
+            &mut reader,
+            secret,
+            Instant::now() + HANDSHAKE_TIMEOUT,
+        )?;
+        info!("Group follower {} joined", peer);
+
+        // the follower starts with the current state and then gets every change of it
+        let (tx, rx) = mpsc::channel();
+        {
+            let mut followers = followers.lock().unwrap();
+            let _ = tx.send(current.lock().unwrap().clone());
+            followers.push(tx.clone());
+        }
+
+        thread::spawn(move || {
+            for message in rx.iter() {
+                if let Err(err) = write_message(&mut stream, &message) {
+                    info!("Group follower {} left: {}", peer, err);
+                    break;
+                }
+            }
+        });
+
+        let pong_tx = tx.clone();
+        thread::spawn(move || {
+            for message in read_messages(reader) {
+                if let GroupMessage::Ping { sent_at_ms } = message {
+                    let pong = GroupMessage::Pong {
+                        sent_at_ms,
+                        leader_time_ms: now_ms(),
+                    };
+                    if pong_tx.send(pong).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // Announces the playback of the given player to the followers, until the player is dropped.
+    // The position is taken from the player with the time it was measured, and announced again
+    // every POSITION_INTERVAL while playing.
+    pub fn follow(&self, events: PlayerEventChannel, position: SharedPosition) {
+        let followers = self.followers.clone();
+        let current = self.current.clone();
+
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for event in events.wait() {
+                match event {
+                    Ok(event) => {
+                        if tx.send(event).is_err() {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+        });
+
+        thread::spawn(move || {
+            let mut playing = None;
+            loop {
+                let message = match rx.recv_timeout(POSITION_INTERVAL) {
+                    Ok(PlayerEvent::Playing {
+                        track_id,
+                        position_ms,
+                        ..
+                    }) => {
+                        playing = Some(track_id);
+                        measured_play(track_id, &position).unwrap_or(GroupMessage::Play {
+                            track_uri: track_id.to_uri(),
+                            position_ms,
+                            timestamp_ms: now_ms(),
+                        })
+                    }
+                    Ok(PlayerEvent::Paused {
+                        track_id,
+                        position_ms,
+                        ..
+                    }) => {
+                        playing = None;
+                        GroupMessage::Pause {
+                            track_uri: track_id.to_uri(),
+                            position_ms,
+                        }
+                    }
+                    Ok(PlayerEvent::Stopped { .. }) => {
+                        playing = None;
+                        GroupMessage::Stop
+                    }
+                    Ok(_) => continue,
+                    Err(RecvTimeoutError::Timeout) => {
+                        match playing.and_then(|track_id| measured_play(track_id, &position)) {
+                            Some(message) => message,
+                            None => continue,
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
+
+                let mut followers = followers.lock().unwrap();
+                *current.lock().unwrap() = message.clone();
+                followers.retain(|follower| follower.send(message.clone()).is_ok());
+            }
+        });
+    }
+}
+
+enum FollowerInput {
+    Leader(GroupMessage),
+    Disconnected,
+}
+
+// Plays what the leader plays. The connection to the leader and the player are owned by
+// background threads, which stop playback when the leader disconnects and reconnect until
+// the follower is dropped.
+pub struct GroupFollower {
+    // the current connection to the leader
+    stream: Arc<Mutex<TcpStream>>,
+    stopped: Arc<AtomicBool>,
+}
+
+struct GroupFollowerInternal {
+    leader_addrs: Vec<SocketAddr>,
+    secret: String,
+    stream: Arc<Mutex<TcpStream>>,
+    stopped: Arc<AtomicBool>,
+    inputs: Sender<FollowerInput>,
+    player: Player,
+    position: SharedPosition,
+    sync: LeaderSync,
+    loaded_track: Option<SpotifyId>,
+    playing: bool,
+}
+
+// What the leader plays and how to get to its position
+struct LeaderSync {
+    // positive when the clock of the leader is ahead
+    clock_offset_ms: i64,
+    // positive to play ahead of the leader, to make up for a longer output delay of the leader
+    latency_ms: i64,
+    leader: GroupMessage,
+    // local time of the last seek to get in sync
+    corrected_at_ms: Option<i64>,
+}
+
+impl GroupFollower {
+    pub fn connect<A: ToSocketAddrs>(
+        addr: A,
+        secret: &str,
+        player: Player,
+        latency_ms: i64,
+    ) -> io::Result<GroupFollower> {
+        let leader_addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        let (stream, reader) = join_leader(&leader_addrs, secret)?;
+        info!("Following group leader {}", stream.peer_addr()?);
+
+        let (tx, rx) = mpsc::channel();
+        receive_from_leader(&stream, reader, tx.clone())?;
+
+        let stream = Arc::new(Mutex::new(stream));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let internal = GroupFollowerInternal {
+            leader_addrs,
+            secret: secret.to_owned(),
+            stream: stream.clone(),
+            stopped: stopped.clone(),
+            inputs: tx,
+            position: player.get_shared_position(),
+            player,
+            sync: LeaderSync::new(latency_ms),
+            loaded_track: None,
+            playing: false,
+        };
+        thread::spawn(move || internal.run(rx));
+
+        Ok(GroupFollower { stream, stopped })
+    }
+}
+
+impl Drop for GroupFollower {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        let _ = self.stream.lock().unwrap().shutdown(Shutdown::Both);
+    }
+}
+
+impl GroupFollowerInternal {
+    fn run(mut self, inputs: Receiver<FollowerInput>) {
+        for input in inputs.iter() {
+            match input {
+                FollowerInput::Leader(message) => self.handle_leader_message(message),
+                FollowerInput::Disconnected => {
+                    self.handle_leader_message(GroupMessage::Stop);
+                    if !self.reconnect() {
+                        break;
+                    }
+                }
+            }
+        }
+        self.player.stop();
+        debug!("Group follower finished.");
+    }
+
+    // Connects to the leader again, until it succeeds or the follower is dropped.
+    fn reconnect(&mut self) -> bool {
+        loop {
+            if self.stopped.load(Ordering::SeqCst) {
+                return false;
+            }
+            thread::sleep(RECONNECT_INTERVAL);
+            if self.stopped.load(Ordering::SeqCst) {
+                return false;
+            }
+
+            let (stream, reader) = match join_leader(&self.leader_addrs, &self.secret) {
+                Ok(connection) => connection,
+                Err(err) => {
+                    warn!("Unable to reconnect to group leader: {}", err);
+                    continue;
+                }
+            };
+            // checked while holding the lock, so the connection is either shut down when the
+            // follower is dropped, or not used at all
+            let mut current = self.stream.lock().unwrap();
+            if self.stopped.load(Ordering::SeqCst) {
+                let _ = stream.shutdown(Shutdown::Both);
+                return false;
+            }
+            if let Err(err) = receive_from_leader(&stream, reader, self.inputs.clone()) {
+                warn!("Unable to reconnect to group leader: {}", err);
+                continue;
+            }
+            info!("Reconnected to group leader");
+            *current = stream;
+            return true;
+        }
+    }
+
+    fn handle_leader_message(&mut self, message: GroupMessage) {
+        match message {
+            GroupMessage::Pong {
+                sent_at_ms,
+                leader_time_ms,
+            } => {
+                let received_at = now_ms();
+                self.sync
+                    .handle_pong(sent_at_ms, leader_time_ms, received_at);
+                trace!(
+                    "Group clock offset {} ms, round trip {} ms",
+                    self.sync.clock_offset_ms,
+                    received_at - sent_at_ms
+                );
+            }
+            GroupMessage::Challenge { .. }
+            | GroupMessage::Auth { .. }
+            | GroupMessage::Ping { .. } => (),
+            GroupMessage::Play { .. } => {
+                let jumped = self.sync.update_leader(message);
+                let now = now_ms();
+                let (track_id, position_ms) = match self.sync.leader_position_ms(now) {
+                    Some(position) => position,
+                    None => return,
+                };
+                if self.loaded_track != Some(track_id) {
+                    self.player.load(track_id, true, position_ms);
+                    self.loaded_track = Some(track_id);
+                    self.sync.corrected_at_ms = Some(now);
+                } else if !self.playing || jumped {
+                    self.player.seek(position_ms);
+                    self.player.play();
+                    self.sync.corrected_at_ms = Some(now);
+                } else {
+                    self.correct_drift(now);
+                }
+                self.playing = true;
+            }
+            GroupMessage::Pause {
+                ref track_uri,
+                position_ms,
+            } => {
+                if let Ok(track_id) = SpotifyId::from_uri(track_uri) {
+                    if self.loaded_track == Some(track_id) {
+                        self.player.pause();
+                        self.player.seek(position_ms);
+                    } else {
+                        self.player.load(track_id, false, position_ms);
+                        self.loaded_track = Some(track_id);
+                    }
+                }
+                self.playing = false;
+                self.sync.leader = message;
+            }
+            GroupMessage::Stop => {
+                self.player.stop();
+                self.loaded_track = None;
+                self.playing = false;
+                self.sync.leader = message;
+            }
+        }
+    }
+
+    fn correct_drift(&mut self, now: i64) {
+        let own = match *self.position.lock().unwrap() {
+            Some(own) => own,
+            None => return,
+        };
+        let measured_at = epoch_ms(own.measured_at);
+        if let Some(position_ms) =
+            self.sync
+                .correction(own.track_id, own.position_ms, measured_at, now)
+        {
+            self.player.seek(position_ms);
+        }
+    }
+}
+
+impl LeaderSync {
+    fn new(latency_ms: i64) -> LeaderSync {
+        LeaderSync {
+            clock_offset_ms: 0,
+            latency_ms,
+            leader: GroupMessage::Stop,
+            corrected_at_ms: None,
+        }
+    }
+
+    // Whether the leader moved to another position than where its previous announcement
+    // would have got it to, i.e. it seeked.
+    fn update_leader(&mut self, message: GroupMessage) -> bool {
+        let jumped = match (&self.leader, &message) {
+            (
+                GroupMessage::Play {
+                    track_uri: ref previous_uri,
+                    position_ms: previous_position,
+                    timestamp_ms: previous_timestamp,
+                },
+                GroupMessage::Play {
+                    ref track_uri,
+                    position_ms,
+                    timestamp_ms,
+                },
+            ) => {
+                let expected = *previous_position as i64 + timestamp_ms - previous_timestamp;
+                previous_uri != track_uri
+                    || (*position_ms as i64 - expected).abs() > MAXIMUM_DRIFT_MS
+            }
+            _ => false,
+        };
+        self.leader = message;
+        jumped
+    }
+
+    // Assumes that the ping took as long to the leader as the pong back.
+    fn handle_pong(&mut self, sent_at_ms: i64, leader_time_ms: i64, received_at_ms: i64) {
+        self.clock_offset_ms = leader_time_ms - (sent_at_ms + received_at_ms) / 2;
+    }
+
+    // Position of the leader's track at the given local time
+    fn leader_position_ms(&self, local_ms: i64) -> Option<(SpotifyId, u32)> {
+        match self.leader {
+            GroupMessage::Play {
+                ref track_uri,
+                position_ms,
+                timestamp_ms,
+            } => {
+                let track_id = SpotifyId::from_uri(track_uri).ok()?;
+                let leader_ms = local_ms + self.clock_offset_ms;
+                let position = position_ms as i64 + leader_ms - timestamp_ms + self.latency_ms;
+                Some((track_id, position.max(0) as u32))
+            }
+            _ => None,
+        }
+    }
+
+    // The position to seek to, if the own position of the track measured at measured_at_ms
+    // is too far off the leader's.
+    fn correction(
+        &mut self,
+        track_id: SpotifyId,
+        position_ms: u32,
+        measured_at_ms: i64,
+        now_ms: i64,
+    ) -> Option<u32> {
+        let (leader_track, leader_position) = self.leader_position_ms(measured_at_ms)?;
+        if leader_track != track_id {
+            return None;
+        }
+        let drift = leader_position as i64 - position_ms as i64;
+        if drift.abs() <= MAXIMUM_DRIFT_MS {
+            return None;
+        }
+        if let Some(corrected_at_ms) = self.corrected_at_ms {
+            if now_ms - corrected_at_ms < CORRECTION_INTERVAL_MS {
+                return None;
+            }
+        }
+        debug!("Out of sync with group leader by {} ms, seeking", drift);
+        self.corrected_at_ms = Some(now_ms);
+        self.leader_position_ms(now_ms)
+            .map(|(_, position)| position)
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,287 @@
+use futures::sync::mpsc as futures_mpsc;
+use std::net::TcpListener;
+
+use super::*;
+
+fn track_uri(n: u8) -> String {
+    let mut id = [0u8; 16];
+    id[15] = n;
+    SpotifyId::from_raw(&id).unwrap().to_uri()
+}
+
+fn play(n: u8, position_ms: u32, timestamp_ms: i64) -> GroupMessage {
+    GroupMessage::Play {
+        track_uri: track_uri(n),
+        position_ms,
+        timestamp_ms,
+    }
+}
+
+fn sync_playing(n: u8, position_ms: u32, timestamp_ms: i64) -> LeaderSync {
+    let mut sync = LeaderSync::new(0);
+    sync.update_leader(play(n, position_ms, timestamp_ms));
+    sync
+}
+
+#[test]
+fn clock_offset_assumes_symmetric_round_trip() {
+    let mut sync = LeaderSync::new(0);
+    sync.handle_pong(1_000, 5_060, 1_100);
+    assert_eq!(sync.clock_offset_ms, 4_010);
+
+    sync.handle_pong(2_000, 1_500, 2_020);
+    assert_eq!(sync.clock_offset_ms, -510);
+}
+
+#[test]
+fn leader_position_accounts_for_offset_and_latency() {
+    let mut sync = LeaderSync::new(0);
+    sync.handle_pong(1_000, 11_000, 1_000);
+    sync.latency_ms = 30;
+    sync.update_leader(play(1, 20_000, 11_500));
+
+    let (track_id, position_ms) = sync.leader_position_ms(2_000).unwrap();
+    assert_eq!(track_id.to_uri(), track_uri(1));
+    assert_eq!(position_ms, 20_000 + 500 + 30);
+}
+
+#[test]
+fn leader_position_is_none_when_not_playing() {
+    let mut sync = LeaderSync::new(0);
+    assert_eq!(sync.leader_position_ms(1_000), None);
+
+    sync.update_leader(GroupMessage::Stop);
+    assert_eq!(sync.leader_position_ms(1_000), None);
+}
+
+#[test]
+fn small_drift_is_not_corrected() {
+    let mut sync = sync_playing(1, 10_000, 1_000);
+    let track_id = SpotifyId::from_uri(&track_uri(1)).unwrap();
+
+    assert_eq!(sync.correction(track_id, 12_000 - 30, 3_000, 3_100), None);
+    assert_eq!(sync.correction(track_id, 12_000 + 30, 3_000, 3_100), None);
+}
+
+#[test]
+fn drift_is_measured_at_the_time_of_the_own_position() {
+    let mut sync = sync_playing(1, 10_000, 1_000);
+    let track_id = SpotifyId::from_uri(&track_uri(1)).unwrap();
+
+    // in sync at the time of measurement, even though a lot of time passed since
+    assert_eq!(sync.correction(track_id, 12_000, 3_000, 4_000), None);
+    // 100 ms behind, seeks to where the leader is now
+    assert_eq!(
+        sync.correction(track_id, 11_900, 3_000, 4_000),
+        Some(13_000)
+    );
+}
+
+#[test]
+fn corrections_wait_for_the_previous_seek() {
+    let mut sync = sync_playing(1, 10_000, 1_000);
+    let track_id = SpotifyId::from_uri(&track_uri(1)).unwrap();
+
+    assert!(sync.correction(track_id, 11_000, 2_000, 2_000).is_none());
+    assert!(sync.correction(track_id, 10_000, 2_000, 2_000).is_some());
+    let retry_ms = 2_000 + CORRECTION_INTERVAL_MS;
+    assert!(sync
+        .correction(track_id, 10_000, 2_000, retry_ms - 1)
+        .is_none());
+    assert!(sync.correction(track_id, 10_000, 2_000, retry_ms).is_some());
+}
+
+#[test]
+fn other_track_is_not_corrected() {
+    let mut sync = sync_playing(1, 10_000, 1_000);
+    let other = SpotifyId::from_uri(&track_uri(2)).unwrap();
+
+    assert_eq!(sync.correction(other, 0, 2_000, 2_000), None);
+}
+
+#[test]
+fn leader_seek_is_detected() {
+    let mut sync = sync_playing(1, 10_000, 1_000);
+
+    assert!(!sync.update_leader(play(1, 11_010, 2_000)));
+    assert!(sync.update_leader(play(1, 30_000, 3_000)));
+    assert!(sync.update_leader(play(2, 31_000, 4_000)));
+    assert!(!sync.update_leader(GroupMessage::Stop));
+}
+
+#[test]
+fn nonce_is_verified_with_the_same_secret() {
+    let signature = sign_nonce("secret", "nonce");
+
+    assert!(verify_nonce("secret", "nonce", &signature));
+    assert!(!verify_nonce("other secret", "nonce", &signature));
+    assert!(!verify_nonce("secret", "other nonce", &signature));
+    assert!(!verify_nonce("secret", "nonce", "not base64!"));
+}
+
+// Accepts a connection on localhost and runs the given peer on it
+fn peer<F>(run: F) -> SocketAddr
+where
+    F: FnOnce(TcpStream, BufReader<TcpStream>) + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let reader = BufReader::new(stream.try_clone().unwrap());
+        run(stream, reader);
+    });
+    addr
+}
+
+fn connect(addr: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(addr).unwrap();
+    let reader = BufReader::new(stream.try_clone().unwrap());
+    (stream, reader)
+}
+
+fn handshake_deadline() -> Instant {
+    Instant::now() + Duration::from_secs(5)
+}
+
+#[test]
+fn handshake_succeeds_with_the_same_secret() {
+    let (result_tx, result_rx) = mpsc::channel();
+    let addr = peer(move |mut stream, mut reader| {
+        let result = leader_handshake(&mut stream, &mut reader, "secret", handshake_deadline());
+        result_tx.send(result.map_err(|err| err.kind())).unwrap();
+    });
+
+    let (mut stream, mut reader) = connect(addr);
+    follower_handshake(&mut stream, &mut reader, "secret", handshake_deadline()).unwrap();
+    assert_eq!(result_rx.recv().unwrap(), Ok(()));
+}
+
+#[test]
+fn follower_with_other_secret_is_rejected() {
+    let (result_tx, result_rx) = mpsc::channel();
+    let addr = peer(move |mut stream, mut reader| {
+        let result = leader_handshake(&mut stream, &mut reader, "secret", handshake_deadline());
+        result_tx.send(result.map_err(|err| err.kind())).unwrap();
+    });
+
+    let (mut stream, mut reader) = connect(addr);
+    assert!(follower_handshake(&mut stream, &mut reader, "other", handshake_deadline()).is_err());
+    assert_eq!(
+        result_rx.recv().unwrap(),
+        Err(io::ErrorKind::PermissionDenied)
+    );
+}
+
+#[test]
+fn leader_with_other_secret_is_rejected() {
+    // accepts any follower and answers its challenge without knowing the secret
+    let addr = peer(|mut stream, mut reader| {
+        let challenge = GroupMessage::Challenge { nonce: new_nonce() };
+        write_message(&mut stream, &challenge).unwrap();
+        read_handshake(&stream, &mut reader, handshake_deadline()).unwrap();
+        if let GroupMessage::Challenge { ref nonce } =
+            read_handshake(&stream, &mut reader, handshake_deadline()).unwrap()
+        {
+            let auth = GroupMessage::Auth {
+                mac: sign_nonce("other", nonce),
+            };
+            write_message(&mut stream, &auth).unwrap();
+        }
+        let _ = reader.read_line(&mut String::new());
+    });
+
+    let (mut stream, mut reader) = connect(addr);
+    let result = follower_handshake(&mut stream, &mut reader, "secret", handshake_deadline());
+    assert_eq!(
+        result.map_err(|err| err.kind()),
+        Err(io::ErrorKind::PermissionDenied)
+    );
+}
+
+#[test]
+fn long_handshake_message_is_rejected() {
+    let addr = peer(|mut stream, _| {
+        let _ = stream.write_all(&[b'a'; MAXIMUM_HANDSHAKE_MESSAGE_LENGTH + 100]);
+        thread::sleep(Duration::from_secs(1));
+    });
+
+    let (mut stream, mut reader) = connect(addr);
+    let result = follower_handshake(&mut stream, &mut reader, "secret", handshake_deadline());
+    assert_eq!(
+        result.map_err(|err| err.kind()),
+        Err(io::ErrorKind::InvalidData)
+    );
+}
+
+#[test]
+fn handshake_must_be_completed_by_the_deadline() {
+    // sends a byte now and then, which keeps every single read within the time
+    let addr = peer(|mut stream, _| {
+        for _ in 0..20 {
+            if stream.write_all(b" ").is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    });
+
+    let (mut stream, mut reader) = connect(addr);
+    let deadline = Instant::now() + Duration::from_millis(300);
+    let result = follower_handshake(&mut stream, &mut reader, "secret", deadline);
+    assert_eq!(
+        result.map_err(|err| err.kind()),
+        Err(io::ErrorKind::TimedOut)
+    );
+}
+
+#[test]
+fn leader_sends_state_to_joined_followers() {
+    let leader = GroupLeader::bind("127.0.0.1:0", String::from("secret")).unwrap();
+    let (events_tx, events_rx) = futures_mpsc::unbounded();
+    leader.follow(events_rx, Arc::new(Mutex::new(None)));
+
+    let (mut stream, reader) = join_leader(&[leader.local_addr()], "secret").unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut messages = read_messages(reader);
+
+    match messages.next() {
+        Some(GroupMessage::Stop) => (),
+        message => panic!("unexpected {:?}", message),
+    }
+
+    events_tx
+        .unbounded_send(PlayerEvent::Playing {
+            play_request_id: 1,
+            track_id: SpotifyId::from_uri(&track_uri(1)).unwrap(),
+            position_ms: 1_000,
+            duration_ms: 180_000,
+        })
+        .unwrap();
+    match messages.next() {
+        Some(GroupMessage::Play {
+            ref track_uri,
+            position_ms,
+            ..
+        }) => {
+            assert_eq!(*track_uri, self::track_uri(1));
+            assert_eq!(position_ms, 1_000);
+        }
+        message => panic!("unexpected {:?}", message),
+    }
+
+    write_message(&mut stream, &GroupMessage::Ping { sent_at_ms: 42 }).unwrap();
+    match messages.next() {
+        Some(GroupMessage::Pong { sent_at_ms, .. }) => assert_eq!(sent_at_ms, 42),
+        message => panic!("unexpected {:?}", message),
+    }
+}
+
+#[test]
+fn leader_rejects_follower_without_the_secret() {
+    let leader = GroupLeader::bind("127.0.0.1:0", String::from("secret")).unwrap();
+
+    assert!(join_leader(&[leader.local_addr()], "other").is_err());
+}
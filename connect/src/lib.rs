@@ -32,4 +32,7 @@ extern crate librespot_protocol as protocol;
 
 pub mod context;
 pub mod discovery;
+pub mod group;
+pub mod player;
 pub mod spirc;
+pub mod transport;
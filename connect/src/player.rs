@@ -0,0 +1,65 @@
+use librespot_core::spotify_id::SpotifyId;
+
+use crate::playback::player::{Player, PlayerEventChannel};
+
+// The part of the player used by the SpircTask.
+pub trait SpircPlayer {
+    // Returns the play request id, which is included in all events about the track.
+    fn load(&mut self, track_id: SpotifyId, start_playing: bool, position_ms: u32) -> u64;
+    fn preload(&self, track_id: SpotifyId);
+    fn prefetch(&self, tracks: Vec<SpotifyId>);
+    fn play(&self);
+    fn pause(&self);
+    fn stop(&self);
+    fn seek(&self, position_ms: u32);
+    fn get_player_event_channel(&self) -> PlayerEventChannel;
+    fn emit_volume_set_event(&self, volume: u16);
+    fn emit_takeover_event(&self, device_ident: String, device_name: String, accepted: bool);
+    fn emit_autoplay_started_event(&self, context_uri: String, station_uri: String);
+}
+
+impl SpircPlayer for Player {
+    fn load(&mut self, track_id: SpotifyId, start_playing: bool, position_ms: u32) -> u64 {
+        Player::load(self, track_id, start_playing, position_ms)
+    }
+
+    fn preload(&self, track_id: SpotifyId) {
+        Player::preload(self, track_id)
+    }
+
+    fn prefetch(&self, tracks: Vec<SpotifyId>) {
+        Player::prefetch(self, tracks)
+    }
+
+    fn play(&self) {
+        Player::play(self)
+    }
+
+    fn pause(&self) {
+        Player::pause(self)
+    }
+
+    fn stop(&self) {
+        Player::stop(self)
+    }
+
+    fn seek(&self, position_ms: u32) {
+        Player::seek(self, position_ms)
+    }
+
+    fn get_player_event_channel(&self) -> PlayerEventChannel {
+        Player::get_player_event_channel(self)
+    }
+
+    fn emit_volume_set_event(&self, volume: u16) {
+        Player::emit_volume_set_event(self, volume)
+    }
+
+    fn emit_takeover_event(&self, device_ident: String, device_name: String, accepted: bool) {
+        Player::emit_takeover_event(self, device_ident, device_name, accepted)
+    }
+
+    fn emit_autoplay_started_event(&self, context_uri: String, station_uri: String) {
+        Player::emit_autoplay_started_event(self, context_uri, station_uri)
+    }
+}
@@ -1,21 +1,26 @@
 use std;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::cmp::min;
 
 use futures::future;
+use futures::stream;
 use futures::sync::mpsc;
 use futures::{Async, Future, Poll, Sink, Stream};
-use protobuf::{self, Message};
-use rand;
+use protobuf;
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
-use serde_json;
+use rand::{Rng, SeedableRng};
 
-use crate::context::StationContext;
+use self::persist::PersistedState;
+use crate::context::{ContextFuture, ResolvedContext, StationContext};
 use crate::playback::mixer::Mixer;
 use crate::playback::player::{Player, PlayerEvent, PlayerEventChannel};
+use crate::player::SpircPlayer;
 use crate::protocol;
 use crate::protocol::spirc::{DeviceState, Frame, MessageType, PlayStatus, State, TrackRef};
-use librespot_core::config::{ConnectConfig, VolumeCtrl};
-use librespot_core::mercury::MercuryError;
+use crate::transport::{FrameSink, FrameStream, MercuryTransport, SpircTransport};
+use librespot_core::config::{
+    ConnectCapabilities, ConnectConfig, ContentRestriction, TakeoverPolicy, VolumeCtrl,
+};
 use librespot_core::session::Session;
 use librespot_core::spotify_id::{SpotifyAudioType, SpotifyId, SpotifyIdError};
 use librespot_core::util::url_encode;
@@ -23,6 +28,14 @@ use librespot_core::util::SeqGenerator;
 use librespot_core::version;
 use librespot_core::volume::Volume;
 
+// The persisted state is written at most once in this time. Later changes are written by the
+// first notify after it or when the SpircTask is dropped.
+const SAVE_STATE_INTERVAL_MS: i64 = 5_000;
+
+// A device which claims playback again within this time after its takeover was refused is
+// handed playback, so two devices refusing each other don't claim it back and forth.
+const TAKEOVER_RECLAIM_WINDOW_MS: i64 = 10_000;
+
 enum SpircPlayStatus {
     Stopped,
     LoadingPlay {
@@ -41,9 +54,21 @@ enum SpircPlayStatus {
     },
 }
 
+// Fetching of the tracks of a playlist, album or other context which were not sent by the client
+#[derive(Clone, Debug, PartialEq)]
+enum ContextPaging {
+    // the tracks of the load frame may only be the start of the context
+    Unresolved,
+    NextPage(String),
+    Fetching,
+    Complete,
+}
+
 pub struct SpircTask {
-    player: Player,
+    player: Box<dyn SpircPlayer>,
     mixer: Box<dyn Mixer>,
+    // volume changes made outside of librespot
+    mixer_watch: Box<dyn Stream<Item = u16, Error = ()>>,
     config: SpircTaskConfig,
 
     sequence: SeqGenerator<u32>,
@@ -55,16 +80,43 @@ pub struct SpircTask {
     mixer_started: bool,
     play_status: SpircPlayStatus,
 
-    subscription: Box<dyn Stream<Item = Frame, Error = MercuryError>>,
-    sender: Box<dyn Sink<SinkItem = Frame, SinkError = MercuryError>>,
+    subscription: FrameStream,
+    sender: FrameSink,
     commands: mpsc::UnboundedReceiver<SpircCommand>,
     player_events: PlayerEventChannel,
 
     shutdown: bool,
-    session: Session,
-    context_fut: Box<dyn Future<Item = serde_json::Value, Error = MercuryError>>,
-    autoplay_fut: Box<dyn Future<Item = String, Error = MercuryError>>,
+    transport: Box<dyn SpircTransport>,
+    context_fut: ContextFuture<StationContext>,
+    autoplay_fut: ContextFuture<String>,
+    load_context_fut: ContextFuture<(ResolvedContext, bool)>,
+    page_fut: ContextFuture<(Vec<TrackRef>, Option<String>)>,
+    // resolves the original track order when shuffle is turned off and it wasn't stored
+    unshuffle_fut: ContextFuture<ResolvedContext>,
+    context_paging: ContextPaging,
     context: Option<StationContext>,
+    // whether context_fut resolves tracks of the autoplay station
+    context_is_autoplay: bool,
+    // tracks added from the autoplay station have its uri set as their context
+    autoplay_station: Option<String>,
+    playing_autoplay: bool,
+
+    state_senders: Vec<mpsc::UnboundedSender<SpircState>>,
+    taken_over_by: Option<SpircDevice>,
+    // device whose takeover was refused last and when playback was claimed back from it
+    reclaimed_from: Option<(String, i64)>,
+    // track order from before shuffling, restored when shuffle is turned off
+    unshuffled_tracks: Option<Vec<TrackRef>>,
+    // source of the shuffle seeds
+    rng: StdRng,
+    // repeat the current track. The spirc State has no field for this, so controllers see
+    // it as repeat, and their repeat button cycles through off, repeat and repeat-track.
+    repeat_track: bool,
+    // the state was restored from the cache and may be resumed by a local play command
+    resumable: bool,
+    // the state changed since it was last persisted
+    save_state_pending: bool,
+    state_saved_at: i64,
 }
 
 pub enum SpircCommand {
@@ -75,17 +127,68 @@ pub enum SpircCommand {
     Next,
     VolumeUp,
     VolumeDown,
+    SetVolume(u16),
+    Seek(u32),
+    Shuffle(bool),
+    Repeat(bool),
+    RepeatTrack(bool),
+    LoadContext { uri: String, start_playing: bool },
+    AddStateSender(mpsc::UnboundedSender<SpircState>),
     Shutdown,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpircPlaybackStatus {
+    Stopped,
+    Loading,
+    Playing,
+    Paused,
+}
+
+#[derive(Debug, Clone)]
+pub struct SpircTrack {
+    pub id: Option<SpotifyId>,
+    pub uri: String,
+    pub queued: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct SpircDevice {
+    pub ident: String,
+    pub name: String,
+}
+
+// Snapshot of the Connect state, published whenever remote clients are notified.
+#[derive(Debug, Clone)]
+pub struct SpircState {
+    pub active: bool,
+    pub status: SpircPlaybackStatus,
+    pub context_uri: String,
+    pub tracks: Vec<SpircTrack>,
+    pub playing_track_index: u32,
+    pub position_ms: u32,
+    pub position_measured_at: u64,
+    pub shuffle: bool,
+    pub repeat: bool,
+    pub repeat_track: bool,
+    pub volume: u16,
+    // The device which took over playback from this one, if any.
+    pub taken_over_by: Option<SpircDevice>,
+}
+
+pub type SpircStateChannel = mpsc::UnboundedReceiver<SpircState>;
+
 struct SpircTaskConfig {
     volume_ctrl: VolumeCtrl,
+    volume_step_size: u16,
     autoplay: bool,
+    takeover_policy: TakeoverPolicy,
+    capabilities: ConnectCapabilities,
+    context_history: usize,
+    context_fetch_threshold: usize,
+    persist_state: bool,
 }
 
-const CONTEXT_TRACKS_HISTORY: usize = 10;
-const CONTEXT_FETCH_THRESHOLD: u32 = 5;
-
 pub struct Spirc {
     commands: mpsc::UnboundedSender<SpircCommand>,
 }
@@ -160,10 +263,10 @@ fn initial_device_state(config: ConnectConfig) -> DeviceState {
                 msg.set_typ(protocol::spirc::CapabilityType::kVolumeSteps);
                 {
                     let repeated = msg.mut_intValue();
-                    if let VolumeCtrl::Fixed = config.volume_ctrl {
-                        repeated.push(0)
-                    } else {
-                        repeated.push(64)
+                    match config.volume_ctrl {
+                        _ if !config.capabilities.volume_control => repeated.push(0),
+                        VolumeCtrl::Fixed => repeated.push(0),
+                        _ => repeated.push(config.volume_steps as i64),
                     }
                 };
                 msg
@@ -198,46 +301,82 @@ fn initial_device_state(config: ConnectConfig) -> DeviceState {
                 msg.set_typ(protocol::spirc::CapabilityType::kSupportedTypes);
                 {
                     let repeated = msg.mut_stringValue();
-                    repeated.push(::std::convert::Into::into("audio/local"));
-                    repeated.push(::std::convert::Into::into("audio/track"));
-                    repeated.push(::std::convert::Into::into("audio/episode"));
-                    repeated.push(::std::convert::Into::into("local"));
-                    repeated.push(::std::convert::Into::into("track"))
+                    let types: &[&str] = match config.capabilities.content {
+                        ContentRestriction::All => &[
+                            "audio/local",
+                            "audio/track",
+                            "audio/episode",
+                            "local",
+                            "track",
+                        ],
+                        ContentRestriction::Music => {
+                            &["audio/local", "audio/track", "local", "track"]
+                        }
+                        ContentRestriction::Podcasts => &["audio/episode"],
+                    };
+                    for typ in types.iter() {
+                        repeated.push(::std::convert::Into::into(*typ));
+                    }
+                    for typ in config.capabilities.extra_types.iter() {
+                        repeated.push(typ.clone());
+                    }
                 };
                 msg
             };
+            if config.capabilities.hidden {
+                let msg = repeated.push_default();
+                msg.set_typ(protocol::spirc::CapabilityType::kHidden);
+                {
+                    let repeated = msg.mut_intValue();
+                    repeated.push(1)
+                };
+            }
         };
         msg
     }
 }
 
-fn calc_logarithmic_volume(volume: u16) -> u16 {
-    // Volume conversion taken from https://www.dr-lex.be/info-stuff/volumecontrols.html#ideal2
-    // Convert the given volume [0..0xffff] to a dB gain
-    // We assume a dB range of 60dB.
-    // Use the equation: a * exp(b * x)
-    // in which a = IDEAL_FACTOR, b = 1/1000
-    const IDEAL_FACTOR: f64 = 6.908;
-    let normalized_volume = volume as f64 / std::u16::MAX as f64; // To get a value between 0 and 1
-
-    let mut val = std::u16::MAX;
-    // Prevent val > std::u16::MAX due to rounding errors
-    if normalized_volume < 0.999 {
-        let new_volume = (normalized_volume * IDEAL_FACTOR).exp() / 1000.0;
-        val = (new_volume * std::u16::MAX as f64) as u16;
+// Checks a context or track uri against the content restriction.
+fn content_allowed(content: ContentRestriction, uri: &str) -> bool {
+    let is_podcast = uri.starts_with("spotify:episode:") || uri.starts_with("spotify:show:");
+    match content {
+        ContentRestriction::All => true,
+        ContentRestriction::Music => !is_podcast,
+        ContentRestriction::Podcasts => is_podcast,
     }
+}
 
-    debug!("input volume:{} to mixer: {}", volume, val);
+// A gid carries no type, so a track ref without an uri is of the type of its context, e.g. an
+// episode of a show. Without a context it is played as a track.
+fn track_allowed(content: ContentRestriction, context_uri: &str, track: &TrackRef) -> bool {
+    let uri = if !track.get_uri().is_empty() {
+        track.get_uri()
+    } else if !context_uri.is_empty() {
+        context_uri
+    } else {
+        "spotify:track:"
+    };
+    content_allowed(content, uri)
+}
 
-    // return the scale factor (0..0xffff) (equivalent to a voltage multiplier).
-    val
+// Moves the track at current_index to the front and shuffles the remaining tracks.
+// The same seed always results in the same order.
+fn shuffle_tracks(tracks: &mut [TrackRef], current_index: usize, seed: u64) {
+    if current_index >= tracks.len() {
+        return;
+    }
+    tracks.swap(0, current_index);
+    if let Some((_, rest)) = tracks.split_first_mut() {
+        let mut rng = StdRng::seed_from_u64(seed);
+        rest.shuffle(&mut rng);
+    }
 }
 
-fn volume_to_mixer(volume: u16, volume_ctrl: &VolumeCtrl) -> u16 {
-    match volume_ctrl {
-        VolumeCtrl::Linear => volume,
-        VolumeCtrl::Log => calc_logarithmic_volume(volume),
-        VolumeCtrl::Fixed => volume,
+fn same_track(a: &TrackRef, b: &TrackRef) -> bool {
+    if a.has_gid() && b.has_gid() {
+        a.get_gid() == b.get_gid()
+    } else {
+        a.get_uri() == b.get_uri()
     }
 }
 
@@ -249,44 +388,46 @@ impl Spirc {
         mixer: Box<dyn Mixer>,
     ) -> (Spirc, SpircTask) {
         debug!("new Spirc[{}]", session.session_id());
+        let transport = Box::new(MercuryTransport::new(&session));
+        Spirc::with_transport(config, Box::new(player), mixer, transport)
+    }
 
-        let ident = session.device_id().to_owned();
-
-        // Uri updated in response to issue #288
-        debug!("canonical_username: {}", url_encode(&session.username()));
-        let uri = format!("hm://remote/user/{}/", url_encode(&session.username()));
-
-        let subscription = session.mercury().subscribe(&uri as &str);
-        let subscription = subscription
-            .map(|stream| stream.map_err(|_| MercuryError))
-            .flatten_stream();
-        let subscription = Box::new(subscription.map(|response| -> Frame {
-            let data = response.payload.first().unwrap();
-            protobuf::parse_from_bytes(data).unwrap()
-        }));
-
-        let sender = Box::new(
-            session
-                .mercury()
-                .sender(uri)
-                .with(|frame: Frame| Ok(frame.write_to_bytes().unwrap())),
-        );
+    // Creates a Spirc which uses the given transport instead of a session, e.g. to drive the
+    // SpircTask with frames from somewhere else.
+    pub fn with_transport(
+        config: ConnectConfig,
+        player: Box<dyn SpircPlayer>,
+        mixer: Box<dyn Mixer>,
+        mut transport: Box<dyn SpircTransport>,
+    ) -> (Spirc, SpircTask) {
+        let ident = transport.device_id();
+
+        let subscription = transport.frames();
+        let sender = transport.sender();
 
         let (cmd_tx, cmd_rx) = mpsc::unbounded();
 
         let volume = config.volume;
         let task_config = SpircTaskConfig {
             volume_ctrl: config.volume_ctrl.to_owned(),
+            volume_step_size: config.volume_step_size,
             autoplay: config.autoplay,
+            takeover_policy: config.takeover_policy.clone(),
+            capabilities: config.capabilities.clone(),
+            context_history: config.context_history,
+            context_fetch_threshold: config.context_fetch_threshold,
+            persist_state: config.persist_state,
         };
 
         let device = initial_device_state(config);
 
         let player_events = player.get_player_event_channel();
+        let mixer_watch = mixer.watch().unwrap_or_else(|| Box::new(stream::empty()));
 
         let mut task = SpircTask {
             player: player,
             mixer: mixer,
+            mixer_watch,
             config: task_config,
 
             sequence: SeqGenerator::new(1),
@@ -305,14 +446,34 @@ impl Spirc {
             player_events: player_events,
 
             shutdown: false,
-            session: session.clone(),
+            transport,
 
             context_fut: Box::new(future::empty()),
             autoplay_fut: Box::new(future::empty()),
+            load_context_fut: Box::new(future::empty()),
+            page_fut: Box::new(future::empty()),
+            unshuffle_fut: Box::new(future::empty()),
+            context_paging: ContextPaging::Complete,
             context: None,
+            context_is_autoplay: false,
+            autoplay_station: None,
+            playing_autoplay: false,
+
+            state_senders: Vec::new(),
+            taken_over_by: None,
+            reclaimed_from: None,
+            unshuffled_tracks: None,
+            rng: StdRng::from_entropy(),
+            repeat_track: false,
+            resumable: false,
+            save_state_pending: false,
+            state_saved_at: 0,
         };
 
         task.set_volume(volume);
+        if task.config.persist_state {
+            task.restore_state();
+        }
 
         let spirc = Spirc { commands: cmd_tx };
 
@@ -342,6 +503,41 @@ impl Spirc {
     pub fn volume_down(&self) {
         let _ = self.commands.unbounded_send(SpircCommand::VolumeDown);
     }
+    pub fn set_volume(&self, volume: u16) {
+        let _ = self
+            .commands
+            .unbounded_send(SpircCommand::SetVolume(volume));
+    }
+    pub fn seek(&self, position_ms: u32) {
+        let _ = self
+            .commands
+            .unbounded_send(SpircCommand::Seek(position_ms));
+    }
+    pub fn shuffle(&self, shuffle: bool) {
+        let _ = self.commands.unbounded_send(SpircCommand::Shuffle(shuffle));
+    }
+    pub fn repeat(&self, repeat: bool) {
+        let _ = self.commands.unbounded_send(SpircCommand::Repeat(repeat));
+    }
+    pub fn repeat_track(&self, repeat_track: bool) {
+        let _ = self
+            .commands
+            .unbounded_send(SpircCommand::RepeatTrack(repeat_track));
+    }
+    pub fn subscribe_state(&self) -> SpircStateChannel {
+        let (state_tx, state_rx) = mpsc::unbounded();
+        let _ = self
+            .commands
+            .unbounded_send(SpircCommand::AddStateSender(state_tx));
+        state_rx
+    }
+    // Starts playback of a playlist, album or other context uri on this device.
+    pub fn load_context(&self, uri: &str, start_playing: bool) {
+        let _ = self.commands.unbounded_send(SpircCommand::LoadContext {
+            uri: uri.to_owned(),
+            start_playing,
+        });
+    }
     pub fn shutdown(&self) {
         let _ = self.commands.unbounded_send(SpircCommand::Shutdown);
     }
@@ -355,7 +551,7 @@ impl Future for SpircTask {
         loop {
             let mut progress = false;
 
-            if self.session.is_invalid() {
+            if self.transport.is_invalid() {
                 return Ok(Async::Ready(()));
             }
 
@@ -391,44 +587,89 @@ impl Future for SpircTask {
                         self.handle_player_event(event);
                     }
                 }
-                // TODO: Refactor
-                match self.context_fut.poll() {
-                    Ok(Async::Ready(value)) => {
-                        let r_context = serde_json::from_value::<StationContext>(value.clone());
-                        self.context = match r_context {
-                            Ok(context) => {
-                                info!(
-                                    "Resolved {:?} tracks from <{:?}>",
-                                    context.tracks.len(),
-                                    self.state.get_context_uri(),
-                                );
-                                Some(context)
-                            }
-                            Err(e) => {
-                                error!("Unable to parse JSONContext {:?}\n{:?}", e, value);
-                                None
-                            }
-                        };
-                        // It needn't be so verbose - can be as simple as
-                        // if let Some(ref context) = r_context {
-                        //     info!("Got {:?} tracks from <{}>", context.tracks.len(), context.uri);
-                        // }
-                        // self.context = r_context;
 
+                match self.mixer_watch.poll() {
+                    Ok(Async::Ready(Some(volume))) => {
+                        progress = true;
+                        self.handle_mixer_volume(volume);
+                    }
+                    Ok(Async::Ready(None)) => (),
+                    Ok(Async::NotReady) => (),
+                    Err(_) => (),
+                }
+
+                match self.context_fut.poll() {
+                    Ok(Async::Ready(mut context)) => {
+                        let context_uri = self.state.get_context_uri().to_owned();
+                        self.retain_allowed_tracks(&context_uri, &mut context.tracks);
+                        info!(
+                            "Resolved {:?} tracks from <{:?}>",
+                            context.tracks.len(),
+                            self.state.get_context_uri(),
+                        );
+                        self.context = Some(context);
                         progress = true;
                         self.context_fut = Box::new(future::empty());
                     }
                     Ok(Async::NotReady) => (),
                     Err(err) => {
+                        self.context = None;
                         self.context_fut = Box::new(future::empty());
                         error!("ContextError: {:?}", err)
                     }
                 }
 
+                match self.load_context_fut.poll() {
+                    Ok(Async::Ready((context, start_playing))) => {
+                        self.handle_load_context(context, start_playing);
+                        progress = true;
+                        self.load_context_fut = Box::new(future::empty());
+                    }
+                    Ok(Async::NotReady) => (),
+                    Err(err) => {
+                        self.load_context_fut = Box::new(future::empty());
+                        error!("LoadContextError: {:?}", err)
+                    }
+                }
+
+                match self.page_fut.poll() {
+                    Ok(Async::Ready((tracks, next_page_url))) => {
+                        self.handle_context_page(tracks, next_page_url);
+                        progress = true;
+                        self.page_fut = Box::new(future::empty());
+                    }
+                    Ok(Async::NotReady) => (),
+                    Err(err) => {
+                        self.page_fut = Box::new(future::empty());
+                        self.context_paging = ContextPaging::Complete;
+                        error!("PageError: {:?}", err)
+                    }
+                }
+
+                match self.unshuffle_fut.poll() {
+                    Ok(Async::Ready(context)) => {
+                        self.handle_unshuffle_context(context);
+                        progress = true;
+                        self.unshuffle_fut = Box::new(future::empty());
+                    }
+                    Ok(Async::NotReady) => (),
+                    Err(err) => {
+                        self.unshuffle_fut = Box::new(future::empty());
+                        error!("UnshuffleError: {:?}", err)
+                    }
+                }
+
                 match self.autoplay_fut.poll() {
                     Ok(Async::Ready(autoplay_station_uri)) => {
                         info!("Autoplay uri resolved to <{:?}>", autoplay_station_uri);
-                        self.context_fut = self.resolve_station(&autoplay_station_uri);
+                        let content = self.config.capabilities.content;
+                        if content_allowed(content, &autoplay_station_uri) {
+                            self.resolve_station(&autoplay_station_uri);
+                            self.context_is_autoplay = true;
+                            self.autoplay_station = Some(autoplay_station_uri);
+                        } else {
+                            info!("Not autoplaying, content is restricted to {:?}", content);
+                        }
                         progress = true;
                         self.autoplay_fut = Box::new(future::empty());
                     }
@@ -440,6 +681,12 @@ impl Future for SpircTask {
                 }
             }
 
+            if self.save_state_pending
+                && self.now_ms() - self.state_saved_at >= SAVE_STATE_INTERVAL_MS
+            {
+                self.save_state();
+            }
+
             let poll_sender = self.sender.poll_complete().unwrap();
 
             // Only shutdown once we've flushed out all our messages
@@ -456,12 +703,7 @@ impl Future for SpircTask {
 
 impl SpircTask {
     fn now_ms(&mut self) -> i64 {
-        let dur = match SystemTime::now().duration_since(UNIX_EPOCH) {
-            Ok(dur) => dur,
-            Err(err) => err.duration(),
-        };
-        (dur.as_secs() as i64 + self.session.time_delta()) * 1000
-            + (dur.subsec_nanos() / 1000_000) as i64
+        self.transport.now_ms()
     }
 
     fn ensure_mixer_started(&mut self) {
@@ -491,6 +733,8 @@ impl SpircTask {
                 if active {
                     self.handle_play();
                     self.notify(None, true);
+                } else if self.resumable {
+                    self.handle_resume();
                 } else {
                     CommandSender::new(self, MessageType::kMessageTypePlay).send();
                 }
@@ -499,6 +743,8 @@ impl SpircTask {
                 if active {
                     self.handle_play_pause();
                     self.notify(None, true);
+                } else if self.resumable {
+                    self.handle_resume();
                 } else {
                     CommandSender::new(self, MessageType::kMessageTypePlayPause).send();
                 }
@@ -543,6 +789,83 @@ impl SpircTask {
                     CommandSender::new(self, MessageType::kMessageTypeVolumeDown).send();
                 }
             }
+            SpircCommand::SetVolume(volume) => {
+                if active {
+                    self.set_volume(volume);
+                    self.notify(None, true);
+                } else {
+                    CommandSender::new(self, MessageType::kMessageTypeVolume)
+                        .volume(volume)
+                        .send();
+                }
+            }
+            SpircCommand::Seek(position_ms) => {
+                if active {
+                    self.handle_seek(position_ms);
+                    self.notify(None, true);
+                } else {
+                    CommandSender::new(self, MessageType::kMessageTypeSeek)
+                        .position(position_ms)
+                        .send();
+                }
+            }
+            SpircCommand::Shuffle(shuffle) => {
+                if active {
+                    self.handle_shuffle(shuffle);
+                    self.notify(None, true);
+                } else {
+                    let mut state = State::new();
+                    state.set_shuffle(shuffle);
+                    CommandSender::new(self, MessageType::kMessageTypeShuffle)
+                        .state(state)
+                        .send();
+                }
+            }
+            SpircCommand::Repeat(repeat) => {
+                if active {
+                    self.handle_repeat(repeat);
+                    self.notify(None, true);
+                } else {
+                    let mut state = State::new();
+                    state.set_repeat(repeat);
+                    CommandSender::new(self, MessageType::kMessageTypeRepeat)
+                        .state(state)
+                        .send();
+                }
+            }
+            SpircCommand::RepeatTrack(repeat_track) => {
+                if active {
+                    self.handle_repeat_track(repeat_track);
+                    self.notify(None, true);
+                } else {
+                    // the active device can only be asked to repeat the context
+                    let mut state = State::new();
+                    state.set_repeat(repeat_track);
+                    CommandSender::new(self, MessageType::kMessageTypeRepeat)
+                        .state(state)
+                        .send();
+                }
+            }
+            SpircCommand::LoadContext { uri, start_playing } => {
+                let content = self.config.capabilities.content;
+                if !content_allowed(content, &uri) {
+                    warn!(
+                        "Ignoring load of <{}>, content is restricted to {:?}",
+                        uri, content
+                    );
+                    return;
+                }
+                // loading a context locally always transfers playback to this device
+                self.load_context_fut = Box::new(
+                    self.transport
+                        .resolve_context(&uri)
+                        .map(move |context| (context, start_playing)),
+                );
+            }
+            SpircCommand::AddStateSender(sender) => {
+                let _ = sender.unbounded_send(self.state_snapshot());
+                self.state_senders.push(sender);
+            }
             SpircCommand::Shutdown => {
                 CommandSender::new(self, MessageType::kMessageTypeGoodbye).send();
                 self.shutdown = true;
@@ -628,7 +951,10 @@ impl SpircTask {
                         }
                     },
                     PlayerEvent::TimeToPreloadNextTrack { .. } => self.handle_preload_next_track(),
-                    PlayerEvent::Unavailable { track_id, .. } => self.handle_unavailable(track_id),
+                    PlayerEvent::Unavailable { track_id, .. }
+                    | PlayerEvent::ExplicitSkipped { track_id, .. } => {
+                        self.handle_unavailable(track_id)
+                    }
                     _ => (),
                 }
             }
@@ -659,6 +985,10 @@ impl SpircTask {
             return;
         }
 
+        if !self.frame_allowed(&frame) {
+            return;
+        }
+
         match frame.get_typ() {
             MessageType::kMessageTypeHello => {
                 self.notify(Some(frame.get_ident()), true);
@@ -724,27 +1054,12 @@ impl SpircTask {
             }
 
             MessageType::kMessageTypeRepeat => {
-                self.state.set_repeat(frame.get_state().get_repeat());
+                self.handle_remote_repeat(frame.get_state().get_repeat());
                 self.notify(None, true);
             }
 
             MessageType::kMessageTypeShuffle => {
-                self.state.set_shuffle(frame.get_state().get_shuffle());
-                if self.state.get_shuffle() {
-                    let current_index = self.state.get_playing_track_index();
-                    {
-                        let tracks = self.state.mut_track();
-                        tracks.swap(0, current_index as usize);
-                        if let Some((_, rest)) = tracks.split_first_mut() {
-                            let mut rng = rand::thread_rng();
-                            rest.shuffle(&mut rng);
-                        }
-                    }
-                    self.state.set_playing_track_index(0);
-                } else {
-                    let context = self.state.get_context_uri();
-                    debug!("{:?}", context);
-                }
+                self.handle_shuffle(frame.get_state().get_shuffle());
                 self.notify(None, true);
             }
 
@@ -786,11 +1101,49 @@ impl SpircTask {
                     && self.device.get_became_active_at()
                         <= frame.get_device_state().get_became_active_at()
                 {
-                    self.device.set_is_active(false);
-                    self.state.set_status(PlayStatus::kPlayStatusStop);
-                    self.player.stop();
-                    self.ensure_mixer_stopped();
-                    self.play_status = SpircPlayStatus::Stopped;
+                    let device = SpircDevice {
+                        ident: frame.get_ident().to_owned(),
+                        name: frame.get_device_state().get_name().to_owned(),
+                    };
+                    let now = self.now_ms();
+                    let recently_reclaimed = match self.reclaimed_from {
+                        Some((ref ident, at)) => {
+                            *ident == device.ident && now - at < TAKEOVER_RECLAIM_WINDOW_MS
+                        }
+                        None => false,
+                    };
+                    if recently_reclaimed {
+                        // The other device refused to hand playback back as well, claiming
+                        // it again would make both devices claim it over and over.
+                        warn!(
+                            "{} ({}) claimed playback again, handing it over",
+                            device.name, device.ident
+                        );
+                    }
+                    if recently_reclaimed || self.allows_takeover(&device) {
+                        self.reclaimed_from = None;
+                        self.device.set_is_active(false);
+                        self.state.set_status(PlayStatus::kPlayStatusStop);
+                        self.player.stop();
+                        self.ensure_mixer_stopped();
+                        self.play_status = SpircPlayStatus::Stopped;
+                        self.player.emit_takeover_event(
+                            device.ident.clone(),
+                            device.name.clone(),
+                            true,
+                        );
+                        self.taken_over_by = Some(device);
+                        self.publish_state();
+                    } else {
+                        info!("Refusing takeover by {} ({})", device.name, device.ident);
+                        // Becoming active again after the other device makes it hand playback
+                        // back to this one. Official clients may ignore this and keep playing.
+                        self.device.set_became_active_at(now);
+                        self.reclaimed_from = Some((device.ident.clone(), now));
+                        self.player
+                            .emit_takeover_event(device.ident, device.name, false);
+                        self.notify(None, true);
+                    }
                 }
             }
 
@@ -798,6 +1151,302 @@ impl SpircTask {
         }
     }
 
+    // Rejects frames which do not match the advertised capabilities
+    fn frame_allowed(&self, frame: &Frame) -> bool {
+        let capabilities = &self.config.capabilities;
+        match frame.get_typ() {
+            MessageType::kMessageTypeVolume
+            | MessageType::kMessageTypeVolumeUp
+            | MessageType::kMessageTypeVolumeDown
+                if !capabilities.volume_control =>
+            {
+                warn!("Ignoring {:?}, volume control is disabled", frame.get_typ());
+                false
+            }
+            MessageType::kMessageTypeLoad | MessageType::kMessageTypeReplace => {
+                let state = frame.get_state();
+                let context_uri = state.get_context_uri();
+                let allowed = (context_uri.is_empty()
+                    || content_allowed(capabilities.content, context_uri))
+                    && state
+                        .get_track()
+                        .iter()
+                        .all(|track| track_allowed(capabilities.content, context_uri, track));
+                if !allowed {
+                    warn!(
+                        "Ignoring {:?} of <{}>, content is restricted to {:?}",
+                        frame.get_typ(),
+                        context_uri,
+                        capabilities.content
+                    );
+                }
+                allowed
+            }
+            _ => true,
+        }
+    }
+
+    // Removes resolved tracks which do not match the content restriction, e.g. episodes of a
+    // playlist or autoplay station
+    fn retain_allowed_tracks(&self, context_uri: &str, tracks: &mut Vec<TrackRef>) {
+        let content = self.config.capabilities.content;
+        let count = tracks.len();
+        tracks.retain(|track| track_allowed(content, context_uri, track));
+        if tracks.len() < count {
+            warn!(
+                "Skipping {} tracks of <{}>, content is restricted to {:?}",
+                count - tracks.len(),
+                context_uri,
+                content
+            );
+        }
+    }
+
+    fn restore_state(&mut self) {
+        let persisted = match self
+            .transport
+            .cache()
+            .and_then(|cache| PersistedState::load(&cache))
+        {
+            Some(persisted) => persisted,
+            None => return,
+        };
+        info!(
+            "Restored {} tracks of <{}>",
+            persisted.state.get_track().len(),
+            persisted.state.get_context_uri()
+        );
+        self.resumable = !persisted.state.get_track().is_empty();
+        self.state = persisted.state;
+        self.state.set_status(PlayStatus::kPlayStatusStop);
+        self.repeat_track = persisted.repeat_track;
+        self.unshuffled_tracks = persisted.unshuffled_tracks;
+        self.context_paging = persisted.context_paging;
+    }
+
+    fn save_state(&mut self) {
+        let now = self.now_ms();
+        let mut state = self.state.clone();
+        let position_ms = self.position();
+        state.set_position_ms(position_ms);
+        state.set_position_measured_at(now as u64);
+        let persisted = PersistedState {
+            state,
+            repeat_track: self.repeat_track,
+            unshuffled_tracks: self.unshuffled_tracks.clone(),
+            context_paging: self.context_paging.clone(),
+        };
+        if let Some(cache) = self.transport.cache() {
+            persisted.save(&cache);
+        }
+        self.save_state_pending = false;
+        self.state_saved_at = now;
+    }
+
+    // Continues playback of the restored state on this device
+    fn handle_resume(&mut self) {
+        info!("Resuming <{}>", self.state.get_context_uri());
+        self.resumable = false;
+        let now = self.now_ms();
+        self.device.set_is_active(true);
+        self.device.set_became_active_at(now);
+
+        // the paging of the restored context continues where it was
+        let context_paging = std::mem::replace(&mut self.context_paging, ContextPaging::Complete);
+        let context_uri = self.state.get_context_uri().to_owned();
+        self.resolve_continuation(&context_uri);
+        if self.context_paging == ContextPaging::Unresolved {
+            self.context_paging = context_paging;
+        }
+        let position_ms = self.state.get_position_ms();
+        self.load_track(true, position_ms);
+        self.notify(None, true);
+    }
+
+    fn allows_takeover(&self, device: &SpircDevice) -> bool {
+        match self.config.takeover_policy {
+            TakeoverPolicy::Always => true,
+            TakeoverPolicy::NotWhilePlaying => match self.play_status {
+                SpircPlayStatus::Playing { .. } | SpircPlayStatus::LoadingPlay { .. } => false,
+                SpircPlayStatus::Paused { .. }
+                | SpircPlayStatus::LoadingPause { .. }
+                | SpircPlayStatus::Stopped => true,
+            },
+            TakeoverPolicy::AllowList(ref allowed) => allowed
+                .iter()
+                .any(|entry| *entry == device.ident || *entry == device.name),
+        }
+    }
+
+    fn handle_load_context(&mut self, context: ResolvedContext, start_playing: bool) {
+        let context_uri = context.uri.clone();
+        let (mut tracks, next_page_url) = context.into_tracks();
+        self.retain_allowed_tracks(&context_uri, &mut tracks);
+        info!("Loading {} tracks from <{}>", tracks.len(), context_uri);
+
+        if !self.device.get_is_active() {
+            let now = self.now_ms();
+            self.device.set_is_active(true);
+            self.device.set_became_active_at(now);
+        }
+
+        self.resolve_continuation(&context_uri);
+        if self.context_paging == ContextPaging::Unresolved {
+            self.context_paging = match next_page_url {
+                Some(url) => ContextPaging::NextPage(url),
+                None => ContextPaging::Complete,
+            };
+        }
+
+        // the user queue is kept and played after the first track of the new context
+        let current_index = self.state.get_playing_track_index() as usize;
+        let queued = self.take_queued_tracks(current_index + 1);
+
+        self.state.set_context_uri(context_uri);
+        self.state
+            .set_track(protobuf::RepeatedField::from_vec(tracks));
+        self.unshuffled_tracks = None;
+        self.state.set_playing_track_index(0);
+
+        if !self.state.get_track().is_empty() {
+            if self.state.get_shuffle() {
+                self.handle_shuffle(true);
+            }
+            self.insert_queued_tracks(1, queued);
+            self.load_track(start_playing, 0);
+        } else {
+            info!("No tracks in context");
+            self.state.set_status(PlayStatus::kPlayStatusStop);
+            self.player.stop();
+            self.ensure_mixer_stopped();
+            self.play_status = SpircPlayStatus::Stopped;
+        }
+
+        self.notify(None, true);
+    }
+
+    fn handle_shuffle(&mut self, shuffle: bool) {
+        self.state.set_shuffle(shuffle);
+        self.unshuffle_fut = Box::new(future::empty());
+        if self.state.get_shuffle() {
+            // the user queue keeps its order and stays in front of the shuffled context
+            let current_index = self.state.get_playing_track_index() as usize;
+            let queued = self.take_queued_tracks(current_index + 1);
+            if self.unshuffled_tracks.is_none() {
+                self.unshuffled_tracks = Some(
+                    self.state
+                        .get_track()
+                        .iter()
+                        .filter(|track| !track.get_queued())
+                        .cloned()
+                        .collect(),
+                );
+            }
+            let seed = self.rng.gen::<u64>();
+            debug!("Shuffling tracks with seed {}", seed);
+            shuffle_tracks(self.state.mut_track(), current_index, seed);
+            self.state.set_playing_track_index(0);
+            self.insert_queued_tracks(1, queued);
+        } else if let Some(tracks) = self.unshuffled_tracks.take() {
+            self.restore_track_order(tracks);
+        } else {
+            self.resolve_track_order();
+        }
+    }
+
+    // Replaces the shuffled tracks with the given original order. Returns false if the
+    // playing track is not part of it, in which case the shuffled tracks are kept.
+    fn restore_track_order(&mut self, tracks: Vec<TrackRef>) -> bool {
+        // A queued track is not part of the original order. While one is playing, the
+        // context is restored relative to the context track which was played before it.
+        let current_index = self.state.get_playing_track_index() as usize;
+        let current_queued = self
+            .state
+            .get_track()
+            .get(current_index)
+            .filter(|track| track.get_queued())
+            .is_some();
+        let (anchor_index, queue_index) = if current_queued {
+            (current_index.checked_sub(1), current_index)
+        } else {
+            (Some(current_index), current_index + 1)
+        };
+        let queued = self.take_queued_tracks(queue_index);
+
+        let anchor_position = match anchor_index.and_then(|i| self.state.get_track().get(i)) {
+            Some(anchor) => match tracks.iter().position(|track| same_track(track, anchor)) {
+                Some(position) => Some(position),
+                None => {
+                    warn!("Current track not in original order, keeping shuffled tracks");
+                    self.insert_queued_tracks(queue_index, queued);
+                    return false;
+                }
+            },
+            None => None,
+        };
+
+        self.state
+            .set_track(protobuf::RepeatedField::from_vec(tracks));
+        let (index, queue_index) = match anchor_position {
+            Some(position) if current_queued => (position + 1, position + 1),
+            Some(position) => (position, position + 1),
+            None if current_queued => (0, 0),
+            None => (0, 1),
+        };
+        self.state.set_playing_track_index(index as u32);
+        self.insert_queued_tracks(queue_index, queued);
+        true
+    }
+
+    // The original order is not known if the tracks were loaded shuffled, e.g. by a remote
+    // client or from the persisted state. It is resolved from the context instead.
+    fn resolve_track_order(&mut self) {
+        let context_uri = self.state.get_context_uri().to_owned();
+        if context_uri.is_empty()
+            || context_uri.starts_with("spotify:station:")
+            || context_uri.starts_with("spotify:dailymix:")
+        {
+            return;
+        }
+        debug!("Resolving the original track order of <{}>", context_uri);
+        self.unshuffle_fut = self.transport.resolve_context(&context_uri);
+    }
+
+    fn handle_unshuffle_context(&mut self, context: ResolvedContext) {
+        if self.state.get_shuffle() || context.uri != self.state.get_context_uri() {
+            return;
+        }
+        let (tracks, next_page_url) = context.into_tracks();
+        if self.restore_track_order(tracks) {
+            // tracks of later pages are fetched again when they are needed
+            self.context_paging = match next_page_url {
+                Some(url) => ContextPaging::NextPage(url),
+                None => ContextPaging::Complete,
+            };
+            self.notify(None, true);
+        }
+    }
+
+    fn handle_repeat(&mut self, repeat: bool) {
+        self.state.set_repeat(repeat);
+        self.repeat_track = false;
+    }
+
+    // Controllers only know about repeat, and ask to turn it off when repeat is on. When
+    // repeating the context, this switches to repeating the track instead.
+    fn handle_remote_repeat(&mut self, repeat: bool) {
+        if !repeat && self.state.get_repeat() && !self.repeat_track {
+            self.handle_repeat_track(true);
+        } else {
+            self.handle_repeat(repeat);
+        }
+    }
+
+    fn handle_repeat_track(&mut self, repeat_track: bool) {
+        self.state.set_repeat(repeat_track);
+        self.repeat_track = repeat_track;
+    }
+
     fn handle_play(&mut self) {
         match self.play_status {
             SpircPlayStatus::Paused {
@@ -894,11 +1543,50 @@ impl SpircTask {
         }
     }
 
+    // Removes the user queued tracks starting at index and returns them in order
+    fn take_queued_tracks(&mut self, index: usize) -> Vec<TrackRef> {
+        let tracks = self.state.mut_track();
+        let mut queued = Vec::new();
+        while index < tracks.len() && tracks[index].get_queued() {
+            queued.push(tracks.remove(index));
+        }
+        queued
+    }
+
+    fn insert_queued_tracks(&mut self, index: usize, queued: Vec<TrackRef>) {
+        let tracks = self.state.mut_track();
+        let mut pos = min(index, tracks.len());
+        for track in queued.into_iter() {
+            tracks.insert(pos, track);
+            pos += 1;
+        }
+    }
+
     fn preview_next_track(&mut self) -> Option<SpotifyId> {
-        self.get_track_id_to_play_from_playlist(self.state.get_playing_track_index() + 1)
+        let next_index = if self.repeat_track {
+            self.state.get_playing_track_index()
+        } else {
+            self.state.get_playing_track_index() + 1
+        };
+        self.get_track_id_to_play_from_playlist(next_index)
             .and_then(|(track_id, _)| Some(track_id))
     }
 
+    fn prefetch_upcoming_tracks(&self) {
+        // Hands the tracks following the current one to the player, which may fetch them into the cache
+        let next_index = self.state.get_playing_track_index() as usize + 1;
+        let upcoming = self
+            .state
+            .get_track()
+            .iter()
+            .skip(next_index)
+            .filter(|track_ref| !self.track_ref_is_unavailable(track_ref))
+            .filter_map(|track_ref| self.get_spotify_id_for_track(track_ref).ok())
+            .filter(|track_id| track_id.audio_type != SpotifyAudioType::NonPlayable)
+            .collect();
+        self.player.prefetch(upcoming);
+    }
+
     fn handle_preload_next_track(&mut self) {
         // Requests the player thread to preload the next track
         match self.play_status {
@@ -946,30 +1634,43 @@ impl SpircTask {
     fn handle_next(&mut self) {
         let mut new_index = self.consume_queued_track() as u32;
         let mut continue_playing = true;
-        let tracks_len = self.state.get_track().len() as u32;
+        let mut tracks_len = self.state.get_track().len() as u32;
         debug!(
             "At track {:?} of {:?} <{:?}> update [{}]",
             new_index,
             self.state.get_track().len(),
             self.state.get_context_uri(),
-            tracks_len - new_index < CONTEXT_FETCH_THRESHOLD
+            tracks_len - new_index < self.config.context_fetch_threshold as u32
         );
         let context_uri = self.state.get_context_uri().to_owned();
+        if tracks_len - new_index < self.config.context_fetch_threshold as u32 {
+            self.fetch_next_page();
+        }
         if (context_uri.starts_with("spotify:station:")
             || context_uri.starts_with("spotify:dailymix:")
             // spotify:user:xxx:collection
-            || context_uri.starts_with(&format!("spotify:user:{}:collection",url_encode(&self.session.username()))))
-            && ((self.state.get_track().len() as u32) - new_index) < CONTEXT_FETCH_THRESHOLD
+            || context_uri.starts_with(&format!("spotify:user:{}:collection",url_encode(&self.transport.username()))))
+            && ((self.state.get_track().len() as u32) - new_index)
+                < self.config.context_fetch_threshold as u32
         {
-            self.context_fut = self.resolve_station(&context_uri);
+            self.resolve_station(&context_uri);
+            self.state.set_playing_track_index(new_index);
             self.update_tracks_from_context();
+            new_index = self.state.get_playing_track_index();
+            tracks_len = self.state.get_track().len() as u32;
         }
-        if self.config.autoplay && new_index == tracks_len - 1 {
+        if self.config.autoplay
+            && new_index + 1 >= tracks_len
+            && self.context_paging == ContextPaging::Complete
+        {
             // Extend the playlist
             // Note: This doesn't seem to reflect in the UI
             // the additional tracks in the frame don't show up as with station view
             debug!("Extending playlist <{}>", context_uri);
+            self.state.set_playing_track_index(new_index);
             self.update_tracks_from_context();
+            new_index = self.state.get_playing_track_index();
+            tracks_len = self.state.get_track().len() as u32;
         }
         if new_index >= tracks_len {
             new_index = 0; // Loop around back to start
@@ -997,14 +1698,8 @@ impl SpircTask {
             // Queued tracks always follow the currently playing track.
             // They should not be considered when calculating the previous
             // track so extract them beforehand and reinsert them after it.
-            let mut queue_tracks = Vec::new();
-            {
-                let queue_index = self.consume_queued_track();
-                let tracks = self.state.mut_track();
-                while queue_index < tracks.len() && tracks[queue_index].get_queued() {
-                    queue_tracks.push(tracks.remove(queue_index));
-                }
-            }
+            let queue_index = self.consume_queued_track();
+            let queue_tracks = self.take_queued_tracks(queue_index);
             let current_index = self.state.get_playing_track_index();
             let new_index = if current_index > 0 {
                 current_index - 1
@@ -1014,11 +1709,7 @@ impl SpircTask {
                 0
             };
             // Reinsert queued tracks after the new playing track.
-            let mut pos = (new_index + 1) as usize;
-            for track in queue_tracks.into_iter() {
-                self.state.mut_track().insert(pos, track);
-                pos += 1;
-            }
+            self.insert_queued_tracks((new_index + 1) as usize, queue_tracks);
 
             self.state.set_playing_track_index(new_index);
 
@@ -1028,8 +1719,12 @@ impl SpircTask {
         }
     }
 
+    fn volume_step(&self) -> u32 {
+        self.config.volume_step_size as u32
+    }
+
     fn handle_volume_up(&mut self) {
-        let mut volume: u32 = self.device.get_volume() as u32 + 4096;
+        let mut volume: u32 = self.device.get_volume() as u32 + self.volume_step();
         if volume > 0xFFFF {
             volume = 0xFFFF;
         }
@@ -1037,7 +1732,7 @@ impl SpircTask {
     }
 
     fn handle_volume_down(&mut self) {
-        let mut volume: i32 = self.device.get_volume() as i32 - 4096;
+        let mut volume: i32 = self.device.get_volume() as i32 - self.volume_step() as i32;
         if volume < 0 {
             volume = 0;
         }
@@ -1045,7 +1740,11 @@ impl SpircTask {
     }
 
     fn handle_end_of_track(&mut self) {
-        self.handle_next();
+        if self.repeat_track {
+            self.load_track(true, 0);
+        } else {
+            self.handle_next();
+        }
         self.notify(None, true);
     }
 
@@ -1061,76 +1760,142 @@ impl SpircTask {
         }
     }
 
-    fn resolve_station(
-        &self,
-        uri: &str,
-    ) -> Box<dyn Future<Item = serde_json::Value, Error = MercuryError>> {
-        let radio_uri = format!("hm://radio-apollo/v3/stations/{}", uri);
+    // Starts fetching the tracks which follow the given context
+    fn resolve_continuation(&mut self, context_uri: &str) {
+        self.page_fut = Box::new(future::empty());
+        if context_uri.starts_with("spotify:station:")
+            || context_uri.starts_with("spotify:dailymix:")
+        {
+            self.resolve_station(context_uri);
+            self.context_paging = ContextPaging::Complete;
+        } else {
+            self.context_paging = ContextPaging::Unresolved;
+            if self.config.autoplay {
+                info!("Fetching autoplay context uri");
+                // Get autoplay_station_uri for regular playlists
+                self.resolve_autoplay_uri(context_uri);
+            }
+        }
+        self.autoplay_station = None;
+        self.playing_autoplay = false;
+    }
 
-        self.resolve_uri(&radio_uri)
+    fn resolve_station(&mut self, uri: &str) {
+        self.context_fut = self.transport.resolve_station(uri);
+        self.context_is_autoplay = false;
     }
 
-    fn resolve_autoplay_uri(
-        &self,
-        uri: &str,
-    ) -> Box<dyn Future<Item = String, Error = MercuryError>> {
-        let query_uri = format!("hm://autoplay-enabled/query?uri={}", uri);
-        let request = self.session.mercury().get(query_uri);
-        Box::new(request.and_then(move |response| {
-            if response.status_code == 200 {
-                let data = response
-                    .payload
-                    .first()
-                    .expect("Empty autoplay uri")
-                    .to_vec();
-                let autoplay_uri = String::from_utf8(data).unwrap();
-                Ok(autoplay_uri)
-            } else {
-                warn!("No autoplay_uri found");
-                Err(MercuryError)
-            }
-        }))
+    fn resolve_autoplay_uri(&mut self, uri: &str) {
+        self.autoplay_fut = self.transport.resolve_autoplay_uri(uri);
     }
 
-    fn resolve_uri(
-        &self,
-        uri: &str,
-    ) -> Box<dyn Future<Item = serde_json::Value, Error = MercuryError>> {
-        let request = self.session.mercury().get(uri);
+    // Starts fetching the tracks which follow the loaded part of a playlist, album or other
+    // non station context
+    fn fetch_next_page(&mut self) {
+        match std::mem::replace(&mut self.context_paging, ContextPaging::Fetching) {
+            ContextPaging::Unresolved => {
+                let context_uri = self.state.get_context_uri().to_owned();
+                let loaded = self
+                    .state
+                    .get_track()
+                    .iter()
+                    .filter(|track| !track.get_queued())
+                    .count();
+                debug!("Resolving tracks of <{}> after {}", context_uri, loaded);
+                self.page_fut = Box::new(self.transport.resolve_context(&context_uri).map(
+                    move |context| {
+                        let (mut tracks, next_page_url) = context.into_tracks();
+                        let loaded = min(loaded, tracks.len());
+                        tracks.drain(0..loaded);
+                        (tracks, next_page_url)
+                    },
+                ));
+            }
+            ContextPaging::NextPage(page_url) => {
+                debug!("Fetching next page <{}>", page_url);
+                self.page_fut = Box::new(
+                    self.transport
+                        .resolve_page(&page_url)
+                        .map(|page| (page.tracks, page.next_page_url)),
+                );
+            }
+            paging => self.context_paging = paging,
+        }
+    }
 
-        Box::new(request.and_then(move |response| {
-            let data = response
-                .payload
-                .first()
-                .expect("Empty payload on context uri");
-            let response: serde_json::Value = serde_json::from_slice(&data).unwrap();
+    fn handle_context_page(&mut self, mut tracks: Vec<TrackRef>, next_page_url: Option<String>) {
+        let context_uri = self.state.get_context_uri().to_owned();
+        self.retain_allowed_tracks(&context_uri, &mut tracks);
+        info!(
+            "Adding {} tracks from the next page of <{}>",
+            tracks.len(),
+            self.state.get_context_uri()
+        );
+        self.context_paging = match next_page_url.filter(|url| !url.is_empty()) {
+            Some(url) => ContextPaging::NextPage(url),
+            None => ContextPaging::Complete,
+        };
+        if tracks.is_empty() {
+            return;
+        }
 
-            Ok(response)
-        }))
+        if let Some(ref mut unshuffled_tracks) = self.unshuffled_tracks {
+            unshuffled_tracks.extend_from_slice(&tracks);
+        }
+        if self.state.get_shuffle() {
+            tracks.shuffle(&mut self.rng);
+        }
+        for track in tracks {
+            self.state.mut_track().push(track);
+        }
+        self.notify(None, true);
     }
 
     fn update_tracks_from_context(&mut self) {
         if let Some(ref context) = self.context {
-            self.context_fut = self.resolve_uri(&context.next_page_url);
+            self.context_fut = self.transport.resolve_next_page(&context.next_page_url);
 
-            let new_tracks = &context.tracks;
-            debug!("Adding {:?} tracks from context to frame", new_tracks.len());
+            let playing_index = self.state.get_playing_track_index() as usize;
             let mut track_vec = self.state.take_track().into_vec();
-            if let Some(head) = track_vec.len().checked_sub(CONTEXT_TRACKS_HISTORY) {
-                track_vec.drain(0..head);
+            // Only drop tracks which were already played, the playing track and the
+            // user queue following it have to stay
+            let head = min(
+                track_vec.len().saturating_sub(self.config.context_history),
+                playing_index,
+            );
+            track_vec.drain(0..head);
+
+            // Skip tracks which were played recently or are already upcoming
+            let mut new_tracks: Vec<TrackRef> = Vec::new();
+            for track in context.tracks.iter() {
+                if track_vec
+                    .iter()
+                    .chain(new_tracks.iter())
+                    .all(|existing| !same_track(existing, track))
+                {
+                    let mut track = track.clone();
+                    if self.context_is_autoplay {
+                        if let Some(ref station_uri) = self.autoplay_station {
+                            track.set_context(station_uri.clone());
+                        }
+                    }
+                    new_tracks.push(track);
+                }
             }
+            debug!(
+                "Adding {:?} of {:?} tracks from context to frame",
+                new_tracks.len(),
+                context.tracks.len()
+            );
+
             track_vec.extend_from_slice(&new_tracks);
+            if let Some(ref mut unshuffled_tracks) = self.unshuffled_tracks {
+                unshuffled_tracks.extend_from_slice(&new_tracks);
+            }
             self.state
                 .set_track(protobuf::RepeatedField::from_vec(track_vec));
-
-            // Update playing index
-            if let Some(new_index) = self
-                .state
-                .get_playing_track_index()
-                .checked_sub(CONTEXT_TRACKS_HISTORY as u32)
-            {
-                self.state.set_playing_track_index(new_index);
-            }
+            self.state
+                .set_playing_track_index((playing_index - head) as u32);
         } else {
             warn!("No context to update from!");
         }
@@ -1142,18 +1907,11 @@ impl SpircTask {
         let context_uri = frame.get_state().get_context_uri().to_owned();
         let tracks = frame.get_state().get_track();
         debug!("Frame has {:?} tracks", tracks.len());
-        if context_uri.starts_with("spotify:station:")
-            || context_uri.starts_with("spotify:dailymix:")
-        {
-            self.context_fut = self.resolve_station(&context_uri);
-        } else if self.config.autoplay {
-            info!("Fetching autoplay context uri");
-            // Get autoplay_station_uri for regular playlists
-            self.autoplay_fut = self.resolve_autoplay_uri(&context_uri);
-        }
+        self.resolve_continuation(&context_uri);
 
         self.state.set_playing_track_index(index);
         self.state.set_track(tracks.into_iter().cloned().collect());
+        self.unshuffled_tracks = None;
         self.state.set_context_uri(context_uri);
         // has_shuffle/repeat seem to always be true in these replace msgs,
         // but to replicate the behaviour of the Android client we have to
@@ -1250,7 +2008,25 @@ impl SpircTask {
             Some((track, index)) => {
                 self.state.set_playing_track_index(index);
 
+                let playing_autoplay = match self.autoplay_station {
+                    Some(ref station_uri) => {
+                        self.state.get_track()[index as usize].get_context() == station_uri
+                    }
+                    None => false,
+                };
+                if playing_autoplay && !self.playing_autoplay {
+                    if let Some(ref station_uri) = self.autoplay_station {
+                        info!("Continuing with autoplay of <{}>", station_uri);
+                        self.player.emit_autoplay_started_event(
+                            self.state.get_context_uri().to_owned(),
+                            station_uri.clone(),
+                        );
+                    }
+                }
+                self.playing_autoplay = playing_autoplay;
+
                 self.play_request_id = Some(self.player.load(track, start_playing, position_ms));
+                self.prefetch_upcoming_tracks();
 
                 self.update_state_position(position_ms);
                 if start_playing {
@@ -1285,27 +2061,99 @@ impl SpircTask {
             PlayStatus::kPlayStatusPlay => "kPlayStatusPlay",
         };
         trace!("Sending status to server: [{}]", status_string);
+        if self.device.get_is_active() {
+            self.resumable = false;
+            self.save_state_pending = self.config.persist_state;
+        }
         let mut cs = CommandSender::new(self, MessageType::kMessageTypeNotify);
         if let Some(s) = recipient {
             cs = cs.recipient(&s);
         }
         cs.send();
+        self.publish_state();
+    }
+
+    fn state_snapshot(&self) -> SpircState {
+        let status = match self.state.get_status() {
+            PlayStatus::kPlayStatusStop => SpircPlaybackStatus::Stopped,
+            PlayStatus::kPlayStatusLoading => SpircPlaybackStatus::Loading,
+            PlayStatus::kPlayStatusPlay => SpircPlaybackStatus::Playing,
+            PlayStatus::kPlayStatusPause => SpircPlaybackStatus::Paused,
+        };
+        let tracks = self
+            .state
+            .get_track()
+            .iter()
+            .map(|track_ref| SpircTrack {
+                id: SpotifyId::from_raw(track_ref.get_gid()).ok(),
+                uri: track_ref.get_uri().to_owned(),
+                queued: track_ref.get_queued(),
+            })
+            .collect();
+
+        SpircState {
+            active: self.device.get_is_active(),
+            status,
+            context_uri: self.state.get_context_uri().to_owned(),
+            tracks,
+            playing_track_index: self.state.get_playing_track_index(),
+            position_ms: self.state.get_position_ms(),
+            position_measured_at: self.state.get_position_measured_at(),
+            shuffle: self.state.get_shuffle(),
+            repeat: self.state.get_repeat(),
+            repeat_track: self.repeat_track,
+            volume: self.device.get_volume() as u16,
+            taken_over_by: if self.device.get_is_active() {
+                None
+            } else {
+                self.taken_over_by.clone()
+            },
+        }
+    }
+
+    fn publish_state(&mut self) {
+        if self.state_senders.is_empty() {
+            return;
+        }
+        let snapshot = self.state_snapshot();
+        self.state_senders
+            .retain(|sender| sender.unbounded_send(snapshot.clone()).is_ok());
     }
 
     fn set_volume(&mut self, volume: u16) {
         self.device.set_volume(volume as u32);
         self.mixer
-            .set_volume(volume_to_mixer(volume, &self.config.volume_ctrl));
-        if let Some(cache) = self.session.cache() {
+            .set_volume(self.config.volume_ctrl.to_mixer(volume));
+        if let Some(cache) = self.transport.cache() {
+            cache.save_volume(Volume { volume })
+        }
+        self.player.emit_volume_set_event(volume);
+    }
+
+    fn handle_mixer_volume(&mut self, mixer_volume: u16) {
+        let volume = self.config.volume_ctrl.from_mixer(mixer_volume);
+        // Readback is not exact, ignore what was set by ourselves
+        let current = self.device.get_volume() as i64;
+        if (volume as i64 - current).abs() <= (self.volume_step() / 2) as i64 {
+            return;
+        }
+
+        debug!("Mixer volume changed externally to {}", volume);
+        self.device.set_volume(volume as u32);
+        if let Some(cache) = self.transport.cache() {
             cache.save_volume(Volume { volume })
         }
         self.player.emit_volume_set_event(volume);
+        self.notify(None, true);
     }
 }
 
 impl Drop for SpircTask {
     fn drop(&mut self) {
-        debug!("drop Spirc[{}]", self.session.session_id());
+        debug!("drop Spirc[{}]", self.ident);
+        if self.save_state_pending {
+            self.save_state();
+        }
     }
 }
 
@@ -1335,12 +2183,21 @@ impl<'a> CommandSender<'a> {
         self
     }
 
-    #[allow(dead_code)]
     fn state(mut self, state: protocol::spirc::State) -> CommandSender<'a> {
         self.frame.set_state(state);
         self
     }
 
+    fn position(mut self, position_ms: u32) -> CommandSender<'a> {
+        self.frame.set_position(position_ms);
+        self
+    }
+
+    fn volume(mut self, volume: u16) -> CommandSender<'a> {
+        self.frame.set_volume(volume as u32);
+        self
+    }
+
     fn send(mut self) {
         if !self.frame.has_state() && self.spirc.device.get_is_active() {
             self.frame.set_state(self.spirc.state.clone());
@@ -1350,3 +2207,8 @@ impl<'a> CommandSender<'a> {
         assert!(send.is_ready());
     }
 }
+
+mod persist;
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,118 @@
+// The Connect state stored in the cache with --persist-state, to resume playback after a
+// restart. Besides the spirc State it holds what the SpircTask keeps outside of it.
+use base64;
+use protobuf::{self, Message};
+use serde_json;
+
+use super::ContextPaging;
+use crate::protocol::spirc::{State, TrackRef};
+use librespot_core::cache::Cache;
+
+const CACHE_KEY: &str = "connect_state.json";
+
+pub(super) struct PersistedState {
+    pub state: State,
+    pub repeat_track: bool,
+    pub unshuffled_tracks: Option<Vec<TrackRef>>,
+    pub context_paging: ContextPaging,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum StoredPaging {
+    Unresolved,
+    NextPage(String),
+    Complete,
+}
+
+// Protobuf messages are stored base64 encoded
+#[derive(Serialize, Deserialize)]
+struct StoredState {
+    state: String,
+    repeat_track: bool,
+    unshuffled_tracks: Option<Vec<String>>,
+    context_paging: StoredPaging,
+}
+
+fn encode<M: Message>(message: &M) -> Option<String> {
+    match message.write_to_bytes() {
+        Ok(data) => Some(base64::encode(&data)),
+        Err(err) => {
+            warn!("Unable to serialize Connect state: {}", err);
+            None
+        }
+    }
+}
+
+fn decode<M: Message>(data: &str) -> Option<M> {
+    let data = base64::decode(data).ok()?;
+    protobuf::parse_from_bytes(&data).ok()
+}
+
+impl PersistedState {
+    pub fn load(cache: &Cache) -> Option<PersistedState> {
+        let contents = cache.data(CACHE_KEY)?;
+        let stored: StoredState = match serde_json::from_slice(&contents) {
+            Ok(stored) => stored,
+            Err(err) => {
+                warn!("Unable to parse cached Connect state: {}", err);
+                return None;
+            }
+        };
+
+        let decoded = decode(&stored.state).and_then(|state| match stored.unshuffled_tracks {
+            Some(ref tracks) => tracks
+                .iter()
+                .map(|track| decode(track))
+                .collect::<Option<_>>()
+                .map(|tracks| (state, Some(tracks))),
+            None => Some((state, None)),
+        });
+        let (state, unshuffled_tracks) = match decoded {
+            Some(decoded) => decoded,
+            None => {
+                warn!("Unable to decode cached Connect state");
+                return None;
+            }
+        };
+
+        Some(PersistedState {
+            state,
+            repeat_track: stored.repeat_track,
+            unshuffled_tracks,
+            context_paging: match stored.context_paging {
+                StoredPaging::Unresolved => ContextPaging::Unresolved,
+                StoredPaging::NextPage(url) => ContextPaging::NextPage(url),
+                StoredPaging::Complete => ContextPaging::Complete,
+            },
+        })
+    }
+
+    pub fn save(&self, cache: &Cache) {
+        let unshuffled_tracks = match self.unshuffled_tracks {
+            Some(ref tracks) => match tracks.iter().map(encode).collect() {
+                Some(tracks) => Some(tracks),
+                None => return,
+            },
+            None => None,
+        };
+        let stored = StoredState {
+            state: match encode(&self.state) {
+                Some(state) => state,
+                None => return,
+            },
+            repeat_track: self.repeat_track,
+            unshuffled_tracks,
+            context_paging: match self.context_paging {
+                // the pages are resolved again, skipping the tracks which are already loaded
+                ContextPaging::Unresolved | ContextPaging::Fetching => StoredPaging::Unresolved,
+                ContextPaging::NextPage(ref url) => StoredPaging::NextPage(url.clone()),
+                ContextPaging::Complete => StoredPaging::Complete,
+            },
+        };
+        match serde_json::to_vec(&stored) {
+            Ok(contents) => cache.save_data(CACHE_KEY, &contents),
+            Err(err) => warn!("Unable to serialize Connect state: {}", err),
+        }
+    }
+}
@@ -0,0 +1,945 @@
+use futures::executor::{self, Notify, NotifyHandle, Spawn};
+use serde::de::DeserializeOwned;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+use super::*;
+use crate::context::PageContext;
+use crate::playback::mixer::softmixer::SoftMixer;
+use librespot_core::cache::Cache;
+use librespot_core::cache_storage::{CacheStorage, MemoryStorage, ReadSeek, StorageEntry};
+use librespot_core::mercury::MercuryError;
+
+const OTHER_DEVICE: &str = "other-device";
+
+struct MockTransport {
+    frames: Option<mpsc::UnboundedReceiver<Frame>>,
+    sent: Option<mpsc::UnboundedSender<Frame>>,
+    now_ms: Rc<Cell<i64>>,
+    // context json by uri, contexts which are missing never resolve
+    contexts: Rc<RefCell<HashMap<String, String>>>,
+    cache: Option<Arc<Cache>>,
+}
+
+impl MockTransport {
+    fn resolve<T: DeserializeOwned + 'static>(&self, uri: &str) -> ContextFuture<T> {
+        match self.contexts.borrow().get(uri) {
+            Some(json) => Box::new(future::result(
+                serde_json::from_str(json).map_err(|_| MercuryError),
+            )),
+            None => Box::new(future::empty()),
+        }
+    }
+}
+
+impl SpircTransport for MockTransport {
+    fn frames(&mut self) -> FrameStream {
+        Box::new(self.frames.take().unwrap().map_err(|_| MercuryError))
+    }
+
+    fn sender(&mut self) -> FrameSink {
+        Box::new(self.sent.take().unwrap().sink_map_err(|_| MercuryError))
+    }
+
+    fn device_id(&self) -> String {
+        String::from("test-device")
+    }
+
+    fn username(&self) -> String {
+        String::from("test-user")
+    }
+
+    fn is_invalid(&self) -> bool {
+        false
+    }
+
+    fn now_ms(&self) -> i64 {
+        self.now_ms.get()
+    }
+
+    fn cache(&self) -> Option<Arc<Cache>> {
+        self.cache.clone()
+    }
+
+    fn resolve_context(&self, uri: &str) -> ContextFuture<ResolvedContext> {
+        self.resolve(uri)
+    }
+
+    fn resolve_station(&self, uri: &str) -> ContextFuture<StationContext> {
+        self.resolve(uri)
+    }
+
+    fn resolve_next_page(&self, next_page_url: &str) -> ContextFuture<StationContext> {
+        self.resolve(next_page_url)
+    }
+
+    fn resolve_page(&self, page_url: &str) -> ContextFuture<PageContext> {
+        self.resolve(page_url)
+    }
+
+    fn resolve_autoplay_uri(&self, uri: &str) -> ContextFuture<String> {
+        self.resolve(uri)
+    }
+}
+
+// Keeps the cache in memory and counts the writes
+#[derive(Default)]
+struct CountingStorage {
+    storage: MemoryStorage,
+    writes: Mutex<Vec<String>>,
+}
+
+impl CountingStorage {
+    fn writes(&self, key: &str) -> usize {
+        self.writes
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|k| *k == key)
+            .count()
+    }
+}
+
+impl CacheStorage for CountingStorage {
+    fn read(&self, key: &str) -> Option<Box<dyn ReadSeek>> {
+        self.storage.read(key)
+    }
+
+    fn write(&self, key: &str, contents: &mut dyn Read) -> io::Result<()> {
+        self.writes.lock().unwrap().push(key.to_owned());
+        self.storage.write(key, contents)
+    }
+
+    fn remove(&self, key: &str) -> io::Result<()> {
+        self.storage.remove(key)
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<StorageEntry>> {
+        self.storage.list(prefix)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum PlayerCall {
+    Load(SpotifyId, bool, u32),
+    Preload(SpotifyId),
+    Play,
+    Pause,
+    Stop,
+    Seek(u32),
+}
+
+struct MockPlayer {
+    calls: Rc<RefCell<Vec<PlayerCall>>>,
+    play_request_id: Rc<Cell<u64>>,
+    events: RefCell<Option<PlayerEventChannel>>,
+}
+
+impl SpircPlayer for MockPlayer {
+    fn load(&mut self, track_id: SpotifyId, start_playing: bool, position_ms: u32) -> u64 {
+        self.calls
+            .borrow_mut()
+            .push(PlayerCall::Load(track_id, start_playing, position_ms));
+        self.play_request_id.set(self.play_request_id.get() + 1);
+        self.play_request_id.get()
+    }
+
+    fn preload(&self, track_id: SpotifyId) {
+        self.calls.borrow_mut().push(PlayerCall::Preload(track_id));
+    }
+
+    fn prefetch(&self, _: Vec<SpotifyId>) {}
+
+    fn play(&self) {
+        self.calls.borrow_mut().push(PlayerCall::Play);
+    }
+
+    fn pause(&self) {
+        self.calls.borrow_mut().push(PlayerCall::Pause);
+    }
+
+    fn stop(&self) {
+        self.calls.borrow_mut().push(PlayerCall::Stop);
+    }
+
+    fn seek(&self, position_ms: u32) {
+        self.calls.borrow_mut().push(PlayerCall::Seek(position_ms));
+    }
+
+    fn get_player_event_channel(&self) -> PlayerEventChannel {
+        self.events.borrow_mut().take().unwrap()
+    }
+
+    fn emit_volume_set_event(&self, _: u16) {}
+
+    fn emit_takeover_event(&self, _: String, _: String, _: bool) {}
+
+    fn emit_autoplay_started_event(&self, _: String, _: String) {}
+}
+
+struct NoopNotify;
+
+impl Notify for NoopNotify {
+    fn notify(&self, _: usize) {}
+}
+
+// A SpircTask connected to mocks, driven by polling it after every input.
+struct TestSpirc {
+    task: Spawn<SpircTask>,
+    spirc: Spirc,
+    frames: mpsc::UnboundedSender<Frame>,
+    sent: Spawn<mpsc::UnboundedReceiver<Frame>>,
+    player_events: mpsc::UnboundedSender<PlayerEvent>,
+    calls: Rc<RefCell<Vec<PlayerCall>>>,
+    play_request_id: Rc<Cell<u64>>,
+    now_ms: Rc<Cell<i64>>,
+    contexts: Rc<RefCell<HashMap<String, String>>>,
+}
+
+impl TestSpirc {
+    fn new(config: ConnectConfig) -> TestSpirc {
+        TestSpirc::with_cache(config, None)
+    }
+
+    fn with_cache(config: ConnectConfig, cache: Option<Arc<Cache>>) -> TestSpirc {
+        let (frames_tx, frames_rx) = mpsc::unbounded();
+        let (sent_tx, sent_rx) = mpsc::unbounded();
+        let (events_tx, events_rx) = mpsc::unbounded();
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let play_request_id = Rc::new(Cell::new(0));
+        let now_ms = Rc::new(Cell::new(1_000_000));
+        let contexts = Rc::new(RefCell::new(HashMap::new()));
+
+        let transport = MockTransport {
+            frames: Some(frames_rx),
+            sent: Some(sent_tx),
+            now_ms: now_ms.clone(),
+            contexts: contexts.clone(),
+            cache,
+        };
+        let player = MockPlayer {
+            calls: calls.clone(),
+            play_request_id: play_request_id.clone(),
+            events: RefCell::new(Some(events_rx)),
+        };
+        let mixer = Box::new(SoftMixer::open(None));
+        let (spirc, task) =
+            Spirc::with_transport(config, Box::new(player), mixer, Box::new(transport));
+
+        let mut test = TestSpirc {
+            task: executor::spawn(task),
+            spirc,
+            frames: frames_tx,
+            sent: executor::spawn(sent_rx),
+            player_events: events_tx,
+            calls,
+            play_request_id,
+            now_ms,
+            contexts,
+        };
+        test.run();
+        test
+    }
+
+    fn run(&mut self) {
+        let notify = NotifyHandle::from(Arc::new(NoopNotify));
+        let result = self.task.poll_future_notify(&notify, 0);
+        assert_eq!(result, Ok(Async::NotReady));
+    }
+
+    fn frame(&mut self, frame: Frame) {
+        self.frames.unbounded_send(frame).unwrap();
+        self.run();
+    }
+
+    fn player_event(&mut self, event: PlayerEvent) {
+        self.player_events.unbounded_send(event).unwrap();
+        self.run();
+    }
+
+    fn command(&mut self, command: impl FnOnce(&Spirc)) {
+        command(&self.spirc);
+        self.run();
+    }
+
+    fn add_context(&mut self, uri: &str, json: String) {
+        self.contexts.borrow_mut().insert(uri.to_owned(), json);
+    }
+
+    fn advance(&mut self, ms: i64) {
+        self.now_ms.set(self.now_ms.get() + ms);
+    }
+
+    // Frames sent since the last call
+    fn sent_frames(&mut self) -> Vec<Frame> {
+        let notify = NotifyHandle::from(Arc::new(NoopNotify));
+        let mut frames = Vec::new();
+        while let Ok(Async::Ready(Some(frame))) = self.sent.poll_stream_notify(&notify, 0) {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    fn take_calls(&mut self) -> Vec<PlayerCall> {
+        self.calls.borrow_mut().drain(..).collect()
+    }
+
+    fn spirc_task(&self) -> &SpircTask {
+        self.task.get_ref()
+    }
+
+    fn track_ids(&self) -> Vec<SpotifyId> {
+        self.spirc_task()
+            .state
+            .get_track()
+            .iter()
+            .map(|track| SpotifyId::from_raw(track.get_gid()).unwrap())
+            .collect()
+    }
+
+    fn queued_track_ids(&self) -> Vec<SpotifyId> {
+        self.spirc_task()
+            .state
+            .get_track()
+            .iter()
+            .filter(|track| track.get_queued())
+            .map(|track| SpotifyId::from_raw(track.get_gid()).unwrap())
+            .collect()
+    }
+
+    fn playing_track(&self) -> SpotifyId {
+        let state = &self.spirc_task().state;
+        let track = &state.get_track()[state.get_playing_track_index() as usize];
+        SpotifyId::from_raw(track.get_gid()).unwrap()
+    }
+
+    fn end_of_track(&mut self) {
+        let track_id = self.playing_track();
+        let play_request_id = self.play_request_id.get();
+        self.player_event(PlayerEvent::Playing {
+            play_request_id,
+            track_id,
+            position_ms: 0,
+            duration_ms: 180_000,
+        });
+        self.player_event(PlayerEvent::EndOfTrack {
+            play_request_id,
+            track_id,
+        });
+    }
+}
+
+fn track_id(n: u8) -> SpotifyId {
+    SpotifyId::from_raw(&[n; 16]).unwrap()
+}
+
+fn track_ref(n: u8) -> TrackRef {
+    let id = track_id(n);
+    let mut track = TrackRef::new();
+    track.set_gid(id.to_raw().to_vec());
+    track.set_uri(id.to_uri());
+    track
+}
+
+fn queued_track_ref(n: u8) -> TrackRef {
+    let mut track = track_ref(n);
+    track.set_queued(true);
+    track
+}
+
+fn track_ids(ns: &[u8]) -> Vec<SpotifyId> {
+    ns.iter().cloned().map(track_id).collect()
+}
+
+// Response of the context resolver for the given tracks
+fn context_json(uri: &str, tracks: &[u8]) -> String {
+    let tracks: Vec<_> = tracks
+        .iter()
+        .map(|n| json!({ "uri": track_id(*n).to_uri() }))
+        .collect();
+    json!({ "uri": uri, "pages": [{ "tracks": tracks }] }).to_string()
+}
+
+fn remote_frame(typ: MessageType) -> Frame {
+    let mut frame = Frame::new();
+    frame.set_version(1);
+    frame.set_ident(OTHER_DEVICE.to_owned());
+    frame.set_typ(typ);
+    frame.mut_device_state().set_name(String::from("Other"));
+    frame
+}
+
+fn load_frame(context_uri: &str, tracks: Vec<TrackRef>, index: u32) -> Frame {
+    let mut frame = remote_frame(MessageType::kMessageTypeLoad);
+    {
+        let state = frame.mut_state();
+        state.set_context_uri(context_uri.to_owned());
+        state.set_track(protobuf::RepeatedField::from_vec(tracks));
+        state.set_playing_track_index(index);
+        state.set_status(PlayStatus::kPlayStatusPlay);
+    }
+    frame
+}
+
+fn replace_frame(context_uri: &str, tracks: Vec<TrackRef>, index: u32) -> Frame {
+    let mut frame = load_frame(context_uri, tracks, index);
+    frame.set_typ(MessageType::kMessageTypeReplace);
+    frame
+}
+
+fn active_notify_frame(became_active_at: i64) -> Frame {
+    let mut frame = remote_frame(MessageType::kMessageTypeNotify);
+    frame.mut_device_state().set_is_active(true);
+    frame
+        .mut_device_state()
+        .set_became_active_at(became_active_at);
+    frame
+}
+
+const CONTEXT_URI: &str = "spotify:album:0000000000000000000000";
+
+fn playing_test_spirc(config: ConnectConfig) -> TestSpirc {
+    let mut test = TestSpirc::new(config);
+    let tracks = (1..=5).map(track_ref).collect();
+    test.frame(load_frame(CONTEXT_URI, tracks, 0));
+    test.sent_frames();
+    test.take_calls();
+    test
+}
+
+#[test]
+fn hello_is_sent_on_start() {
+    let mut test = TestSpirc::new(ConnectConfig::default());
+    let sent = test.sent_frames();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].get_typ(), MessageType::kMessageTypeHello);
+}
+
+#[test]
+fn load_frame_loads_the_playing_track() {
+    let mut test = TestSpirc::new(ConnectConfig::default());
+    test.sent_frames();
+
+    let tracks = (1..=3).map(track_ref).collect();
+    test.frame(load_frame(CONTEXT_URI, tracks, 1));
+
+    assert_eq!(
+        test.take_calls(),
+        vec![PlayerCall::Load(track_id(2), true, 0)]
+    );
+    assert!(test.spirc_task().device.get_is_active());
+
+    let sent = test.sent_frames();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].get_typ(), MessageType::kMessageTypeNotify);
+    assert_eq!(sent[0].get_state().get_playing_track_index(), 1);
+    assert_eq!(sent[0].get_state().get_context_uri(), CONTEXT_URI);
+}
+
+#[test]
+fn end_of_track_loads_the_next_track() {
+    let mut test = playing_test_spirc(ConnectConfig::default());
+    test.end_of_track();
+    assert_eq!(
+        test.take_calls(),
+        vec![PlayerCall::Load(track_id(2), true, 0)]
+    );
+}
+
+#[test]
+fn newer_active_device_takes_over() {
+    let mut test = playing_test_spirc(ConnectConfig::default());
+    test.advance(1000);
+    let now = test.now_ms.get();
+    test.frame(active_notify_frame(now));
+
+    assert!(!test.spirc_task().device.get_is_active());
+    assert_eq!(test.take_calls(), vec![PlayerCall::Stop]);
+}
+
+#[test]
+fn notify_of_older_active_device_is_ignored() {
+    let mut test = playing_test_spirc(ConnectConfig::default());
+    test.frame(active_notify_frame(0));
+
+    assert!(test.spirc_task().device.get_is_active());
+    assert!(test.take_calls().is_empty());
+}
+
+#[test]
+fn local_pause_pauses_the_player() {
+    let mut test = playing_test_spirc(ConnectConfig::default());
+    let play_request_id = test.play_request_id.get();
+    test.player_event(PlayerEvent::Playing {
+        play_request_id,
+        track_id: track_id(1),
+        position_ms: 0,
+        duration_ms: 180_000,
+    });
+    test.sent_frames();
+
+    test.command(Spirc::pause);
+
+    assert_eq!(test.take_calls(), vec![PlayerCall::Pause]);
+    let sent = test.sent_frames();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(
+        sent[0].get_state().get_status(),
+        PlayStatus::kPlayStatusPause
+    );
+}
+
+// Adds tracks 10 and 11 to the queue while track 1 of 1..5 is playing
+fn queue_test_spirc() -> TestSpirc {
+    let mut test = playing_test_spirc(ConnectConfig::default());
+    let tracks = vec![
+        track_ref(1),
+        queued_track_ref(10),
+        queued_track_ref(11),
+        track_ref(2),
+        track_ref(3),
+        track_ref(4),
+        track_ref(5),
+    ];
+    test.frame(replace_frame(CONTEXT_URI, tracks, 0));
+    test.take_calls();
+    test
+}
+
+#[test]
+fn queued_tracks_play_after_the_current_track() {
+    let mut test = queue_test_spirc();
+
+    test.end_of_track();
+    assert_eq!(
+        test.take_calls(),
+        vec![PlayerCall::Load(track_id(10), true, 0)]
+    );
+    test.end_of_track();
+    assert_eq!(
+        test.take_calls(),
+        vec![PlayerCall::Load(track_id(11), true, 0)]
+    );
+    test.end_of_track();
+    assert_eq!(
+        test.take_calls(),
+        vec![PlayerCall::Load(track_id(2), true, 0)]
+    );
+}
+
+#[test]
+fn queued_tracks_are_removed_once_played() {
+    let mut test = queue_test_spirc();
+
+    test.end_of_track();
+    assert_eq!(test.track_ids(), track_ids(&[1, 10, 11, 2, 3, 4, 5]));
+    test.end_of_track();
+    assert_eq!(test.track_ids(), track_ids(&[1, 11, 2, 3, 4, 5]));
+    test.end_of_track();
+    assert_eq!(test.track_ids(), track_ids(&[1, 2, 3, 4, 5]));
+    assert!(test.queued_track_ids().is_empty());
+    assert_eq!(test.playing_track(), track_id(2));
+}
+
+#[test]
+fn queued_tracks_survive_a_context_load() {
+    let mut test = queue_test_spirc();
+    let playlist = "spotify:playlist:0000000000000000000000";
+    test.add_context(playlist, context_json(playlist, &[20, 21, 22]));
+
+    test.command(|spirc| spirc.load_context(playlist, true));
+
+    assert_eq!(
+        test.take_calls(),
+        vec![PlayerCall::Load(track_id(20), true, 0)]
+    );
+    assert_eq!(test.spirc_task().state.get_context_uri(), playlist);
+    assert_eq!(test.track_ids(), track_ids(&[20, 10, 11, 21, 22]));
+    assert_eq!(test.queued_track_ids(), track_ids(&[10, 11]));
+
+    test.end_of_track();
+    assert_eq!(
+        test.take_calls(),
+        vec![PlayerCall::Load(track_id(10), true, 0)]
+    );
+}
+
+#[test]
+fn shuffle_tracks_keeps_the_current_track_first() {
+    let shuffle = |seed| {
+        let mut tracks: Vec<_> = (1..=20).map(track_ref).collect();
+        shuffle_tracks(&mut tracks, 4, seed);
+        tracks
+            .iter()
+            .map(|track| SpotifyId::from_raw(track.get_gid()).unwrap())
+            .collect::<Vec<_>>()
+    };
+
+    let shuffled = shuffle(42);
+    assert_eq!(shuffled[0], track_id(5));
+    assert_eq!(shuffled, shuffle(42));
+    assert_ne!(shuffled, shuffle(43));
+    assert_eq!(shuffled.len(), 20);
+    for id in track_ids(&(1..=20).collect::<Vec<_>>()) {
+        assert!(shuffled.contains(&id));
+    }
+}
+
+fn shuffle_test_spirc(seed: u64) -> TestSpirc {
+    let mut test = TestSpirc::new(ConnectConfig::default());
+    test.task.get_mut().rng = StdRng::seed_from_u64(seed);
+    let tracks = (1..=10).map(track_ref).collect();
+    test.frame(load_frame(CONTEXT_URI, tracks, 2));
+    test.take_calls();
+    test
+}
+
+#[test]
+fn shuffle_order_depends_on_the_injected_rng() {
+    let mut a = shuffle_test_spirc(7);
+    let mut b = shuffle_test_spirc(7);
+    a.command(|spirc| spirc.shuffle(true));
+    b.command(|spirc| spirc.shuffle(true));
+
+    assert_eq!(a.track_ids(), b.track_ids());
+    assert_eq!(a.playing_track(), track_id(3));
+    assert_eq!(a.spirc_task().state.get_playing_track_index(), 0);
+}
+
+#[test]
+fn unshuffle_restores_the_original_order() {
+    let mut test = shuffle_test_spirc(7);
+    test.command(|spirc| spirc.shuffle(true));
+    test.end_of_track();
+    let playing = test.playing_track();
+    assert_ne!(test.track_ids(), track_ids(&(1..=10).collect::<Vec<_>>()));
+
+    test.command(|spirc| spirc.shuffle(false));
+
+    assert_eq!(test.track_ids(), track_ids(&(1..=10).collect::<Vec<_>>()));
+    assert_eq!(test.playing_track(), playing);
+}
+
+#[test]
+fn unshuffle_without_stored_order_resolves_the_context() {
+    let mut test = TestSpirc::new(ConnectConfig::default());
+    test.add_context(CONTEXT_URI, context_json(CONTEXT_URI, &[1, 2, 3, 4, 5]));
+    let tracks = [3, 1, 5, 2, 4].iter().cloned().map(track_ref).collect();
+    let mut frame = load_frame(CONTEXT_URI, tracks, 0);
+    frame.mut_state().set_shuffle(true);
+    test.frame(frame);
+
+    test.command(|spirc| spirc.shuffle(false));
+
+    assert_eq!(test.track_ids(), track_ids(&[1, 2, 3, 4, 5]));
+    assert_eq!(test.playing_track(), track_id(3));
+}
+
+#[test]
+fn refused_takeover_claims_playback_back() {
+    let config = ConnectConfig {
+        takeover_policy: TakeoverPolicy::AllowList(Vec::new()),
+        ..ConnectConfig::default()
+    };
+
+    let mut test = playing_test_spirc(config);
+    test.advance(1000);
+    let now = test.now_ms.get();
+    test.frame(active_notify_frame(now));
+
+    assert!(test.spirc_task().device.get_is_active());
+    assert!(test.take_calls().is_empty());
+    let sent = test.sent_frames();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].get_device_state().get_became_active_at(), now);
+}
+
+#[test]
+fn device_claiming_playback_again_is_handed_playback() {
+    let config = ConnectConfig {
+        takeover_policy: TakeoverPolicy::AllowList(Vec::new()),
+        ..ConnectConfig::default()
+    };
+
+    let mut test = playing_test_spirc(config);
+    test.advance(1000);
+    test.frame(active_notify_frame(test.now_ms.get()));
+    test.advance(1000);
+    test.frame(active_notify_frame(test.now_ms.get()));
+
+    assert!(!test.spirc_task().device.get_is_active());
+    assert_eq!(test.take_calls(), vec![PlayerCall::Stop]);
+}
+
+#[test]
+fn takeover_is_refused_again_after_the_reclaim_window() {
+    let config = ConnectConfig {
+        takeover_policy: TakeoverPolicy::AllowList(Vec::new()),
+        ..ConnectConfig::default()
+    };
+
+    let mut test = playing_test_spirc(config);
+    test.advance(1000);
+    test.frame(active_notify_frame(test.now_ms.get()));
+    test.advance(TAKEOVER_RECLAIM_WINDOW_MS);
+    test.frame(active_notify_frame(test.now_ms.get()));
+
+    assert!(test.spirc_task().device.get_is_active());
+    assert!(test.take_calls().is_empty());
+}
+
+fn episode_uri(n: u8) -> String {
+    format!("spotify:episode:{}", track_id(n).to_base62())
+}
+
+#[test]
+fn local_load_of_restricted_context_is_ignored() {
+    let mut config = ConnectConfig::default();
+    config.capabilities.content = ContentRestriction::Podcasts;
+    let mut test = TestSpirc::new(config);
+    test.add_context(CONTEXT_URI, context_json(CONTEXT_URI, &[1, 2, 3]));
+
+    test.command(|spirc| spirc.load_context(CONTEXT_URI, true));
+
+    assert!(!test.spirc_task().device.get_is_active());
+    assert!(test.take_calls().is_empty());
+}
+
+#[test]
+fn restricted_tracks_of_a_local_load_are_skipped() {
+    let mut config = ConnectConfig::default();
+    config.capabilities.content = ContentRestriction::Music;
+    let mut test = TestSpirc::new(config);
+    let tracks = vec![
+        json!({ "uri": track_id(1).to_uri() }),
+        json!({ "uri": episode_uri(2) }),
+        json!({ "uri": track_id(3).to_uri() }),
+    ];
+    let context = json!({ "uri": CONTEXT_URI, "pages": [{ "tracks": tracks }] });
+    test.add_context(CONTEXT_URI, context.to_string());
+
+    test.command(|spirc| spirc.load_context(CONTEXT_URI, true));
+
+    assert_eq!(test.track_ids(), track_ids(&[1, 3]));
+    assert_eq!(test.playing_track(), track_id(1));
+}
+
+#[test]
+fn restricted_tracks_of_autoplay_stations_are_skipped() {
+    const STATION_URI: &str = "spotify:station:album:0000000000000000000000";
+    let config = ConnectConfig {
+        autoplay: true,
+        capabilities: ConnectCapabilities {
+            content: ContentRestriction::Music,
+            ..ConnectCapabilities::default()
+        },
+        ..ConnectConfig::default()
+    };
+
+    let mut test = TestSpirc::new(config);
+    test.add_context(CONTEXT_URI, json!(STATION_URI).to_string());
+    let station = json!({
+        "uri": STATION_URI,
+        "next_page_url": "",
+        "tracks": [
+            { "uri": episode_uri(6), "original_gid": track_id(6).to_base62(), "uid": "" },
+            { "uri": track_id(7).to_uri(), "original_gid": track_id(7).to_base62(), "uid": "" },
+        ],
+    });
+    test.add_context(STATION_URI, station.to_string());
+
+    let tracks = (1..=5).map(track_ref).collect();
+    test.frame(load_frame(CONTEXT_URI, tracks, 0));
+
+    let task = test.spirc_task();
+    assert_eq!(task.autoplay_station, Some(STATION_URI.to_owned()));
+    let station_tracks = &task.context.as_ref().unwrap().tracks;
+    assert_eq!(station_tracks.len(), 1);
+    assert_eq!(station_tracks[0].get_uri(), track_id(7).to_uri());
+}
+
+#[test]
+fn gid_only_episodes_of_a_show_are_allowed() {
+    const SHOW_URI: &str = "spotify:show:0000000000000000000000";
+    let mut config = ConnectConfig::default();
+    config.capabilities.content = ContentRestriction::Podcasts;
+    let mut test = TestSpirc::new(config);
+    let tracks = (1..=3)
+        .map(|n| {
+            let mut track = TrackRef::new();
+            track.set_gid(track_id(n).to_raw().to_vec());
+            track
+        })
+        .collect();
+
+    test.frame(load_frame(SHOW_URI, tracks, 0));
+
+    assert!(test.spirc_task().device.get_is_active());
+    assert_eq!(test.track_ids(), track_ids(&[1, 2, 3]));
+}
+
+const CONNECT_STATE_KEY: &str = "connect_state.json";
+
+fn persisting_test_spirc(storage: &Arc<CountingStorage>) -> TestSpirc {
+    let config = ConnectConfig {
+        persist_state: true,
+        ..ConnectConfig::default()
+    };
+    let cache = Cache::with_storage(storage.clone(), false);
+    TestSpirc::with_cache(config, Some(Arc::new(cache)))
+}
+
+#[test]
+fn state_saves_are_debounced() {
+    let storage = Arc::new(CountingStorage::default());
+    let mut test = persisting_test_spirc(&storage);
+    let tracks = (1..=5).map(track_ref).collect();
+    test.frame(load_frame(CONTEXT_URI, tracks, 0));
+    assert_eq!(storage.writes(CONNECT_STATE_KEY), 1);
+
+    test.command(Spirc::pause);
+    test.command(Spirc::play);
+    assert_eq!(storage.writes(CONNECT_STATE_KEY), 1);
+
+    test.advance(SAVE_STATE_INTERVAL_MS);
+    test.command(Spirc::pause);
+    assert_eq!(storage.writes(CONNECT_STATE_KEY), 2);
+}
+
+#[test]
+fn pending_state_is_saved_when_dropped() {
+    let storage = Arc::new(CountingStorage::default());
+    let mut test = persisting_test_spirc(&storage);
+    let tracks = (1..=5).map(track_ref).collect();
+    test.frame(load_frame(CONTEXT_URI, tracks, 0));
+    test.command(Spirc::pause);
+    assert_eq!(storage.writes(CONNECT_STATE_KEY), 1);
+
+    drop(test);
+    assert_eq!(storage.writes(CONNECT_STATE_KEY), 2);
+}
+
+#[test]
+fn persisted_state_restores_shuffle_repeat_and_paging() {
+    let storage = Arc::new(CountingStorage::default());
+    let mut test = persisting_test_spirc(&storage);
+    let tracks: Vec<_> = (1..=5)
+        .map(|n| json!({ "uri": track_id(n).to_uri() }))
+        .collect();
+    let context = json!({
+        "uri": CONTEXT_URI,
+        "pages": [{ "tracks": tracks, "next_page_url": "hm://next-page" }],
+    });
+    test.add_context(CONTEXT_URI, context.to_string());
+    test.command(|spirc| spirc.load_context(CONTEXT_URI, true));
+    test.command(|spirc| spirc.shuffle(true));
+    test.command(|spirc| spirc.repeat_track(true));
+    let shuffled = test.track_ids();
+    let unshuffled_tracks = test.spirc_task().unshuffled_tracks.clone();
+    drop(test);
+
+    let test = persisting_test_spirc(&storage);
+
+    assert_eq!(test.track_ids(), shuffled);
+    let task = test.spirc_task();
+    assert!(task.state.get_shuffle());
+    assert!(task.repeat_track);
+    assert!(unshuffled_tracks.is_some());
+    assert_eq!(task.unshuffled_tracks, unshuffled_tracks);
+    assert_eq!(
+        task.context_paging,
+        ContextPaging::NextPage(String::from("hm://next-page"))
+    );
+}
+
+#[test]
+fn volume_steps_and_step_size_are_separate() {
+    let config = ConnectConfig {
+        volume: 0x8000,
+        volume_steps: 10,
+        volume_step_size: 1000,
+        ..ConnectConfig::default()
+    };
+
+    let mut test = TestSpirc::new(config);
+
+    let hello = test.sent_frames().remove(0);
+    let steps = hello
+        .get_device_state()
+        .get_capabilities()
+        .iter()
+        .find(|c| c.get_typ() == protocol::spirc::CapabilityType::kVolumeSteps)
+        .unwrap()
+        .get_intValue()
+        .to_vec();
+    assert_eq!(steps, vec![10]);
+
+    test.frame(load_frame(CONTEXT_URI, vec![track_ref(1)], 0));
+    test.command(|spirc| spirc.volume_up());
+    assert_eq!(test.spirc_task().device.get_volume(), 0x8000 + 1000);
+    test.command(|spirc| spirc.volume_down());
+    test.command(|spirc| spirc.volume_down());
+    assert_eq!(test.spirc_task().device.get_volume(), 0x8000 - 1000);
+}
+
+fn repeat_frame(repeat: bool) -> Frame {
+    let mut frame = remote_frame(MessageType::kMessageTypeRepeat);
+    frame.mut_state().set_repeat(repeat);
+    frame
+}
+
+#[test]
+fn remote_repeat_cycles_through_repeat_track() {
+    let mut test = playing_test_spirc(ConnectConfig::default());
+
+    test.frame(repeat_frame(true));
+    assert!(test.spirc_task().state.get_repeat());
+    assert!(!test.spirc_task().repeat_track);
+
+    test.frame(repeat_frame(false));
+    assert!(test.spirc_task().state.get_repeat());
+    assert!(test.spirc_task().repeat_track);
+
+    test.frame(repeat_frame(false));
+    assert!(!test.spirc_task().state.get_repeat());
+    assert!(!test.spirc_task().repeat_track);
+}
+
+#[test]
+fn repeat_track_reloads_the_track_at_its_end() {
+    let mut test = playing_test_spirc(ConnectConfig::default());
+    test.command(|spirc| spirc.repeat_track(true));
+    test.take_calls();
+
+    test.end_of_track();
+
+    assert_eq!(
+        test.take_calls(),
+        vec![PlayerCall::Load(track_id(1), true, 0)]
+    );
+    assert_eq!(test.playing_track(), track_id(1));
+}
+
+#[test]
+fn repeat_track_preloads_the_same_track() {
+    let mut test = playing_test_spirc(ConnectConfig::default());
+    test.command(|spirc| spirc.repeat_track(true));
+    let play_request_id = test.play_request_id.get();
+    test.player_event(PlayerEvent::Playing {
+        play_request_id,
+        track_id: track_id(1),
+        position_ms: 0,
+        duration_ms: 180_000,
+    });
+    test.take_calls();
+
+    test.player_event(PlayerEvent::TimeToPreloadNextTrack {
+        play_request_id,
+        track_id: track_id(1),
+    });
+
+    assert_eq!(test.take_calls(), vec![PlayerCall::Preload(track_id(1))]);
+}
@@ -0,0 +1,123 @@
+use futures::{Future, Sink, Stream};
+use protobuf::{self, Message};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::context::{self, ContextFuture, PageContext, ResolvedContext, StationContext};
+use crate::protocol::spirc::Frame;
+use librespot_core::cache::Cache;
+use librespot_core::mercury::MercuryError;
+use librespot_core::session::Session;
+use librespot_core::util::url_encode;
+
+pub type FrameStream = Box<dyn Stream<Item = Frame, Error = MercuryError>>;
+pub type FrameSink = Box<dyn Sink<SinkItem = Frame, SinkError = MercuryError>>;
+
+// Connects the SpircTask to Spotify: carries spirc frames between this device and the other
+// Connect devices of the user, resolves contexts and provides the server clock and the cache.
+pub trait SpircTransport {
+    // Frames sent by any device of the user, including the ones sent by this device.
+    // Called once when the SpircTask is created.
+    fn frames(&mut self) -> FrameStream;
+    // Called once when the SpircTask is created.
+    fn sender(&mut self) -> FrameSink;
+
+    fn device_id(&self) -> String;
+    fn username(&self) -> String;
+    // The SpircTask finishes once the connection is no longer usable.
+    fn is_invalid(&self) -> bool;
+    // Current time of the Spotify servers in milliseconds since the epoch.
+    fn now_ms(&self) -> i64;
+    fn cache(&self) -> Option<Arc<Cache>>;
+
+    fn resolve_context(&self, uri: &str) -> ContextFuture<ResolvedContext>;
+    fn resolve_station(&self, uri: &str) -> ContextFuture<StationContext>;
+    fn resolve_next_page(&self, next_page_url: &str) -> ContextFuture<StationContext>;
+    fn resolve_page(&self, page_url: &str) -> ContextFuture<PageContext>;
+    fn resolve_autoplay_uri(&self, uri: &str) -> ContextFuture<String>;
+}
+
+// Exchanges frames through the Mercury remote channel of the session's user.
+pub struct MercuryTransport {
+    session: Session,
+    uri: String,
+}
+
+impl MercuryTransport {
+    pub fn new(session: &Session) -> MercuryTransport {
+        // Uri updated in response to issue #288
+        debug!("canonical_username: {}", url_encode(&session.username()));
+        let uri = format!("hm://remote/user/{}/", url_encode(&session.username()));
+
+        MercuryTransport {
+            session: session.clone(),
+            uri,
+        }
+    }
+}
+
+impl SpircTransport for MercuryTransport {
+    fn frames(&mut self) -> FrameStream {
+        let subscription = self.session.mercury().subscribe(&self.uri as &str);
+        let subscription = subscription
+            .map(|stream| stream.map_err(|_| MercuryError))
+            .flatten_stream();
+        Box::new(subscription.map(|response| -> Frame {
+            let data = response.payload.first().unwrap();
+            protobuf::parse_from_bytes(data).unwrap()
+        }))
+    }
+
+    fn sender(&mut self) -> FrameSink {
+        Box::new(
+            self.session
+                .mercury()
+                .sender(self.uri.clone())
+                .with(|frame: Frame| Ok(frame.write_to_bytes().unwrap())),
+        )
+    }
+
+    fn device_id(&self) -> String {
+        self.session.device_id().to_owned()
+    }
+
+    fn username(&self) -> String {
+        self.session.username()
+    }
+
+    fn is_invalid(&self) -> bool {
+        self.session.is_invalid()
+    }
+
+    fn now_ms(&self) -> i64 {
+        let dur = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(dur) => dur,
+            Err(err) => err.duration(),
+        };
+        (dur.as_secs() as i64 + self.session.time_delta()) * 1000 + dur.subsec_millis() as i64
+    }
+
+    fn cache(&self) -> Option<Arc<Cache>> {
+        self.session.cache().cloned()
+    }
+
+    fn resolve_context(&self, uri: &str) -> ContextFuture<ResolvedContext> {
+        context::resolve_context(&self.session, uri)
+    }
+
+    fn resolve_station(&self, uri: &str) -> ContextFuture<StationContext> {
+        context::resolve_station(&self.session, uri)
+    }
+
+    fn resolve_next_page(&self, next_page_url: &str) -> ContextFuture<StationContext> {
+        context::resolve_next_page(&self.session, next_page_url)
+    }
+
+    fn resolve_page(&self, page_url: &str) -> ContextFuture<PageContext> {
+        context::resolve_page(&self.session, page_url)
+    }
+
+    fn resolve_autoplay_uri(&self, uri: &str) -> ContextFuture<String> {
+        context::resolve_autoplay_uri(&self.session, uri)
+    }
+}
@@ -7,10 +7,8 @@ use protobuf::ProtobufEnum;
 use serde;
 use serde_json;
 use sha1::{Digest, Sha1};
-use std::fs::File;
 use std::io::{self, Read, Write};
 use std::ops::FnOnce;
-use std::path::Path;
 
 use crate::protocol::authentication::AuthenticationType;
 
@@ -113,26 +111,17 @@ impl Credentials {
         }
     }
 
-    fn from_reader<R: Read>(mut reader: R) -> Credentials {
+    pub(crate) fn from_reader<R: Read>(mut reader: R) -> Credentials {
         let mut contents = String::new();
         reader.read_to_string(&mut contents).unwrap();
 
         serde_json::from_str(&contents).unwrap()
     }
 
-    pub(crate) fn from_file<P: AsRef<Path>>(path: P) -> Option<Credentials> {
-        File::open(path).ok().map(Credentials::from_reader)
-    }
-
-    fn save_to_writer<W: Write>(&self, writer: &mut W) {
+    pub(crate) fn save_to_writer<W: Write>(&self, writer: &mut W) {
         let contents = serde_json::to_string(&self.clone()).unwrap();
         writer.write_all(contents.as_bytes()).unwrap();
     }
-
-    pub(crate) fn save_to_file<P: AsRef<Path>>(&self, path: P) {
-        let mut file = File::create(path).unwrap();
-        self.save_to_writer(&mut file)
-    }
 }
 
 fn serialize_protobuf_enum<T, S>(v: &T, ser: S) -> Result<S::Ok, S::Error>
@@ -1,93 +1,383 @@
-use std::fs;
-use std::fs::File;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
 use std::io;
-use std::io::Read;
-use std::path::Path;
+use std::io::{BufRead, BufReader, Read, Write};
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
 use crate::authentication::Credentials;
-use crate::spotify_id::FileId;
+use crate::cache_storage::{CacheStorage, FilesystemStorage};
+use crate::spotify_id::{FileId, SpotifyId};
 use crate::volume::Volume;
 
+pub use crate::cache_storage::ReadSeek;
+
+// A partially downloaded file and its downloaded ranges as (offset, length) pairs
+pub type PartialFile = (Box<dyn ReadSeek>, Vec<(usize, usize)>);
+
 #[derive(Clone)]
 pub struct Cache {
-    root: PathBuf,
+    storage: Arc<dyn CacheStorage>,
     use_audio_cache: bool,
+    // tracks of files which are still being downloaded, written once the file is complete
+    pending_tracks: Arc<Mutex<HashMap<FileId, SpotifyId>>>,
 }
 
-fn mkdir_existing(path: &Path) -> io::Result<()> {
-    fs::create_dir(path).or_else(|err| {
-        if err.kind() == io::ErrorKind::AlreadyExists {
-            Ok(())
-        } else {
-            Err(err)
+#[derive(Debug, Clone)]
+pub struct CachedFile {
+    pub file_id: FileId,
+    pub track_uri: Option<String>,
+    pub size: u64,
+    pub modified: SystemTime,
+    // an incomplete download which may be resumed later
+    pub partial: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileIntegrity {
+    Valid,
+    Corrupt,
+    Unknown,
+}
+
+fn checksum<R: Read>(mut reader: R) -> io::Result<String> {
+    let mut hasher = Sha1::new();
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
         }
-    })
+        hasher.input(&buffer[..n]);
+    }
+    Ok(hex_string(&hasher.result()))
+}
+
+fn hex_string(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
 }
 
 impl Cache {
     pub fn new(location: PathBuf, use_audio_cache: bool) -> Cache {
-        mkdir_existing(&location).unwrap();
-        mkdir_existing(&location.join("files")).unwrap();
+        Cache::with_storage(Arc::new(FilesystemStorage::new(location)), use_audio_cache)
+    }
 
+    pub fn with_storage(storage: Arc<dyn CacheStorage>, use_audio_cache: bool) -> Cache {
         Cache {
-            root: location,
-            use_audio_cache: use_audio_cache,
+            storage,
+            use_audio_cache,
+            pending_tracks: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn use_audio_cache(&self) -> bool {
+        self.use_audio_cache
+    }
+
+    fn read_string(&self, key: &str) -> Option<String> {
+        let mut contents = String::new();
+        self.storage.read(key)?.read_to_string(&mut contents).ok()?;
+        Some(contents)
+    }
+
+    fn read_bytes(&self, key: &str) -> Option<Vec<u8>> {
+        let mut contents = Vec::new();
+        self.storage.read(key)?.read_to_end(&mut contents).ok()?;
+        Some(contents)
+    }
+
+    fn write_bytes(&self, key: &str, mut contents: &[u8]) {
+        if let Err(err) = self.storage.write(key, &mut contents) {
+            warn!("Unable to write {} to cache: {}", key, err);
         }
     }
 }
 
 impl Cache {
-    fn credentials_path(&self) -> PathBuf {
-        self.root.join("credentials.json")
-    }
+    const CREDENTIALS_KEY: &'static str = "credentials.json";
 
     pub fn credentials(&self) -> Option<Credentials> {
-        let path = self.credentials_path();
-        Credentials::from_file(path)
+        self.storage
+            .read(Self::CREDENTIALS_KEY)
+            .map(Credentials::from_reader)
     }
 
     pub fn save_credentials(&self, cred: &Credentials) {
-        let path = self.credentials_path();
-        cred.save_to_file(&path);
+        let mut contents = Vec::new();
+        cred.save_to_writer(&mut contents);
+        self.write_bytes(Self::CREDENTIALS_KEY, &contents);
+    }
+
+    pub fn remove_credentials(&self) -> io::Result<()> {
+        self.storage.remove(Self::CREDENTIALS_KEY)
     }
 }
 
 // cache volume to root/volume
 impl Cache {
-    fn volume_path(&self) -> PathBuf {
-        self.root.join("volume")
-    }
+    const VOLUME_KEY: &'static str = "volume";
 
     pub fn volume(&self) -> Option<u16> {
-        let path = self.volume_path();
-        Volume::from_file(path)
+        self.storage.read(Self::VOLUME_KEY).map(Volume::from_reader)
     }
 
     pub fn save_volume(&self, volume: Volume) {
-        let path = self.volume_path();
-        volume.save_to_file(&path);
+        let mut contents = Vec::new();
+        volume.save_to_writer(&mut contents);
+        self.write_bytes(Self::VOLUME_KEY, &contents);
+    }
+}
+
+// cache data of other librespot components to root/<key>, e.g. the Connect state
+impl Cache {
+    pub fn data(&self, key: &str) -> Option<Vec<u8>> {
+        self.read_bytes(key)
+    }
+
+    pub fn save_data(&self, key: &str, contents: &[u8]) {
+        self.write_bytes(key, contents);
     }
 }
 
 impl Cache {
-    fn file_path(&self, file: FileId) -> PathBuf {
+    fn file_key(&self, file: FileId) -> String {
         let name = file.to_base16();
-        self.root.join("files").join(&name[0..2]).join(&name[2..])
+        format!("files/{}/{}", &name[0..2], &name[2..])
+    }
+
+    // sha1 of the file contents, written along with the file to allow verifying it later
+    fn checksum_key(&self, file: FileId) -> String {
+        self.file_key(file) + ".sha1"
     }
 
-    pub fn file(&self, file: FileId) -> Option<File> {
-        File::open(self.file_path(file)).ok()
+    // uri of the track the file belongs to
+    fn track_key(&self, file: FileId) -> String {
+        self.file_key(file) + ".track"
+    }
+
+    // incomplete download along with the byte ranges of it which are present
+    fn partial_key(&self, file: FileId) -> String {
+        self.file_key(file) + ".partial"
+    }
+
+    fn partial_ranges_key(&self, file: FileId) -> String {
+        self.file_key(file) + ".ranges"
+    }
+
+    pub fn file(&self, file: FileId) -> Option<Box<dyn ReadSeek>> {
+        self.storage.read(&self.file_key(file))
     }
 
     pub fn save_file(&self, file: FileId, contents: &mut dyn Read) {
         if self.use_audio_cache {
-            let path = self.file_path(file);
+            let key = self.file_key(file);
+            if let Err(err) = self.storage.write(&key, contents) {
+                warn!("Unable to save {} to cache: {}", file, err);
+                return;
+            }
+
+            match self.file(file).map(checksum) {
+                Some(Ok(sum)) => self.write_bytes(&self.checksum_key(file), sum.as_bytes()),
+                _ => warn!("Unable to compute checksum of {}", file),
+            }
+
+            if let Some(track) = self.pending_tracks.lock().unwrap().remove(&file) {
+                self.write_track(file, track);
+            }
+
+            self.remove_partial_file(file);
+        }
+    }
+
+    pub fn partial_file(&self, file: FileId) -> Option<PartialFile> {
+        let ranges_file = self.storage.read(&self.partial_ranges_key(file))?;
+        let mut ranges = Vec::new();
+        for line in BufReader::new(ranges_file).lines() {
+            let line = line.ok()?;
+            let mut parts = line.split_whitespace();
+            let start = parts.next()?.parse::<usize>().ok()?;
+            let length = parts.next()?.parse::<usize>().ok()?;
+            ranges.push((start, length));
+        }
+
+        let partial = self.storage.read(&self.partial_key(file))?;
+        Some((partial, ranges))
+    }
+
+    pub fn save_partial_file(
+        &self,
+        file: FileId,
+        contents: &mut dyn Read,
+        ranges: &[(usize, usize)],
+    ) {
+        if self.use_audio_cache {
+            if let Err(err) = self.write_partial_file(file, contents, ranges) {
+                warn!(
+                    "Unable to save partial download of {} to cache: {}",
+                    file, err
+                );
+            }
+        }
+    }
+
+    // Both are written to temporary entries and then moved in place, the data first. Until the
+    // ranges are moved as well, the previous ranges remain, which describe a part of the new
+    // data, as a download only ever grows. So an interrupted save never leaves ranges behind
+    // which claim data that isn't there.
+    fn write_partial_file(
+        &self,
+        file: FileId,
+        contents: &mut dyn Read,
+        ranges: &[(usize, usize)],
+    ) -> io::Result<()> {
+        let partial_key = self.partial_key(file);
+        let ranges_key = self.partial_ranges_key(file);
+        let partial_temp_key = partial_key.clone() + ".tmp";
+        let ranges_temp_key = ranges_key.clone() + ".tmp";
+
+        let mut ranges_contents = Vec::new();
+        for &(start, length) in ranges {
+            writeln!(ranges_contents, "{} {}", start, length)?;
+        }
+        self.storage.write(&partial_temp_key, contents)?;
+        self.storage
+            .write(&ranges_temp_key, &mut &ranges_contents[..])?;
+
+        self.storage.rename(&partial_temp_key, &partial_key)?;
+        self.storage.rename(&ranges_temp_key, &ranges_key)
+    }
+
+    pub fn remove_partial_file(&self, file: FileId) {
+        let _ = self.storage.remove(&self.partial_ranges_key(file));
+        let _ = self.storage.remove(&self.partial_key(file));
+        let _ = self
+            .storage
+            .remove(&(self.partial_ranges_key(file) + ".tmp"));
+        let _ = self.storage.remove(&(self.partial_key(file) + ".tmp"));
+    }
+
+    fn remove_cached_file(&self, file: &CachedFile) -> io::Result<()> {
+        if file.partial {
+            self.storage
+                .remove(&self.partial_ranges_key(file.file_id))?;
+            self.storage.remove(&self.partial_key(file.file_id))
+        } else {
+            self.remove_file(file.file_id)
+        }
+    }
+
+    // Records the track a file belongs to. For files which are not cached yet, it is only
+    // written once the download completes, so abandoned downloads leave no sidecar behind.
+    pub fn save_file_track(&self, file: FileId, track: SpotifyId) {
+        if self.use_audio_cache {
+            if self.file(file).is_some() {
+                self.write_track(file, track);
+            } else {
+                self.pending_tracks.lock().unwrap().insert(file, track);
+            }
+        }
+    }
+
+    // Drops the track recorded for a file whose download failed or was cancelled.
+    pub fn forget_file_track(&self, file: FileId) {
+        self.pending_tracks.lock().unwrap().remove(&file);
+    }
+
+    fn write_track(&self, file: FileId, track: SpotifyId) {
+        self.write_bytes(&self.track_key(file), track.to_uri().as_bytes());
+    }
+
+    pub fn remove_file(&self, file: FileId) -> io::Result<()> {
+        self.pending_tracks.lock().unwrap().remove(&file);
+        self.storage.remove(&self.file_key(file))?;
+        self.storage.remove(&self.checksum_key(file))?;
+        self.storage.remove(&self.track_key(file))?;
+        self.storage.remove(&self.partial_ranges_key(file))?;
+        self.storage.remove(&self.partial_key(file))
+    }
 
-            mkdir_existing(path.parent().unwrap()).unwrap();
+    pub fn verify_file(&self, file: FileId) -> FileIntegrity {
+        let expected = match self.read_string(&self.checksum_key(file)) {
+            Some(sum) => sum,
+            None => return FileIntegrity::Unknown,
+        };
 
-            let mut cache_file = File::create(path).unwrap();
-            ::std::io::copy(contents, &mut cache_file).unwrap();
+        match self.file(file).map(checksum) {
+            Some(Ok(ref sum)) if *sum == expected.trim() => FileIntegrity::Valid,
+            _ => FileIntegrity::Corrupt,
         }
     }
 }
+
+// inspection and cleanup of root/files
+impl Cache {
+    // Lists the cached audio files, including partial downloads.
+    pub fn files(&self) -> io::Result<Vec<CachedFile>> {
+        let mut files = Vec::new();
+
+        for entry in self.storage.list("files/")? {
+            let mut name = entry.key["files/".len()..].replace('/', "");
+            let partial = name.ends_with(".partial");
+            if partial {
+                name.truncate(name.len() - ".partial".len());
+            }
+            // skip checksums and other files stored next to the audio files
+            let file_id = match FileId::from_base16(&name) {
+                Ok(file_id) => file_id,
+                Err(_) => continue,
+            };
+
+            files.push(CachedFile {
+                file_id,
+                track_uri: self.read_string(&self.track_key(file_id)),
+                size: entry.size,
+                modified: entry.modified,
+                partial,
+            });
+        }
+
+        Ok(files)
+    }
+
+    // Removes all files which have not been written within the given duration.
+    pub fn purge_older_than(&self, age: Duration) -> io::Result<Vec<CachedFile>> {
+        let now = SystemTime::now();
+        let mut purged = Vec::new();
+
+        for file in self.files()? {
+            let file_age = now.duration_since(file.modified).unwrap_or_default();
+            if file_age > age {
+                self.remove_cached_file(&file)?;
+                purged.push(file);
+            }
+        }
+
+        Ok(purged)
+    }
+
+    // Removes the files which were written first until the cache uses at most max_size bytes.
+    // Reading a file does not change its modification time, so this is not a least recently
+    // used eviction: a track played every day is purged as soon as it is the oldest download.
+    pub fn purge_to_size(&self, max_size: u64) -> io::Result<Vec<CachedFile>> {
+        let mut files = self.files()?;
+        files.sort_by_key(|file| file.modified);
+
+        let mut usage: u64 = files.iter().map(|file| file.size).sum();
+        let mut purged = Vec::new();
+
+        for file in files {
+            if usage <= max_size {
+                break;
+            }
+            self.remove_cached_file(&file)?;
+            usage -= file.size;
+            purged.push(file);
+        }
+
+        Ok(purged)
+    }
+}
+
+#[cfg(test)]
+mod tests;
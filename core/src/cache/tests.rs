@@ -0,0 +1,265 @@
+use super::*;
+use crate::cache_storage::MemoryStorage;
+
+fn file_id(n: u8) -> FileId {
+    FileId([n; 20])
+}
+
+fn track(n: u8) -> SpotifyId {
+    let mut id = [0u8; 16];
+    id[15] = n;
+    SpotifyId::from_raw(&id).unwrap()
+}
+
+fn test_cache() -> (Arc<MemoryStorage>, Cache) {
+    let storage = Arc::new(MemoryStorage::new());
+    let cache = Cache::with_storage(storage.clone(), true);
+    (storage, cache)
+}
+
+fn read_key(storage: &MemoryStorage, key: &str) -> Option<String> {
+    let mut contents = String::new();
+    storage.read(key)?.read_to_string(&mut contents).unwrap();
+    Some(contents)
+}
+
+#[test]
+fn saved_file_is_read_back_with_checksum() {
+    let (storage, cache) = test_cache();
+
+    cache.save_file(file_id(1), &mut &b"audio data"[..]);
+
+    let mut contents = Vec::new();
+    cache
+        .file(file_id(1))
+        .unwrap()
+        .read_to_end(&mut contents)
+        .unwrap();
+    assert_eq!(contents, b"audio data");
+    assert_eq!(
+        read_key(&storage, &cache.checksum_key(file_id(1))),
+        Some(checksum(&b"audio data"[..]).unwrap())
+    );
+    assert_eq!(cache.verify_file(file_id(1)), FileIntegrity::Valid);
+}
+
+#[test]
+fn changed_file_is_corrupt() {
+    let (storage, cache) = test_cache();
+    cache.save_file(file_id(1), &mut &b"audio data"[..]);
+
+    storage
+        .write(&cache.file_key(file_id(1)), &mut &b"audio dat4"[..])
+        .unwrap();
+
+    assert_eq!(cache.verify_file(file_id(1)), FileIntegrity::Corrupt);
+}
+
+#[test]
+fn file_without_checksum_is_unknown() {
+    let (storage, cache) = test_cache();
+    storage
+        .write(&cache.file_key(file_id(1)), &mut &b"audio data"[..])
+        .unwrap();
+
+    assert_eq!(cache.verify_file(file_id(1)), FileIntegrity::Unknown);
+}
+
+#[test]
+fn track_is_written_once_the_download_completes() {
+    let (storage, cache) = test_cache();
+
+    cache.save_file_track(file_id(1), track(1));
+    assert_eq!(read_key(&storage, &cache.track_key(file_id(1))), None);
+
+    cache.save_file(file_id(1), &mut &b"audio data"[..]);
+    assert_eq!(
+        read_key(&storage, &cache.track_key(file_id(1))),
+        Some(track(1).to_uri())
+    );
+
+    let files = cache.files().unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].file_id, file_id(1));
+    assert_eq!(files[0].track_uri, Some(track(1).to_uri()));
+    assert_eq!(files[0].size, 10);
+    assert!(!files[0].partial);
+}
+
+#[test]
+fn track_of_cached_file_is_written_at_once() {
+    let (storage, cache) = test_cache();
+    cache.save_file(file_id(1), &mut &b"audio data"[..]);
+
+    cache.save_file_track(file_id(1), track(2));
+
+    assert_eq!(
+        read_key(&storage, &cache.track_key(file_id(1))),
+        Some(track(2).to_uri())
+    );
+}
+
+#[test]
+fn removed_file_leaves_no_sidecars() {
+    let (storage, cache) = test_cache();
+    cache.save_file_track(file_id(1), track(1));
+    cache.save_file(file_id(1), &mut &b"audio data"[..]);
+
+    cache.remove_file(file_id(1)).unwrap();
+
+    assert!(storage.list("files/").unwrap().is_empty());
+    assert!(cache.files().unwrap().is_empty());
+}
+
+#[test]
+fn nothing_is_saved_without_audio_cache() {
+    let storage = Arc::new(MemoryStorage::new());
+    let cache = Cache::with_storage(storage.clone(), false);
+
+    cache.save_file_track(file_id(1), track(1));
+    cache.save_file(file_id(1), &mut &b"audio data"[..]);
+
+    assert!(storage.list("").unwrap().is_empty());
+}
+
+fn days_ago(days: u64) -> SystemTime {
+    SystemTime::now() - Duration::from_secs(days * 24 * 60 * 60)
+}
+
+// Caches a file of the given size, downloaded the given number of days ago
+fn save_old_file(storage: &MemoryStorage, cache: &Cache, n: u8, size: usize, days: u64) {
+    cache.save_file_track(file_id(n), track(n));
+    cache.save_file(file_id(n), &mut &vec![n; size][..]);
+    storage.set_modified(&cache.file_key(file_id(n)), days_ago(days));
+}
+
+fn cached_ids(cache: &Cache) -> Vec<FileId> {
+    let mut ids: Vec<_> = cache
+        .files()
+        .unwrap()
+        .iter()
+        .map(|file| file.file_id)
+        .collect();
+    ids.sort();
+    ids
+}
+
+#[test]
+fn purge_by_age_removes_old_files_and_their_sidecars() {
+    let (storage, cache) = test_cache();
+    save_old_file(&storage, &cache, 1, 10, 40);
+    save_old_file(&storage, &cache, 2, 10, 5);
+    cache.save_partial_file(file_id(3), &mut &b"part"[..], &[(0, 4)]);
+    storage.set_modified(&cache.partial_key(file_id(3)), days_ago(40));
+
+    let purged = cache
+        .purge_older_than(Duration::from_secs(30 * 24 * 60 * 60))
+        .unwrap();
+
+    let mut purged_ids: Vec<_> = purged.iter().map(|file| file.file_id).collect();
+    purged_ids.sort();
+    assert_eq!(purged_ids, vec![file_id(1), file_id(3)]);
+    assert_eq!(cached_ids(&cache), vec![file_id(2)]);
+    assert!(cache.partial_file(file_id(3)).is_none());
+    let keys: Vec<_> = storage
+        .list("files/")
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.key)
+        .collect();
+    assert_eq!(keys.len(), 3);
+    assert!(keys
+        .iter()
+        .all(|key| key.contains(&file_id(2).to_base16()[2..])));
+}
+
+#[test]
+fn purge_by_size_removes_the_first_downloads() {
+    let (storage, cache) = test_cache();
+    save_old_file(&storage, &cache, 1, 100, 3);
+    save_old_file(&storage, &cache, 2, 100, 1);
+    save_old_file(&storage, &cache, 3, 100, 2);
+    // reading a file doesn't keep it
+    cache.file(file_id(1)).unwrap();
+
+    let purged = cache.purge_to_size(200).unwrap();
+
+    assert_eq!(purged.len(), 1);
+    assert_eq!(purged[0].file_id, file_id(1));
+    assert_eq!(cached_ids(&cache), vec![file_id(2), file_id(3)]);
+
+    let purged = cache.purge_to_size(150).unwrap();
+    assert_eq!(purged[0].file_id, file_id(3));
+    assert_eq!(cached_ids(&cache), vec![file_id(2)]);
+}
+
+#[test]
+fn purge_by_size_keeps_cache_within_limit() {
+    let (storage, cache) = test_cache();
+    save_old_file(&storage, &cache, 1, 100, 3);
+    save_old_file(&storage, &cache, 2, 100, 1);
+
+    assert!(cache.purge_to_size(200).unwrap().is_empty());
+    assert!(cache.purge_to_size(0).unwrap().len() == 2);
+    assert!(cache.files().unwrap().is_empty());
+}
+
+#[test]
+fn partial_file_is_moved_in_place_with_its_ranges() {
+    let (storage, cache) = test_cache();
+
+    cache.save_partial_file(file_id(1), &mut &b"part"[..], &[(0, 2), (3, 1)]);
+
+    let keys: Vec<_> = storage
+        .list("files/")
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.key)
+        .collect();
+    assert_eq!(
+        keys,
+        vec![
+            cache.partial_key(file_id(1)),
+            cache.partial_ranges_key(file_id(1))
+        ]
+    );
+    let (_, ranges) = cache.partial_file(file_id(1)).unwrap();
+    assert_eq!(ranges, vec![(0, 2), (3, 1)]);
+}
+
+#[test]
+fn interrupted_partial_save_keeps_previous_ranges() {
+    let (storage, cache) = test_cache();
+    cache.save_partial_file(file_id(1), &mut &b"pa  "[..], &[(0, 2)]);
+
+    // a save which was interrupted before the ranges were moved in place
+    storage
+        .write(
+            &(cache.partial_ranges_key(file_id(1)) + ".tmp"),
+            &mut &b"0 4\n"[..],
+        )
+        .unwrap();
+    storage
+        .write(&cache.partial_key(file_id(1)), &mut &b"part"[..])
+        .unwrap();
+
+    let (mut partial, ranges) = cache.partial_file(file_id(1)).unwrap();
+    let mut contents = Vec::new();
+    partial.read_to_end(&mut contents).unwrap();
+    assert_eq!(ranges, vec![(0, 2)]);
+    assert_eq!(&contents[..2], b"pa");
+
+    cache.remove_partial_file(file_id(1));
+    assert!(storage.list("files/").unwrap().is_empty());
+}
+
+#[test]
+fn forgotten_track_is_not_written() {
+    let (storage, cache) = test_cache();
+    cache.save_file_track(file_id(1), track(1));
+
+    cache.forget_file_track(file_id(1));
+    cache.save_file(file_id(1), &mut &b"audio data"[..]);
+
+    assert_eq!(read_key(&storage, &cache.track_key(file_id(1))), None);
+}
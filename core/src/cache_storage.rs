@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+pub trait ReadSeek: Read + Seek + Send {}
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+#[derive(Debug, Clone)]
+pub struct StorageEntry {
+    pub key: String,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+// Backend used by the Cache to store credentials, volume and audio files.
+// Keys are relative paths using '/' as separator, e.g. "volume" or "files/ab/cdef...".
+pub trait CacheStorage: Send + Sync {
+    fn read(&self, key: &str) -> Option<Box<dyn ReadSeek>>;
+    fn write(&self, key: &str, contents: &mut dyn Read) -> io::Result<()>;
+    // Removing a key which does not exist is not an error.
+    fn remove(&self, key: &str) -> io::Result<()>;
+    // Lists all entries with keys starting with the given prefix.
+    fn list(&self, prefix: &str) -> io::Result<Vec<StorageEntry>>;
+
+    // Replaces the entry at `to` with the one at `from`. Backends which can do this atomically
+    // should, the default implementation copies the entry.
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut contents = self.read(from).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("{} does not exist", from))
+        })?;
+        self.write(to, &mut contents)?;
+        self.remove(from)
+    }
+}
+
+// Stores every key as a file below the root directory.
+pub struct FilesystemStorage {
+    root: PathBuf,
+}
+
+fn mkdir_existing(path: &Path) -> io::Result<()> {
+    fs::create_dir(path).or_else(|err| {
+        if err.kind() == io::ErrorKind::AlreadyExists {
+            Ok(())
+        } else {
+            Err(err)
+        }
+    })
+}
+
+impl FilesystemStorage {
+    pub fn new(location: PathBuf) -> FilesystemStorage {
+        mkdir_existing(&location).unwrap();
+        mkdir_existing(&location.join("files")).unwrap();
+
+        FilesystemStorage { root: location }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        key.split('/')
+            .fold(self.root.clone(), |path, part| path.join(part))
+    }
+
+    fn list_dir(&self, dir: &Path, key: &str, entries: &mut Vec<StorageEntry>) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let entry_key = if key.is_empty() {
+                name
+            } else {
+                format!("{}/{}", key, name)
+            };
+
+            if entry.file_type()?.is_dir() {
+                self.list_dir(&entry.path(), &entry_key, entries)?;
+            } else {
+                let metadata = entry.metadata()?;
+                entries.push(StorageEntry {
+                    key: entry_key,
+                    size: metadata.len(),
+                    modified: metadata.modified()?,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CacheStorage for FilesystemStorage {
+    fn read(&self, key: &str) -> Option<Box<dyn ReadSeek>> {
+        File::open(self.path(key))
+            .ok()
+            .map(|file| Box::new(file) as Box<dyn ReadSeek>)
+    }
+
+    fn write(&self, key: &str, contents: &mut dyn Read) -> io::Result<()> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = File::create(path)?;
+        io::copy(contents, &mut file)?;
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> io::Result<()> {
+        fs::remove_file(self.path(key)).or_else(|err| {
+            if err.kind() == io::ErrorKind::NotFound {
+                Ok(())
+            } else {
+                Err(err)
+            }
+        })
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<StorageEntry>> {
+        let mut entries = Vec::new();
+
+        // only walk the directory the prefix points into
+        let dir_key = match prefix.rfind('/') {
+            Some(index) => &prefix[..index],
+            None => "",
+        };
+        let dir = self.path(dir_key);
+        if dir.is_dir() {
+            self.list_dir(&dir, dir_key, &mut entries)?;
+        }
+
+        entries.retain(|entry| entry.key.starts_with(prefix));
+        Ok(entries)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        fs::rename(self.path(from), self.path(to))
+    }
+}
+
+// Keeps all entries in memory, e.g. for clients which must not write to disk, or for tests.
+#[derive(Default)]
+pub struct MemoryStorage {
+    entries: Mutex<HashMap<String, MemoryEntry>>,
+}
+
+struct MemoryEntry {
+    contents: Vec<u8>,
+    modified: SystemTime,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    // Changes the modification time of an existing entry, e.g. to have it purged as if it
+    // was written long ago.
+    pub fn set_modified(&self, key: &str, modified: SystemTime) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(key) {
+            entry.modified = modified;
+        }
+    }
+}
+
+impl CacheStorage for MemoryStorage {
+    fn read(&self, key: &str) -> Option<Box<dyn ReadSeek>> {
+        let contents = self.entries.lock().unwrap().get(key)?.contents.clone();
+        Some(Box::new(io::Cursor::new(contents)))
+    }
+
+    fn write(&self, key: &str, contents: &mut dyn Read) -> io::Result<()> {
+        let mut data = Vec::new();
+        contents.read_to_end(&mut data)?;
+        let entry = MemoryEntry {
+            contents: data,
+            modified: SystemTime::now(),
+        };
+        self.entries.lock().unwrap().insert(key.to_owned(), entry);
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> io::Result<()> {
+        self.entries.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<StorageEntry>> {
+        let mut entries: Vec<_> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|&(key, _)| key.starts_with(prefix))
+            .map(|(key, entry)| StorageEntry {
+                key: key.clone(),
+                size: entry.contents.len() as u64,
+                modified: entry.modified,
+            })
+            .collect();
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(entries)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.remove(from).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("{} does not exist", from))
+        })?;
+        entries.insert(to.to_owned(), entry);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests;
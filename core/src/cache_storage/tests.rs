@@ -0,0 +1,99 @@
+use super::*;
+
+fn read_all(storage: &dyn CacheStorage, key: &str) -> Option<Vec<u8>> {
+    let mut contents = Vec::new();
+    storage.read(key)?.read_to_end(&mut contents).unwrap();
+    Some(contents)
+}
+
+fn keys(storage: &dyn CacheStorage, prefix: &str) -> Vec<String> {
+    let mut keys: Vec<_> = storage
+        .list(prefix)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.key)
+        .collect();
+    keys.sort();
+    keys
+}
+
+// The behaviour the Cache relies on, which every backend has to provide
+fn check_contract(storage: &dyn CacheStorage) {
+    assert!(storage.read("volume").is_none());
+
+    storage.write("volume", &mut &b"50"[..]).unwrap();
+    assert_eq!(read_all(storage, "volume"), Some(b"50".to_vec()));
+
+    // writing replaces the previous contents
+    storage.write("volume", &mut &b"7"[..]).unwrap();
+    assert_eq!(read_all(storage, "volume"), Some(b"7".to_vec()));
+
+    // nested keys need no preparation
+    storage.write("files/ab/cdef", &mut &b"audio"[..]).unwrap();
+    storage
+        .write("files/ab/cdef.sha1", &mut &b"sum"[..])
+        .unwrap();
+    storage
+        .write("files/12/3456", &mut &b"more audio"[..])
+        .unwrap();
+
+    assert_eq!(
+        keys(storage, "files/"),
+        vec!["files/12/3456", "files/ab/cdef", "files/ab/cdef.sha1"]
+    );
+    assert_eq!(
+        keys(storage, "files/ab/cd"),
+        vec!["files/ab/cdef", "files/ab/cdef.sha1"]
+    );
+    assert!(keys(storage, "files/ff/").is_empty());
+    assert!(keys(storage, "missing/").is_empty());
+
+    let entries = storage.list("files/12/").unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].size, 10);
+
+    storage.remove("files/ab/cdef").unwrap();
+    assert!(storage.read("files/ab/cdef").is_none());
+    assert_eq!(keys(storage, "files/ab/"), vec!["files/ab/cdef.sha1"]);
+
+    // removing a missing key is not an error
+    storage.remove("files/ab/cdef").unwrap();
+    storage.remove("never-written").unwrap();
+}
+
+#[test]
+fn memory_storage_fulfils_contract() {
+    check_contract(&MemoryStorage::new());
+}
+
+#[test]
+fn filesystem_storage_fulfils_contract() {
+    let dir = tempfile::tempdir().unwrap();
+    check_contract(&FilesystemStorage::new(dir.path().to_owned()));
+}
+
+#[test]
+fn filesystem_storage_lists_files_written_before() {
+    let dir = tempfile::tempdir().unwrap();
+    FilesystemStorage::new(dir.path().to_owned())
+        .write("files/ab/cdef", &mut &b"audio"[..])
+        .unwrap();
+
+    let storage = FilesystemStorage::new(dir.path().to_owned());
+    assert_eq!(keys(&storage, "files/"), vec!["files/ab/cdef"]);
+    assert_eq!(read_all(&storage, "files/ab/cdef"), Some(b"audio".to_vec()));
+}
+
+#[test]
+fn memory_storage_modified_time_can_be_changed() {
+    let storage = MemoryStorage::new();
+    storage.write("files/ab/cdef", &mut &b"audio"[..]).unwrap();
+    let long_ago = SystemTime::UNIX_EPOCH;
+
+    storage.set_modified("files/ab/cdef", long_ago);
+    storage.set_modified("missing", long_ago);
+
+    let entries = storage.list("files/").unwrap();
+    assert_eq!(entries[0].modified, long_ago);
+    assert!(storage.read("missing").is_none());
+}
@@ -85,23 +85,177 @@ pub struct ConnectConfig {
     pub device_type: DeviceType,
     pub volume: u16,
     pub volume_ctrl: VolumeCtrl,
+    // Number of steps between mute and full volume offered to clients.
+    pub volume_steps: u16,
+    // Amount by which volume up and down change the volume, out of 0xFFFF.
+    pub volume_step_size: u16,
     pub autoplay: bool,
+    pub takeover_policy: TakeoverPolicy,
+    pub capabilities: ConnectCapabilities,
+    // Number of played tracks kept when more tracks of a station or autoplay are added.
+    pub context_history: usize,
+    // More tracks are fetched once fewer than this number of tracks are left to play.
+    pub context_fetch_threshold: usize,
+    // Store the Connect state in the cache, so playback can be resumed after a restart.
+    pub persist_state: bool,
 }
 
+pub const DEFAULT_VOLUME_STEPS: u16 = 64;
+pub const DEFAULT_VOLUME_STEP_SIZE: u16 = 4096;
+pub const DEFAULT_CONTEXT_HISTORY: usize = 10;
+pub const DEFAULT_CONTEXT_FETCH_THRESHOLD: usize = 5;
+
+impl Default for ConnectConfig {
+    fn default() -> ConnectConfig {
+        ConnectConfig {
+            name: String::from("Librespot"),
+            device_type: DeviceType::default(),
+            volume: 0x8000,
+            volume_ctrl: VolumeCtrl::default(),
+            volume_steps: DEFAULT_VOLUME_STEPS,
+            volume_step_size: DEFAULT_VOLUME_STEP_SIZE,
+            autoplay: false,
+            takeover_policy: TakeoverPolicy::default(),
+            capabilities: ConnectCapabilities::default(),
+            context_history: DEFAULT_CONTEXT_HISTORY,
+            context_fetch_threshold: DEFAULT_CONTEXT_FETCH_THRESHOLD,
+            persist_state: false,
+        }
+    }
+}
+
+// Capabilities advertised to other Connect clients, in addition to the device type and name.
+#[derive(Clone, Debug)]
+pub struct ConnectCapabilities {
+    pub volume_control: bool,
+    pub hidden: bool,
+    pub content: ContentRestriction,
+    // Supported types advertised on top of the ones implied by the content restriction.
+    pub extra_types: Vec<String>,
+}
+
+impl Default for ConnectCapabilities {
+    fn default() -> ConnectCapabilities {
+        ConnectCapabilities {
+            volume_control: true,
+            hidden: false,
+            content: ContentRestriction::default(),
+            extra_types: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentRestriction {
+    All,
+    Music,
+    Podcasts,
+}
+
+impl FromStr for ContentRestriction {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use self::ContentRestriction::*;
+        match s.to_lowercase().as_ref() {
+            "all" => Ok(All),
+            "music" => Ok(Music),
+            "podcasts" => Ok(Podcasts),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Default for ContentRestriction {
+    fn default() -> ContentRestriction {
+        ContentRestriction::All
+    }
+}
+
+// Maps the volume shown by Connect clients to the amplitude factor applied by the mixer.
+// Log and Cubic take the dB range of the curve.
 #[derive(Clone, Debug)]
 pub enum VolumeCtrl {
     Linear,
-    Log,
+    Log(f64),
+    Cubic(f64),
     Fixed,
 }
 
+pub const DEFAULT_VOLUME_DB_RANGE: f64 = 60.0;
+
+pub fn db_to_amplitude(db: f64) -> f64 {
+    10f64.powf(db / 20.0)
+}
+
+pub fn amplitude_to_db(amplitude: f64) -> f64 {
+    20.0 * amplitude.log10()
+}
+
+// Scales a fraction to 0..0xffff, saturating outside of 0..1.
+fn fraction_to_volume(fraction: f64) -> u16 {
+    if fraction.is_nan() || fraction <= 0.0 {
+        0
+    } else if fraction >= 1.0 {
+        0xFFFF
+    } else {
+        (fraction * 0xFFFF as f64).round() as u16
+    }
+}
+
+impl VolumeCtrl {
+    // Returns the same kind of volume control with another dB range.
+    pub fn with_db_range(self, db_range: f64) -> VolumeCtrl {
+        match self {
+            VolumeCtrl::Log(_) => VolumeCtrl::Log(db_range),
+            VolumeCtrl::Cubic(_) => VolumeCtrl::Cubic(db_range),
+            other => other,
+        }
+    }
+
+    // Maps a volume (0..0xffff) as shown by Connect clients to the mixer volume, which is the
+    // scale factor applied to the samples.
+    pub fn to_mixer(&self, volume: u16) -> u16 {
+        let position = volume as f64 / 0xFFFF as f64;
+        let amplitude = match *self {
+            _ if volume == 0 => 0.0,
+            VolumeCtrl::Linear | VolumeCtrl::Fixed => position,
+            // https://www.dr-lex.be/info-stuff/volumecontrols.html#ideal2
+            VolumeCtrl::Log(db_range) => db_to_amplitude(db_range * (position - 1.0)),
+            // the position is the cube root of the amplitude, as done by alsamixer
+            VolumeCtrl::Cubic(db_range) => {
+                let min_amplitude = db_to_amplitude(-db_range);
+                min_amplitude + (1.0 - min_amplitude) * position.powi(3)
+            }
+        };
+        fraction_to_volume(amplitude)
+    }
+
+    // Maps a mixer volume back to the volume shown by Connect clients.
+    pub fn from_mixer(&self, volume: u16) -> u16 {
+        let amplitude = volume as f64 / 0xFFFF as f64;
+        let position = match *self {
+            _ if volume == 0 => 0.0,
+            VolumeCtrl::Linear | VolumeCtrl::Fixed => amplitude,
+            VolumeCtrl::Log(db_range) => 1.0 + amplitude_to_db(amplitude) / db_range,
+            VolumeCtrl::Cubic(db_range) => {
+                let min_amplitude = db_to_amplitude(-db_range);
+                ((amplitude - min_amplitude) / (1.0 - min_amplitude))
+                    .max(0.0)
+                    .cbrt()
+            }
+        };
+        fraction_to_volume(position)
+    }
+}
+
 impl FromStr for VolumeCtrl {
     type Err = ();
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         use self::VolumeCtrl::*;
         match s.to_lowercase().as_ref() {
             "linear" => Ok(Linear),
-            "log" => Ok(Log),
+            "log" => Ok(Log(DEFAULT_VOLUME_DB_RANGE)),
+            "cubic" => Ok(Cubic(DEFAULT_VOLUME_DB_RANGE)),
             "fixed" => Ok(Fixed),
             _ => Err(()),
         }
@@ -110,6 +264,22 @@ impl FromStr for VolumeCtrl {
 
 impl Default for VolumeCtrl {
     fn default() -> VolumeCtrl {
-        VolumeCtrl::Log
+        VolumeCtrl::Log(DEFAULT_VOLUME_DB_RANGE)
+    }
+}
+
+// Decides whether playback is handed over when another device becomes active. A refused
+// takeover is answered by claiming playback back, which official clients may ignore.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TakeoverPolicy {
+    Always,
+    NotWhilePlaying,
+    // Only hand over to the listed devices, given by device name or id.
+    AllowList(Vec<String>),
+}
+
+impl Default for TakeoverPolicy {
+    fn default() -> TakeoverPolicy {
+        TakeoverPolicy::Always
     }
 }
@@ -43,6 +43,7 @@ mod apresolve;
 pub mod audio_key;
 pub mod authentication;
 pub mod cache;
+pub mod cache_storage;
 pub mod channel;
 pub mod config;
 mod connection;
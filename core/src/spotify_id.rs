@@ -121,6 +121,24 @@ impl SpotifyId {
 pub struct FileId(pub [u8; 20]);
 
 impl FileId {
+    pub fn from_base16(id: &str) -> Result<FileId, SpotifyIdError> {
+        let data = id.as_bytes();
+        if data.len() != 40 {
+            return Err(SpotifyIdError);
+        }
+
+        let mut dst = [0u8; 20];
+        for (i, c) in data.iter().enumerate() {
+            let d = match BASE16_DIGITS.iter().position(|e| e == c) {
+                None => return Err(SpotifyIdError),
+                Some(x) => x as u8,
+            };
+            dst[i / 2] = dst[i / 2] * 16 + d;
+        }
+
+        Ok(FileId(dst))
+    }
+
     pub fn to_base16(&self) -> String {
         self.0
             .iter()
@@ -65,6 +65,7 @@ pub struct AudioItem {
     pub name: String,
     pub duration: i32,
     pub available: bool,
+    pub explicit: bool,
     pub alternatives: Option<Vec<SpotifyId>>,
 }
 
@@ -103,6 +104,7 @@ impl AudioFiles for Track {
                 name: item.name,
                 duration: item.duration,
                 available: item.available,
+                explicit: item.explicit,
                 alternatives: Some(item.alternatives),
             })
         }))
@@ -122,6 +124,7 @@ impl AudioFiles for Episode {
                 name: item.name,
                 duration: item.duration,
                 available: item.available,
+                explicit: item.explicit,
                 alternatives: None,
             })
         }))
@@ -157,6 +160,7 @@ pub struct Track {
     pub files: LinearMap<FileFormat, FileId>,
     pub alternatives: Vec<SpotifyId>,
     pub available: bool,
+    pub explicit: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -247,6 +251,7 @@ impl Metadata for Track {
                 .map(|alt| SpotifyId::from_raw(alt.get_gid()).unwrap())
                 .collect(),
             available: parse_restrictions(msg.get_restriction(), &country, "premium"),
+            explicit: msg.get_explicit(),
         }
     }
 }
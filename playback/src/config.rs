@@ -31,6 +31,11 @@ pub struct PlayerConfig {
     pub normalisation: bool,
     pub normalisation_pregain: f32,
     pub gapless: bool,
+    pub prefetch_tracks: usize,
+    // in bytes per second, None or 0 for no limit
+    pub prefetch_bandwidth: Option<usize>,
+    // Treat explicit tracks and episodes as unavailable.
+    pub skip_explicit: bool,
 }
 
 impl Default for PlayerConfig {
@@ -40,6 +45,9 @@ impl Default for PlayerConfig {
             normalisation: false,
             normalisation_pregain: 0.0,
             gapless: true,
+            prefetch_tracks: 0,
+            prefetch_bandwidth: None,
+            skip_explicit: false,
         }
     }
 }
@@ -40,3 +40,4 @@ pub mod audio_backend;
 pub mod config;
 pub mod mixer;
 pub mod player;
+mod prefetch;
@@ -1,9 +1,18 @@
 use super::AudioFilter;
 use super::{Mixer, MixerConfig};
+use futures::sync::mpsc;
+use futures::{Poll, Stream};
 use std;
 use std::error::Error;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::thread;
 
 use alsa;
+use alsa::PollDescriptors;
+use libc;
+
+use librespot_core::config::{amplitude_to_db, db_to_amplitude};
 
 const SND_CTL_TLV_DB_GAIN_MUTE: i64 = -9999999;
 
@@ -23,6 +32,22 @@ pub struct AlsaMixer {
     params: AlsaMixerVolumeParams,
 }
 
+// The volume changes sent by the watch thread. Dropping it closes `_stop`, which wakes the
+// thread up so that it exits.
+struct VolumeWatch {
+    volumes: mpsc::UnboundedReceiver<u16>,
+    _stop: UnixStream,
+}
+
+impl Stream for VolumeWatch {
+    type Item = u16;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<u16>, ()> {
+        self.volumes.poll()
+    }
+}
+
 impl AlsaMixer {
     fn pvol<T>(&self, vol: T, min: T, max: T) -> f64
     where
@@ -118,17 +143,12 @@ impl AlsaMixer {
                 }
 
                 if self.config.mapped_volume {
-                    // Cubic mapping ala alsamixer
-                    // https://linux.die.net/man/1/alsamixer
-                    // In alsamixer, the volume is mapped to a value that is more natural for a
-                    // human ear. The mapping is designed so that the position in the interval is
-                    // proportional to the volume as a human ear would perceive it, i.e. the
-                    // position is the cubic root of the linear sample multiplication factor. For
-                    // controls with a small range (24 dB or less), the mapping is linear in the dB
-                    // values so that each step has the same size visually. TODO
+                    // The volume is the amplitude given by VolumeCtrl::to_mixer, so the curve
+                    // and dB range of --volume-ctrl and --volume-range apply. It only needs to
+                    // be converted to the dB value of the control.
                     // TODO: Check if min is not mute!
-                    let vol_db = (self.pvol(vol, 0x0000, 0xFFFF).log10() * 6000.0).floor() as i64
-                        + self.params.max_db.0;
+                    let db = amplitude_to_db(self.pvol(vol, 0x0000, 0xFFFF));
+                    let vol_db = (db * 100.0).floor() as i64 + self.params.max_db.0;
                     selem
                         .set_playback_db_all(alsa::mixer::MilliBel(vol_db), alsa::Round::Floor)
                         .expect("Couldn't set alsa dB volume");
@@ -164,10 +184,33 @@ impl AlsaMixer {
                     );
                 };
             }
+            None if self.params.has_switch
+                && selem
+                    .get_playback_switch(alsa::mixer::SelemChannelId::mono())
+                    .map(|b| b == 0)
+                    .unwrap_or(false) =>
+            {
+                trace!("Alsa mixer is muted");
+            }
+            None if self.config.mapped_volume => {
+                // Inverse of the dB mapping above, mapped back by VolumeCtrl::from_mixer
+                let vol = if cur_vol_db.0 <= self.params.min_db.0 {
+                    0.0
+                } else {
+                    db_to_amplitude((cur_vol_db.0 - self.params.max_db.0) as f64 / 100.0)
+                };
+                new_vol = (vol.min(1.0) * 0xFFFF as f64) as u16;
+                trace!(
+                    "Mapping volume [{:.3}%] {:?} [u16] <<- Alsa {:?} [dB]",
+                    self.pvol(new_vol, 0x0000, 0xFFFF) * 100.0,
+                    new_vol,
+                    cur_vol_db.0 as f64 / 100.0
+                );
+            }
             None => {
                 new_vol = (((cur_vol - self.params.min) as f64 / self.params.range) * 0xFFFF as f64)
                     as u16;
-                debug!(
+                trace!(
                     "Mapping volume [{:.3}%] {:?} [u16] <<- Alsa [{:.3}%] {:?} [i64]",
                     self.pvol(new_vol, 0x0000, 0xFFFF),
                     new_vol,
@@ -183,6 +226,42 @@ impl AlsaMixer {
 
         Ok(new_vol)
     }
+
+    // Sends the volume whenever a control of the card changes, until the receiver is dropped
+    // or the other end of `stopped` is closed.
+    fn watch_volume(
+        &self,
+        tx: &mpsc::UnboundedSender<u16>,
+        stopped: &UnixStream,
+    ) -> Result<(), Box<dyn Error>> {
+        let ctl = alsa::Ctl::new(&self.config.card, true)?;
+        ctl.subscribe_events(true)?;
+        let mut fds = ctl.get()?;
+        let stop_index = fds.len();
+        fds.push(libc::pollfd {
+            fd: stopped.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        });
+
+        let mut last_volume = self.volume();
+        loop {
+            alsa::poll::poll(&mut fds, -1)?;
+            if fds[stop_index].revents != 0 {
+                return Ok(());
+            }
+            // The events are only drained, the volume is read once for all of them
+            while ctl.read()?.is_some() {}
+
+            let volume = self.volume();
+            if volume != last_volume {
+                last_volume = volume;
+                if tx.unbounded_send(volume).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
 }
 
 impl Mixer for AlsaMixer {
@@ -219,4 +298,30 @@ impl Mixer for AlsaMixer {
     fn get_audio_filter(&self) -> Option<Box<dyn AudioFilter + Send>> {
         None
     }
+
+    fn watch(&self) -> Option<Box<dyn Stream<Item = u16, Error = ()>>> {
+        if !self.config.watch_volume {
+            return None;
+        }
+
+        let (stop, stopped) = match UnixStream::pair() {
+            Ok(pair) => pair,
+            Err(e) => {
+                error!("Error watching volume of <{}>, {:?}", self.config.card, e);
+                return None;
+            }
+        };
+        let (tx, rx) = mpsc::unbounded();
+        let mixer = self.clone();
+        thread::spawn(move || {
+            if let Err(e) = mixer.watch_volume(&tx, &stopped) {
+                error!("Error watching volume of <{}>, {:?}", mixer.config.card, e);
+            }
+        });
+
+        Some(Box::new(VolumeWatch {
+            volumes: rx,
+            _stop: stop,
+        }))
+    }
 }
@@ -1,3 +1,5 @@
+use futures::Stream;
+
 pub trait Mixer: Send {
     fn open(_: Option<MixerConfig>) -> Self
     where
@@ -9,6 +11,10 @@ pub trait Mixer: Send {
     fn get_audio_filter(&self) -> Option<Box<dyn AudioFilter + Send>> {
         None
     }
+    // Volume changes made outside of librespot, e.g. with alsamixer or a hardware knob.
+    fn watch(&self) -> Option<Box<dyn Stream<Item = u16, Error = ()>>> {
+        None
+    }
 }
 
 pub trait AudioFilter {
@@ -26,6 +32,7 @@ pub struct MixerConfig {
     pub mixer: String,
     pub index: u32,
     pub mapped_volume: bool,
+    pub watch_volume: bool,
 }
 
 impl Default for MixerConfig {
@@ -35,6 +42,7 @@ impl Default for MixerConfig {
             mixer: String::from("PCM"),
             index: 0,
             mapped_volume: true,
+            watch_volume: false,
         }
     }
 }
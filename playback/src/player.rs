@@ -6,12 +6,13 @@ use std::borrow::Cow;
 use std::cmp::max;
 use std::io::{Read, Result, Seek, SeekFrom};
 use std::mem;
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
 use crate::config::{Bitrate, PlayerConfig};
 use librespot_core::session::Session;
-use librespot_core::spotify_id::SpotifyId;
+use librespot_core::spotify_id::{FileId, SpotifyId};
 
 use librespot_core::util::SeqGenerator;
 
@@ -24,6 +25,7 @@ use crate::audio::{
 use crate::audio_backend::Sink;
 use crate::metadata::{AudioItem, FileFormat};
 use crate::mixer::AudioFilter;
+use crate::prefetch::Prefetcher;
 
 const PRELOAD_NEXT_TRACK_BEFORE_END_DURATION_MS: u32 = 30000;
 
@@ -31,8 +33,22 @@ pub struct Player {
     commands: Option<futures::sync::mpsc::UnboundedSender<PlayerCommand>>,
     thread_handle: Option<thread::JoinHandle<()>>,
     play_request_id_generator: SeqGenerator<u64>,
+    prefetcher: Option<Prefetcher>,
+    position: SharedPosition,
 }
 
+// Position of the track which is played and the time at which it was measured.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PlayerPosition {
+    pub track_id: SpotifyId,
+    pub position_ms: u32,
+    pub measured_at: SystemTime,
+}
+
+// Updated by the player with every packet while playing, for those which need the position
+// more often than it is sent with the events. It keeps the last position when playback stops.
+pub type SharedPosition = Arc<Mutex<Option<PlayerPosition>>>;
+
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub enum SinkStatus {
     Running,
@@ -54,6 +70,8 @@ struct PlayerInternal {
     sink_event_callback: Option<SinkEventCallback>,
     audio_filter: Option<Box<dyn AudioFilter + Send>>,
     event_senders: Vec<futures::sync::mpsc::UnboundedSender<PlayerEvent>>,
+    reported_underrun_count: usize,
+    position: SharedPosition,
 }
 
 enum PlayerCommand {
@@ -73,6 +91,15 @@ enum PlayerCommand {
     AddEventSender(futures::sync::mpsc::UnboundedSender<PlayerEvent>),
     SetSinkEventCallback(Option<SinkEventCallback>),
     EmitVolumeSetEvent(u16),
+    EmitTakeoverEvent {
+        device_ident: String,
+        device_name: String,
+        accepted: bool,
+    },
+    EmitAutoplayStartedEvent {
+        context_uri: String,
+        station_uri: String,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -121,6 +148,13 @@ pub enum PlayerEvent {
         position_ms: u32,
         duration_ms: u32,
     },
+    // Playback had to wait for data to be downloaded. This indicates network problems.
+    Underrun {
+        play_request_id: u64,
+        track_id: SpotifyId,
+        position_ms: u32,
+        underrun_count: usize,
+    },
     // The player thinks it's a good idea to issue a preload command for the next track now.
     // This event is intended for use within spirc.
     TimeToPreloadNextTrack {
@@ -139,10 +173,32 @@ pub enum PlayerEvent {
         play_request_id: u64,
         track_id: SpotifyId,
     },
+    // The requested track is explicit and was skipped because explicit content is disabled.
+    ExplicitSkipped {
+        play_request_id: u64,
+        track_id: SpotifyId,
+    },
     // The mixer volume was set to a new level.
     VolumeSet {
         volume: u16,
     },
+    // Another Connect device tried to take over playback. If the takeover was accepted, this
+    // device has stopped playing.
+    Takeover {
+        device_ident: String,
+        device_name: String,
+        accepted: bool,
+    },
+    // The end of the context was reached and playback continues with similar tracks.
+    AutoplayStarted {
+        context_uri: String,
+        station_uri: String,
+    },
+    // The audio sink is about to be started, or was closed. Sent right after the sink event
+    // callback ran, so consumers of the events get it in order with the other events.
+    SinkStatusChanged {
+        sink_status: SinkStatus,
+    },
 }
 
 impl PlayerEvent {
@@ -155,6 +211,9 @@ impl PlayerEvent {
             | Unavailable {
                 play_request_id, ..
             }
+            | ExplicitSkipped {
+                play_request_id, ..
+            }
             | Started {
                 play_request_id, ..
             }
@@ -170,10 +229,17 @@ impl PlayerEvent {
             | Paused {
                 play_request_id, ..
             }
+            | Underrun {
+                play_request_id, ..
+            }
             | Stopped {
                 play_request_id, ..
             } => Some(*play_request_id),
-            Changed { .. } | VolumeSet { .. } => None,
+            Changed { .. }
+            | VolumeSet { .. }
+            | Takeover { .. }
+            | AutoplayStarted { .. }
+            | SinkStatusChanged { .. } => None,
         }
     }
 }
@@ -240,6 +306,10 @@ impl Player {
         let (cmd_tx, cmd_rx) = futures::sync::mpsc::unbounded();
         let (event_sender, event_receiver) = futures::sync::mpsc::unbounded();
 
+        let prefetcher = Prefetcher::new(config.clone(), session.clone());
+        let position = Arc::new(Mutex::new(None));
+        let internal_position = position.clone();
+
         let handle = thread::spawn(move || {
             debug!("new Player[{}]", session.session_id());
 
@@ -255,6 +325,8 @@ impl Player {
                 sink_event_callback: None,
                 audio_filter: audio_filter,
                 event_senders: [event_sender].to_vec(),
+                reported_underrun_count: 0,
+                position: internal_position,
             };
 
             // While PlayerInternal is written as a future, it still contains blocking code.
@@ -268,6 +340,8 @@ impl Player {
                 commands: Some(cmd_tx),
                 thread_handle: Some(handle),
                 play_request_id_generator: SeqGenerator::new(0),
+                prefetcher,
+                position,
             },
             event_receiver,
         )
@@ -293,6 +367,12 @@ impl Player {
         self.command(PlayerCommand::Preload { track_id });
     }
 
+    pub fn prefetch(&self, tracks: Vec<SpotifyId>) {
+        if let Some(ref prefetcher) = self.prefetcher {
+            prefetcher.prefetch(tracks);
+        }
+    }
+
     pub fn play(&self) {
         self.command(PlayerCommand::Play)
     }
@@ -315,6 +395,10 @@ impl Player {
         event_receiver
     }
 
+    pub fn get_shared_position(&self) -> SharedPosition {
+        self.position.clone()
+    }
+
     pub fn get_end_of_track_future(&self) -> Box<dyn Future<Item = (), Error = ()>> {
         let result = self
             .get_player_event_channel()
@@ -335,6 +419,21 @@ impl Player {
     pub fn emit_volume_set_event(&self, volume: u16) {
         self.command(PlayerCommand::EmitVolumeSetEvent(volume));
     }
+
+    pub fn emit_takeover_event(&self, device_ident: String, device_name: String, accepted: bool) {
+        self.command(PlayerCommand::EmitTakeoverEvent {
+            device_ident,
+            device_name,
+            accepted,
+        });
+    }
+
+    pub fn emit_autoplay_started_event(&self, context_uri: String, station_uri: String) {
+        self.command(PlayerCommand::EmitAutoplayStartedEvent {
+            context_uri,
+            station_uri,
+        });
+    }
 }
 
 impl Drop for Player {
@@ -359,11 +458,17 @@ struct PlayerLoadedTrackData {
     stream_position_pcm: u64,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum PlayerLoadError {
+    Failed,
+    Explicit,
+}
+
 enum PlayerPreload {
     None,
     Loading {
         track_id: SpotifyId,
-        loader: Box<dyn Future<Item = PlayerLoadedTrackData, Error = ()>>,
+        loader: Box<dyn Future<Item = PlayerLoadedTrackData, Error = PlayerLoadError>>,
     },
     Ready {
         track_id: SpotifyId,
@@ -379,7 +484,7 @@ enum PlayerState {
         track_id: SpotifyId,
         play_request_id: u64,
         start_playback: bool,
-        loader: Box<dyn Future<Item = PlayerLoadedTrackData, Error = ()>>,
+        loader: Box<dyn Future<Item = PlayerLoadedTrackData, Error = PlayerLoadError>>,
     },
     Paused {
         track_id: SpotifyId,
@@ -563,14 +668,17 @@ impl PlayerState {
     }
 }
 
-struct PlayerTrackLoader {
-    session: Session,
-    config: PlayerConfig,
+pub(crate) struct PlayerTrackLoader {
+    pub(crate) session: Session,
+    pub(crate) config: PlayerConfig,
 }
 
 impl PlayerTrackLoader {
-    fn find_available_alternative<'a>(&self, audio: &'a AudioItem) -> Option<Cow<'a, AudioItem>> {
-        if audio.available {
+    pub(crate) fn find_available_alternative<'a>(
+        &self,
+        audio: &'a AudioItem,
+    ) -> Option<Cow<'a, AudioItem>> {
+        if self.is_playable(audio) {
             Some(Cow::Borrowed(audio))
         } else {
             if let Some(alternatives) = &audio.alternatives {
@@ -580,7 +688,7 @@ impl PlayerTrackLoader {
                 let alternatives = future::join_all(alternatives).wait().unwrap();
                 alternatives
                     .into_iter()
-                    .find(|alt| alt.available)
+                    .find(|alt| self.is_playable(alt))
                     .map(Cow::Owned)
             } else {
                 None
@@ -588,7 +696,11 @@ impl PlayerTrackLoader {
         }
     }
 
-    fn stream_data_rate(&self, format: FileFormat) -> usize {
+    fn is_playable(&self, audio: &AudioItem) -> bool {
+        audio.available && !(self.config.skip_explicit && audio.explicit)
+    }
+
+    pub(crate) fn stream_data_rate(&self, format: FileFormat) -> usize {
         match format {
             FileFormat::OGG_VORBIS_96 => 12 * 1024,
             FileFormat::OGG_VORBIS_160 => 20 * 1024,
@@ -607,28 +719,7 @@ impl PlayerTrackLoader {
         }
     }
 
-    fn load_track(&self, spotify_id: SpotifyId, position_ms: u32) -> Option<PlayerLoadedTrackData> {
-        let audio = match AudioItem::get_audio_item(&self.session, spotify_id).wait() {
-            Ok(audio) => audio,
-            Err(_) => {
-                error!("Unable to load audio item.");
-                return None;
-            }
-        };
-
-        info!("Loading <{}> with Spotify URI <{}>", audio.name, audio.uri);
-
-        let audio = match self.find_available_alternative(&audio) {
-            Some(audio) => audio,
-            None => {
-                warn!("<{}> is not available", audio.uri);
-                return None;
-            }
-        };
-
-        assert!(audio.duration >= 0);
-        let duration_ms = audio.duration as u32;
-
+    pub(crate) fn find_file(&self, audio: &AudioItem) -> Option<(FileFormat, FileId)> {
         // (Most) podcasts seem to support only 96 bit Vorbis, so fall back to it
         let formats = match self.config.bitrate {
             Bitrate::Bitrate96 => [
@@ -652,15 +743,55 @@ impl PlayerTrackLoader {
             .find(|format| audio.files.contains_key(format))
             .unwrap();
 
-        let file_id = match audio.files.get(&format) {
-            Some(&file_id) => file_id,
+        match audio.files.get(&format) {
+            Some(&file_id) => Some((*format, file_id)),
             None => {
                 warn!("<{}> in not available in format {:?}", audio.name, format);
-                return None;
+                None
             }
+        }
+    }
+
+    fn load_track(
+        &self,
+        spotify_id: SpotifyId,
+        position_ms: u32,
+    ) -> ::std::result::Result<PlayerLoadedTrackData, PlayerLoadError> {
+        let audio = match AudioItem::get_audio_item(&self.session, spotify_id).wait() {
+            Ok(audio) => audio,
+            Err(_) => {
+                error!("Unable to load audio item.");
+                return Err(PlayerLoadError::Failed);
+            }
+        };
+
+        info!("Loading <{}> with Spotify URI <{}>", audio.name, audio.uri);
+
+        let audio = match self.find_available_alternative(&audio) {
+            Some(audio) => audio,
+            None if self.config.skip_explicit && audio.explicit => {
+                warn!("<{}> is explicit, skipping", audio.uri);
+                return Err(PlayerLoadError::Explicit);
+            }
+            None => {
+                warn!("<{}> is not available", audio.uri);
+                return Err(PlayerLoadError::Failed);
+            }
+        };
+
+        assert!(audio.duration >= 0);
+        let duration_ms = audio.duration as u32;
+
+        let (format, file_id) = match self.find_file(&audio) {
+            Some(file) => file,
+            None => return Err(PlayerLoadError::Failed),
         };
 
-        let bytes_per_second = self.stream_data_rate(*format);
+        if let Some(cache) = self.session.cache() {
+            cache.save_file_track(file_id, audio.id);
+        }
+
+        let bytes_per_second = self.stream_data_rate(format);
         let play_from_beginning = position_ms == 0;
 
         let key = self.session.audio_key().request(spotify_id, file_id);
@@ -675,7 +806,7 @@ impl PlayerTrackLoader {
             Ok(encrypted_file) => encrypted_file,
             Err(_) => {
                 error!("Unable to load encrypted file.");
-                return None;
+                return Err(PlayerLoadError::Failed);
             }
         };
 
@@ -693,7 +824,7 @@ impl PlayerTrackLoader {
             Ok(key) => key,
             Err(_) => {
                 error!("Unable to load decryption key");
-                return None;
+                return Err(PlayerLoadError::Failed);
             }
         };
 
@@ -722,7 +853,7 @@ impl PlayerTrackLoader {
         }
         let stream_position_pcm = PlayerInternal::position_ms_to_pcm(position_ms);
         info!("<{}> ({} ms) loaded", audio.name, audio.duration);
-        Some(PlayerLoadedTrackData {
+        Ok(PlayerLoadedTrackData {
             decoder,
             normalisation_factor,
             stream_loader_controller,
@@ -780,9 +911,15 @@ impl Future for PlayerInternal {
                         }
                     }
                     Ok(Async::NotReady) => (),
-                    Err(_) => {
+                    Err(err) => {
                         warn!("Unable to load <{:?}>\nSkipping to next track", track_id);
                         assert!(self.state.is_loading());
+                        if err == PlayerLoadError::Explicit {
+                            self.send_event(PlayerEvent::ExplicitSkipped {
+                                track_id,
+                                play_request_id,
+                            });
+                        }
                         self.send_event(PlayerEvent::EndOfTrack {
                             track_id,
                             play_request_id,
@@ -805,7 +942,7 @@ impl Future for PlayerInternal {
                         };
                     }
                     Ok(Async::NotReady) => (),
-                    Err(_) => {
+                    Err(err) => {
                         debug!("Unable to preload {:?}", track_id);
                         self.preload = PlayerPreload::None;
                         // Let Spirc know that the track was unavailable.
@@ -816,10 +953,17 @@ impl Future for PlayerInternal {
                             play_request_id, ..
                         } = self.state
                         {
-                            self.send_event(PlayerEvent::Unavailable {
-                                track_id,
-                                play_request_id,
-                            });
+                            if err == PlayerLoadError::Explicit {
+                                self.send_event(PlayerEvent::ExplicitSkipped {
+                                    track_id,
+                                    play_request_id,
+                                });
+                            } else {
+                                self.send_event(PlayerEvent::Unavailable {
+                                    track_id,
+                                    play_request_id,
+                                });
+                            }
                         }
                     }
                 }
@@ -845,6 +989,7 @@ impl Future for PlayerInternal {
                         *stream_position_pcm =
                             *stream_position_pcm + (packet.data().len() / 2) as u64;
                         let stream_position_millis = Self::position_pcm_to_ms(*stream_position_pcm);
+                        Self::set_position(&self.position, track_id, stream_position_millis);
 
                         let notify_about_position = match *reported_nominal_start_time {
                             None => true,
@@ -880,6 +1025,26 @@ impl Future for PlayerInternal {
                 };
             }
 
+            if let PlayerState::Playing {
+                track_id,
+                play_request_id,
+                stream_position_pcm,
+                ref stream_loader_controller,
+                ..
+            } = self.state
+            {
+                let underrun_count = stream_loader_controller.underrun_count();
+                if underrun_count > self.reported_underrun_count {
+                    self.reported_underrun_count = underrun_count;
+                    self.send_event(PlayerEvent::Underrun {
+                        track_id,
+                        play_request_id,
+                        position_ms: Self::position_pcm_to_ms(stream_position_pcm),
+                        underrun_count,
+                    });
+                }
+            }
+
             if let PlayerState::Playing {
                 track_id,
                 play_request_id,
@@ -935,9 +1100,7 @@ impl PlayerInternal {
     fn ensure_sink_running(&mut self) {
         if self.sink_status != SinkStatus::Running {
             trace!("== Starting sink ==");
-            if let Some(callback) = &mut self.sink_event_callback {
-                callback(SinkStatus::Running);
-            }
+            self.sink_status_changed(SinkStatus::Running);
             match self.sink.start() {
                 Ok(()) => self.sink_status = SinkStatus::Running,
                 Err(err) => error!("Could not start audio: {}", err),
@@ -945,6 +1108,13 @@ impl PlayerInternal {
         }
     }
 
+    fn sink_status_changed(&mut self, sink_status: SinkStatus) {
+        if let Some(callback) = &mut self.sink_event_callback {
+            callback(sink_status);
+        }
+        self.send_event(PlayerEvent::SinkStatusChanged { sink_status });
+    }
+
     fn ensure_sink_stopped(&mut self, temporarily: bool) {
         match self.sink_status {
             SinkStatus::Running => {
@@ -955,16 +1125,13 @@ impl PlayerInternal {
                 } else {
                     SinkStatus::Closed
                 };
-                if let Some(callback) = &mut self.sink_event_callback {
-                    callback(self.sink_status);
-                }
+                let sink_status = self.sink_status;
+                self.sink_status_changed(sink_status);
             }
             SinkStatus::TemporarilyClosed => {
                 if !temporarily {
                     self.sink_status = SinkStatus::Closed;
-                    if let Some(callback) = &mut self.sink_event_callback {
-                        callback(SinkStatus::Closed);
-                    }
+                    self.sink_status_changed(SinkStatus::Closed);
                 }
             }
             SinkStatus::Closed => (),
@@ -1101,6 +1268,7 @@ impl PlayerInternal {
         start_playback: bool,
     ) {
         let position_ms = Self::position_pcm_to_ms(loaded_track.stream_position_pcm);
+        self.reported_underrun_count = loaded_track.stream_loader_controller.underrun_count();
 
         if start_playback {
             self.ensure_sink_running();
@@ -1512,10 +1680,45 @@ impl PlayerInternal {
             PlayerCommand::EmitVolumeSetEvent(volume) => {
                 self.send_event(PlayerEvent::VolumeSet { volume })
             }
+
+            PlayerCommand::EmitTakeoverEvent {
+                device_ident,
+                device_name,
+                accepted,
+            } => self.send_event(PlayerEvent::Takeover {
+                device_ident,
+                device_name,
+                accepted,
+            }),
+
+            PlayerCommand::EmitAutoplayStartedEvent {
+                context_uri,
+                station_uri,
+            } => self.send_event(PlayerEvent::AutoplayStarted {
+                context_uri,
+                station_uri,
+            }),
         }
     }
 
+    fn set_position(position: &SharedPosition, track_id: SpotifyId, position_ms: u32) {
+        *position.lock().unwrap() = Some(PlayerPosition {
+            track_id,
+            position_ms,
+            measured_at: SystemTime::now(),
+        });
+    }
+
     fn send_event(&mut self, event: PlayerEvent) {
+        // the shared position is at least as recent as the one of the event
+        if let PlayerEvent::Playing {
+            track_id,
+            position_ms,
+            ..
+        } = event
+        {
+            Self::set_position(&self.position, track_id, position_ms);
+        }
         let mut index = 0;
         while index < self.event_senders.len() {
             match self.event_senders[index].unbounded_send(event.clone()) {
@@ -1531,7 +1734,7 @@ impl PlayerInternal {
         &self,
         spotify_id: SpotifyId,
         position_ms: u32,
-    ) -> Box<dyn Future<Item = PlayerLoadedTrackData, Error = ()>> {
+    ) -> Box<dyn Future<Item = PlayerLoadedTrackData, Error = PlayerLoadError>> {
         // This method creates a future that returns the loaded stream and associated info.
         // Ideally all work should be done using asynchronous code. However, seek() on the
         // audio stream is implemented in a blocking fashion. Thus, we can't turn it into future
@@ -1546,15 +1749,14 @@ impl PlayerInternal {
         let (result_tx, result_rx) = futures::sync::oneshot::channel();
 
         std::thread::spawn(move || {
-            loader
-                .load_track(spotify_id, position_ms)
-                .and_then(move |data| {
-                    let _ = result_tx.send(data);
-                    Some(())
-                });
+            let _ = result_tx.send(loader.load_track(spotify_id, position_ms));
         });
 
-        Box::new(result_rx.map_err(|_| ()))
+        Box::new(
+            result_rx
+                .map_err(|_| PlayerLoadError::Failed)
+                .and_then(|result| result),
+        )
     }
 
     fn preload_data_before_playback(&mut self) {
@@ -1619,6 +1821,21 @@ impl ::std::fmt::Debug for PlayerCommand {
             PlayerCommand::EmitVolumeSetEvent(volume) => {
                 f.debug_tuple("VolumeSet").field(&volume).finish()
             }
+            PlayerCommand::EmitTakeoverEvent {
+                ref device_ident,
+                accepted,
+                ..
+            } => f
+                .debug_tuple("Takeover")
+                .field(&device_ident)
+                .field(&accepted)
+                .finish(),
+            PlayerCommand::EmitAutoplayStartedEvent {
+                ref station_uri, ..
+            } => f
+                .debug_tuple("AutoplayStarted")
+                .field(&station_uri)
+                .finish(),
         }
     }
 }
@@ -0,0 +1,229 @@
+use futures::Future;
+use std::cmp::min;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use librespot_core::session::Session;
+use librespot_core::spotify_id::SpotifyId;
+
+use crate::audio::{AudioFile, Range, StreamLoaderController};
+use crate::config::PlayerConfig;
+use crate::metadata::AudioItem;
+use crate::player::PlayerTrackLoader;
+
+const PREFETCH_CHUNK_SIZE: usize = 256 * 1024;
+// The amount of data that is requested at once while prefetching a track. With a bandwidth
+// limit in place, the chunk size is reduced to what may be downloaded in one second.
+
+const PREFETCH_MAXIMUM_PING_TIME_MS: usize = 500;
+// Prefetching is only done on a good connection. If the measured ping time to the Spotify
+// server exceeds this value, prefetching of the current track is abandoned.
+
+const PREFETCH_BACKOFF: Duration = Duration::from_secs(30);
+// After giving up because of a bad connection, wait this long before trying again.
+
+pub struct Prefetcher {
+    tracks_tx: Sender<Vec<SpotifyId>>,
+}
+
+enum PrefetchResult {
+    Done,
+    Interrupted(Vec<SpotifyId>),
+    BadConnection,
+}
+
+struct PrefetcherInternal {
+    loader: PlayerTrackLoader,
+    tracks_rx: Receiver<Vec<SpotifyId>>,
+    number_of_tracks: usize,
+    bandwidth_limit: Option<usize>,
+}
+
+impl Prefetcher {
+    pub fn new(config: PlayerConfig, session: Session) -> Option<Prefetcher> {
+        if config.prefetch_tracks == 0 {
+            return None;
+        }
+
+        match session.cache() {
+            Some(cache) if cache.use_audio_cache() => (),
+            _ => {
+                warn!("Track prefetching requires an audio cache, disabling it.");
+                return None;
+            }
+        }
+
+        let (tracks_tx, tracks_rx) = mpsc::channel();
+
+        let internal = PrefetcherInternal {
+            number_of_tracks: config.prefetch_tracks,
+            // a limit of 0 would never make progress, treat it as no limit
+            bandwidth_limit: config.prefetch_bandwidth.filter(|limit| *limit > 0),
+            loader: PlayerTrackLoader { session, config },
+            tracks_rx,
+        };
+
+        thread::spawn(move || {
+            internal.run();
+            debug!("Prefetcher thread finished.");
+        });
+
+        Some(Prefetcher { tracks_tx })
+    }
+
+    // Replaces the list of upcoming tracks. Only the first tracks of the list are fetched.
+    pub fn prefetch(&self, tracks: Vec<SpotifyId>) {
+        let _ = self.tracks_tx.send(tracks);
+    }
+}
+
+impl PrefetcherInternal {
+    fn run(self) {
+        let mut pending = match self.tracks_rx.recv() {
+            Ok(tracks) => tracks,
+            Err(_) => return,
+        };
+
+        loop {
+            let mut result = PrefetchResult::Done;
+            for track_id in pending.iter().take(self.number_of_tracks) {
+                if self.loader.session.is_invalid() {
+                    return;
+                }
+                result = self.prefetch_track(*track_id);
+                match result {
+                    PrefetchResult::Done => (),
+                    _ => break,
+                }
+            }
+
+            pending = match result {
+                PrefetchResult::Interrupted(tracks) => tracks,
+                PrefetchResult::BadConnection => {
+                    // wait for the connection to recover, but follow queue changes.
+                    match self.tracks_rx.recv_timeout(PREFETCH_BACKOFF) {
+                        Ok(tracks) => tracks,
+                        Err(mpsc::RecvTimeoutError::Timeout) => pending,
+                        Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+                PrefetchResult::Done => match self.tracks_rx.recv() {
+                    Ok(tracks) => tracks,
+                    Err(_) => return,
+                },
+            };
+        }
+    }
+
+    fn poll_new_tracks(&self) -> Option<Vec<SpotifyId>> {
+        let mut latest = None;
+        loop {
+            match self.tracks_rx.try_recv() {
+                Ok(tracks) => latest = Some(tracks),
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => return latest,
+            }
+        }
+    }
+
+    fn prefetch_track(&self, track_id: SpotifyId) -> PrefetchResult {
+        let session = &self.loader.session;
+
+        let audio = match AudioItem::get_audio_item(session, track_id).wait() {
+            Ok(audio) => audio,
+            Err(_) => {
+                debug!("Unable to get audio item for prefetching <{:?}>", track_id);
+                return PrefetchResult::Done;
+            }
+        };
+
+        let audio = match self.loader.find_available_alternative(&audio) {
+            Some(audio) => audio,
+            None => return PrefetchResult::Done,
+        };
+
+        let (format, file_id) = match self.loader.find_file(&audio) {
+            Some(file) => file,
+            None => return PrefetchResult::Done,
+        };
+
+        if let Some(cache) = session.cache() {
+            if cache.file(file_id).is_some() {
+                trace!("<{}> is already cached", audio.uri);
+                return PrefetchResult::Done;
+            }
+            cache.save_file_track(file_id, audio.id);
+        }
+
+        let bytes_per_second = self.loader.stream_data_rate(format);
+        let file = match AudioFile::open(session, file_id, bytes_per_second, false).wait() {
+            Ok(file) => file,
+            Err(_) => {
+                debug!("Unable to open <{}> for prefetching", audio.uri);
+                return PrefetchResult::Done;
+            }
+        };
+
+        let mut stream_loader_controller = file.get_stream_loader_controller();
+        let file_size = stream_loader_controller.len();
+        let chunk_size = match self.bandwidth_limit {
+            Some(limit) => min(limit, PREFETCH_CHUNK_SIZE),
+            None => PREFETCH_CHUNK_SIZE,
+        };
+
+        info!("Prefetching <{}> ({} bytes)", audio.uri, file_size);
+
+        let mut restart_with = None;
+        let mut offset = 0;
+        while offset < file_size {
+            let length = min(chunk_size, file_size - offset);
+            self.fetch_chunk(&mut stream_loader_controller, Range::new(offset, length));
+            offset += length;
+
+            if stream_loader_controller.ping_time_ms() > PREFETCH_MAXIMUM_PING_TIME_MS {
+                debug!(
+                    "Ping time of {} ms is too high, pausing prefetch.",
+                    stream_loader_controller.ping_time_ms()
+                );
+                stream_loader_controller.close();
+                return PrefetchResult::BadConnection;
+            }
+
+            if let Some(tracks) = self.poll_new_tracks() {
+                if !tracks
+                    .iter()
+                    .take(self.number_of_tracks)
+                    .any(|id| *id == track_id)
+                {
+                    debug!(
+                        "<{}> is no longer upcoming, stop prefetching it.",
+                        audio.uri
+                    );
+                    stream_loader_controller.close();
+                    return PrefetchResult::Interrupted(tracks);
+                }
+                // finish the current track, then start over with the new queue.
+                restart_with = Some(tracks);
+            }
+        }
+
+        debug!("Prefetched <{}>", audio.uri);
+        match restart_with {
+            Some(tracks) => PrefetchResult::Interrupted(tracks),
+            None => PrefetchResult::Done,
+        }
+    }
+
+    fn fetch_chunk(&self, stream_loader_controller: &mut StreamLoaderController, range: Range) {
+        let chunk_start_time = Instant::now();
+        stream_loader_controller.fetch_blocking(range);
+
+        if let Some(limit) = self.bandwidth_limit {
+            let budget = Duration::from_millis((1000 * range.length / limit) as u64);
+            let elapsed = Instant::now() - chunk_start_time;
+            if elapsed < budget {
+                thread::sleep(budget - elapsed);
+            }
+        }
+    }
+}
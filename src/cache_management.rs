@@ -0,0 +1,181 @@
+use log::error;
+use std::time::{Duration, SystemTime};
+
+use librespot::core::cache::{Cache, CachedFile, FileIntegrity};
+
+const CACHE_ACTIONS: &[&str] = &[
+    "cache-list",
+    "cache-usage",
+    "cache-verify",
+    "cache-purge-age",
+    "cache-purge-size",
+    "cache-remove-credentials",
+];
+
+pub fn add_cache_options(opts: &mut getopts::Options) {
+    opts.optflag(
+        "",
+        "cache-list",
+        "List the files in the cache with their track and size, then exit.",
+    )
+    .optflag(
+        "",
+        "cache-usage",
+        "Show the disk space used by cached files, then exit.",
+    )
+    .optflag(
+        "",
+        "cache-verify",
+        "Verify the checksums of cached files, then exit.",
+    )
+    .optopt(
+        "",
+        "cache-purge-age",
+        "Remove cached files which were downloaded more than the given number of days ago, then exit.",
+        "DAYS",
+    )
+    .optopt(
+        "",
+        "cache-purge-size",
+        "Remove the cached files which were downloaded first until the cache is at most the given size in MB, then exit. Playing a cached file does not make it newer.",
+        "SIZE",
+    )
+    .optflag(
+        "",
+        "cache-remove-credentials",
+        "Remove the credentials stored in the cache, then exit.",
+    );
+}
+
+pub fn cache_action_requested(matches: &getopts::Matches) -> bool {
+    CACHE_ACTIONS
+        .iter()
+        .any(|action| matches.opt_present(action))
+}
+
+// Runs the cache actions given on the command line and returns the exit code.
+pub fn run_cache_actions(matches: &getopts::Matches, cache: &Cache) -> i32 {
+    let mut status = 0;
+
+    if matches.opt_present("cache-remove-credentials") {
+        match cache.remove_credentials() {
+            Ok(()) => println!("Removed stored credentials"),
+            Err(err) => {
+                error!("Unable to remove credentials: {}", err);
+                status = 1;
+            }
+        }
+    }
+
+    if let Some(days) = matches.opt_str("cache-purge-age") {
+        let days = days.parse::<u64>().expect("Invalid number of days");
+        match cache.purge_older_than(Duration::from_secs(days * 24 * 60 * 60)) {
+            Ok(purged) => print_purged(&purged),
+            Err(err) => {
+                error!("Unable to purge cache: {}", err);
+                status = 1;
+            }
+        }
+    }
+
+    if let Some(size) = matches.opt_str("cache-purge-size") {
+        let size = size.parse::<u64>().expect("Invalid cache size");
+        match cache.purge_to_size(size * 1024 * 1024) {
+            Ok(purged) => print_purged(&purged),
+            Err(err) => {
+                error!("Unable to purge cache: {}", err);
+                status = 1;
+            }
+        }
+    }
+
+    if matches.opt_present("cache-verify") {
+        match cache.files() {
+            Ok(files) => {
+                // partial downloads have no checksum yet
+                let files: Vec<_> = files.into_iter().filter(|file| !file.partial).collect();
+                let mut corrupt = 0;
+                for file in files.iter() {
+                    match cache.verify_file(file.file_id) {
+                        FileIntegrity::Valid => (),
+                        FileIntegrity::Corrupt => {
+                            println!("{} is corrupt", file.file_id);
+                            corrupt += 1;
+                        }
+                        FileIntegrity::Unknown => println!("{} has no checksum", file.file_id),
+                    }
+                }
+                println!("Verified {} files, {} corrupt", files.len(), corrupt);
+                if corrupt > 0 {
+                    status = 1;
+                }
+            }
+            Err(err) => {
+                error!("Unable to read cache: {}", err);
+                status = 1;
+            }
+        }
+    }
+
+    if matches.opt_present("cache-list") {
+        match cache.files() {
+            Ok(mut files) => {
+                files.sort_by_key(|file| file.modified);
+                for file in files.iter() {
+                    println!(
+                        "{}  {:>10}  {:>6} days  {}{}",
+                        file.file_id,
+                        file.size,
+                        age_in_days(file),
+                        file.track_uri.as_ref().map_or("unknown", String::as_str),
+                        if file.partial { "  (partial)" } else { "" }
+                    );
+                }
+            }
+            Err(err) => {
+                error!("Unable to read cache: {}", err);
+                status = 1;
+            }
+        }
+    }
+
+    if matches.opt_present("cache-usage") {
+        match cache.files() {
+            Ok(files) => {
+                let usage: u64 = files.iter().map(|file| file.size).sum();
+                let partial = files.iter().filter(|file| file.partial).count();
+                println!(
+                    "{} files ({} partial), {:.1} MB",
+                    files.len(),
+                    partial,
+                    usage as f64 / (1024.0 * 1024.0)
+                );
+            }
+            Err(err) => {
+                error!("Unable to read cache: {}", err);
+                status = 1;
+            }
+        }
+    }
+
+    status
+}
+
+fn age_in_days(file: &CachedFile) -> u64 {
+    SystemTime::now()
+        .duration_since(file.modified)
+        .map(|age| age.as_secs() / (24 * 60 * 60))
+        .unwrap_or(0)
+}
+
+fn print_purged(purged: &[CachedFile]) {
+    let size: u64 = purged.iter().map(|file| file.size).sum();
+    println!(
+        "Purged {} files, {:.1} MB",
+        purged.len(),
+        size as f64 / (1024.0 * 1024.0)
+    );
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,86 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::*;
+use librespot::core::cache_storage::{CacheStorage, MemoryStorage};
+use librespot::core::spotify_id::FileId;
+
+fn matches(args: &[&str]) -> getopts::Matches {
+    let mut opts = getopts::Options::new();
+    add_cache_options(&mut opts);
+    opts.parse(args).unwrap()
+}
+
+// A cache with files of 1 MB, downloaded the given number of days ago
+fn cache_with_files(ages_in_days: &[u64]) -> Cache {
+    let storage = Arc::new(MemoryStorage::new());
+    let cache = Cache::with_storage(storage.clone(), true);
+    for (n, days) in ages_in_days.iter().enumerate() {
+        let file_id = FileId([n as u8 + 1; 20]);
+        cache.save_file(file_id, &mut &vec![0; 1024 * 1024][..]);
+        let name = file_id.to_base16();
+        let key = format!("files/{}/{}", &name[0..2], &name[2..]);
+        storage.set_modified(
+            &key,
+            SystemTime::now() - Duration::from_secs(days * 24 * 60 * 60),
+        );
+    }
+    cache
+}
+
+fn cached_ids(cache: &Cache) -> Vec<u8> {
+    let mut ids: Vec<_> = cache
+        .files()
+        .unwrap()
+        .iter()
+        .map(|file| file.file_id.0[0])
+        .collect();
+    ids.sort();
+    ids
+}
+
+#[test]
+fn only_cache_options_are_actions() {
+    assert!(!cache_action_requested(&matches(&[])));
+    assert!(cache_action_requested(&matches(&["--cache-usage"])));
+    assert!(cache_action_requested(&matches(&[
+        "--cache-purge-age",
+        "7"
+    ])));
+}
+
+#[test]
+fn purge_age_option_removes_old_files() {
+    let cache = cache_with_files(&[10, 2, 30]);
+
+    assert_eq!(
+        run_cache_actions(&matches(&["--cache-purge-age", "7"]), &cache),
+        0
+    );
+    assert_eq!(cached_ids(&cache), vec![2]);
+}
+
+#[test]
+fn purge_size_option_removes_first_downloads() {
+    let cache = cache_with_files(&[10, 2, 30]);
+
+    assert_eq!(
+        run_cache_actions(&matches(&["--cache-purge-size", "2"]), &cache),
+        0
+    );
+    assert_eq!(cached_ids(&cache), vec![1, 2]);
+}
+
+#[test]
+fn verify_option_fails_on_corrupt_files() {
+    let storage = Arc::new(MemoryStorage::new());
+    let cache = Cache::with_storage(storage.clone(), true);
+    let file_id = FileId([1; 20]);
+    cache.save_file(file_id, &mut &b"audio"[..]);
+    assert_eq!(run_cache_actions(&matches(&["--cache-verify"]), &cache), 0);
+
+    let name = file_id.to_base16();
+    let key = format!("files/{}/{}", &name[0..2], &name[2..]);
+    storage.write(&key, &mut &b"audi0"[..]).unwrap();
+    assert_eq!(run_cache_actions(&matches(&["--cache-verify"]), &cache), 1);
+}
@@ -0,0 +1,250 @@
+// Writes player, sink and session events as one JSON object per line, in the order they
+// happened. Sink events come with the player events, so they are in order with them. An alternative to --onevent for supervisors which consume a single stream.
+// Events are dropped rather than queued without limit when the target doesn't keep up.
+use log::{error, info, warn};
+use serde_json::{json, Value};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use librespot::playback::player::{PlayerEvent, SinkStatus};
+
+const MAXIMUM_QUEUED_EVENTS: usize = 1024;
+const REOPEN_INTERVAL: Duration = Duration::from_secs(1);
+// Time to wait before opening the target again after it failed, e.g. because the reader of
+// the FIFO or socket went away.
+
+#[derive(Clone)]
+pub struct EventSink {
+    events: SyncSender<Value>,
+    // events dropped since the last one which was written
+    dropped: Arc<AtomicUsize>,
+}
+
+impl EventSink {
+    // target is "-" for stdout, "unix:PATH" for a Unix socket or the path of a file or FIFO.
+    // Opening a FIFO waits for a reader, so it is opened by the writer thread.
+    pub fn open(target: String) -> EventSink {
+        let (tx, rx) = mpsc::sync_channel(MAXIMUM_QUEUED_EVENTS);
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let writer_dropped = dropped.clone();
+        thread::spawn(move || write_events(&target, rx, &writer_dropped));
+        EventSink {
+            events: tx,
+            dropped,
+        }
+    }
+
+    pub fn player_event(&self, event: &PlayerEvent) {
+        self.send(player_event_json(event));
+    }
+
+    pub fn session_event(&self, connected: bool, user_name: String) {
+        let event = if connected {
+            "session_connected"
+        } else {
+            "session_disconnected"
+        };
+        self.send(json!({ "event": event, "user_name": user_name }));
+    }
+
+    fn send(&self, event: Value) {
+        if let Err(TrySendError::Full(_)) = self.events.try_send(event) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn open_writer(target: &str) -> io::Result<Box<dyn Write>> {
+    if target == "-" {
+        return Ok(Box::new(io::stdout()));
+    }
+    let mut scheme = target.splitn(2, ':');
+    if let (Some("unix"), Some(path)) = (scheme.next(), scheme.next()) {
+        return open_unix_socket(path);
+    }
+    let file = OpenOptions::new().append(true).create(true).open(target)?;
+    Ok(Box::new(file))
+}
+
+#[cfg(unix)]
+fn open_unix_socket(path: &str) -> io::Result<Box<dyn Write>> {
+    Ok(Box::new(UnixStream::connect(path)?))
+}
+
+#[cfg(not(unix))]
+fn open_unix_socket(_: &str) -> io::Result<Box<dyn Write>> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Unix sockets are not supported on this platform",
+    ))
+}
+
+// Only the first failure is logged, the target may well be missing for a while.
+fn open_writer_retrying(target: &str) -> Box<dyn Write> {
+    let mut logged = false;
+    loop {
+        match open_writer(target) {
+            Ok(writer) => {
+                info!("Writing events to {}", target);
+                return writer;
+            }
+            Err(err) => {
+                if !logged {
+                    error!("Unable to open event sink {}: {}", target, err);
+                    logged = true;
+                }
+            }
+        }
+        thread::sleep(REOPEN_INTERVAL);
+    }
+}
+
+fn write_events(target: &str, events: Receiver<Value>, dropped: &AtomicUsize) {
+    write_events_with(|| open_writer_retrying(target), target, events, dropped)
+}
+
+// Writes to what open returns, and opens it again after a write failed.
+fn write_events_with<F>(mut open: F, target: &str, events: Receiver<Value>, dropped: &AtomicUsize)
+where
+    F: FnMut() -> Box<dyn Write>,
+{
+    let mut writer = open();
+    for event in events.iter() {
+        while let Err(err) = writeln!(writer, "{}", event).and_then(|_| writer.flush()) {
+            error!("Unable to write event to {}: {}", target, err);
+            thread::sleep(REOPEN_INTERVAL);
+            writer = open();
+        }
+
+        let count = dropped.swap(0, Ordering::Relaxed);
+        if count > 0 {
+            warn!(
+                "Dropped {} events, the event sink {} did not keep up",
+                count, target
+            );
+        }
+    }
+}
+
+fn player_event_json(event: &PlayerEvent) -> Value {
+    match *event {
+        PlayerEvent::Changed {
+            old_track_id,
+            new_track_id,
+        } => json!({
+            "event": "change",
+            "old_track_id": old_track_id.to_base62(),
+            "track_id": new_track_id.to_base62(),
+        }),
+        PlayerEvent::Started {
+            track_id,
+            position_ms,
+            ..
+        } => json!({
+            "event": "start",
+            "track_id": track_id.to_base62(),
+            "position_ms": position_ms,
+        }),
+        PlayerEvent::Stopped { track_id, .. } => json!({
+            "event": "stop",
+            "track_id": track_id.to_base62(),
+        }),
+        PlayerEvent::Loading {
+            track_id,
+            position_ms,
+            ..
+        } => json!({
+            "event": "loading",
+            "track_id": track_id.to_base62(),
+            "position_ms": position_ms,
+        }),
+        PlayerEvent::Playing {
+            track_id,
+            position_ms,
+            duration_ms,
+            ..
+        } => json!({
+            "event": "playing",
+            "track_id": track_id.to_base62(),
+            "position_ms": position_ms,
+            "duration_ms": duration_ms,
+        }),
+        PlayerEvent::Paused {
+            track_id,
+            position_ms,
+            duration_ms,
+            ..
+        } => json!({
+            "event": "paused",
+            "track_id": track_id.to_base62(),
+            "position_ms": position_ms,
+            "duration_ms": duration_ms,
+        }),
+        PlayerEvent::Underrun {
+            track_id,
+            position_ms,
+            underrun_count,
+            ..
+        } => json!({
+            "event": "underrun",
+            "track_id": track_id.to_base62(),
+            "position_ms": position_ms,
+            "underrun_count": underrun_count,
+        }),
+        PlayerEvent::TimeToPreloadNextTrack { track_id, .. } => json!({
+            "event": "preload",
+            "track_id": track_id.to_base62(),
+        }),
+        PlayerEvent::EndOfTrack { track_id, .. } => json!({
+            "event": "end_of_track",
+            "track_id": track_id.to_base62(),
+        }),
+        PlayerEvent::Unavailable { track_id, .. } => json!({
+            "event": "unavailable",
+            "track_id": track_id.to_base62(),
+        }),
+        PlayerEvent::ExplicitSkipped { track_id, .. } => json!({
+            "event": "explicit_skipped",
+            "track_id": track_id.to_base62(),
+        }),
+        PlayerEvent::VolumeSet { volume } => json!({
+            "event": "volume_set",
+            "volume": volume,
+        }),
+        PlayerEvent::Takeover {
+            ref device_ident,
+            ref device_name,
+            accepted,
+        } => json!({
+            "event": if accepted { "takeover" } else { "takeover_refused" },
+            "device_id": device_ident,
+            "device_name": device_name,
+        }),
+        PlayerEvent::AutoplayStarted {
+            ref context_uri,
+            ref station_uri,
+        } => json!({
+            "event": "autoplay_started",
+            "context_uri": context_uri,
+            "station_uri": station_uri,
+        }),
+        PlayerEvent::SinkStatusChanged { sink_status } => json!({
+            "event": "sink",
+            "sink_status": match sink_status {
+                SinkStatus::Running => "running",
+                SinkStatus::TemporarilyClosed => "temporarily_closed",
+                SinkStatus::Closed => "closed",
+            },
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests;
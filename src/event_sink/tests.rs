@@ -0,0 +1,144 @@
+use std::io::Read;
+use std::sync::Mutex;
+use std::{env, fs, process};
+
+use super::*;
+use librespot::core::spotify_id::SpotifyId;
+
+fn track_id(n: u8) -> SpotifyId {
+    SpotifyId::from_raw(&[n; 16]).unwrap()
+}
+
+#[test]
+fn player_events_are_written_as_json() {
+    let playing = player_event_json(&PlayerEvent::Playing {
+        play_request_id: 1,
+        track_id: track_id(1),
+        position_ms: 1_000,
+        duration_ms: 180_000,
+    });
+    assert_eq!(
+        playing,
+        json!({
+            "event": "playing",
+            "track_id": track_id(1).to_base62(),
+            "position_ms": 1_000,
+            "duration_ms": 180_000,
+        })
+    );
+
+    let refused = player_event_json(&PlayerEvent::Takeover {
+        device_ident: String::from("ident"),
+        device_name: String::from("Kitchen"),
+        accepted: false,
+    });
+    assert_eq!(refused["event"], "takeover_refused");
+    assert_eq!(refused["device_name"], "Kitchen");
+
+    let sink = player_event_json(&PlayerEvent::SinkStatusChanged {
+        sink_status: SinkStatus::TemporarilyClosed,
+    });
+    assert_eq!(
+        sink,
+        json!({ "event": "sink", "sink_status": "temporarily_closed" })
+    );
+}
+
+#[test]
+fn events_beyond_the_queue_are_counted_as_dropped() {
+    let (tx, rx) = mpsc::sync_channel(MAXIMUM_QUEUED_EVENTS);
+    let sink = EventSink {
+        events: tx,
+        dropped: Arc::new(AtomicUsize::new(0)),
+    };
+
+    for _ in 0..MAXIMUM_QUEUED_EVENTS + 5 {
+        sink.session_event(true, String::from("user"));
+    }
+
+    assert_eq!(sink.dropped.load(Ordering::Relaxed), 5);
+    assert_eq!(rx.try_iter().count(), MAXIMUM_QUEUED_EVENTS);
+}
+
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct BrokenPipe;
+
+impl Write for BrokenPipe {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(
+            io::ErrorKind::BrokenPipe,
+            "reader went away",
+        ))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn target_is_reopened_after_a_write_error() {
+    let (tx, rx) = mpsc::sync_channel(MAXIMUM_QUEUED_EVENTS);
+    tx.send(json!({ "event": "first" })).unwrap();
+    tx.send(json!({ "event": "second" })).unwrap();
+    drop(tx);
+
+    let buffer = SharedBuffer::default();
+    let mut opened = 0;
+    let dropped = AtomicUsize::new(3);
+    write_events_with(
+        || -> Box<dyn Write> {
+            opened += 1;
+            if opened == 1 {
+                Box::new(BrokenPipe)
+            } else {
+                Box::new(buffer.clone())
+            }
+        },
+        "test",
+        rx,
+        &dropped,
+    );
+
+    assert_eq!(opened, 2);
+    let written = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    assert_eq!(written, "{\"event\":\"first\"}\n{\"event\":\"second\"}\n");
+    // reported once the target keeps up again
+    assert_eq!(dropped.load(Ordering::Relaxed), 0);
+}
+
+#[cfg(unix)]
+#[test]
+fn unix_target_connects_to_the_socket() {
+    use std::os::unix::net::UnixListener;
+
+    let path = env::temp_dir().join(format!("librespot-event-sink-{}", process::id()));
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+
+    let mut writer = open_writer(&format!("unix:{}", path.display())).unwrap();
+    writer.write_all(b"{}\n").unwrap();
+    drop(writer);
+
+    let mut written = String::new();
+    listener
+        .accept()
+        .unwrap()
+        .0
+        .read_to_string(&mut written)
+        .unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(written, "{}\n");
+}
@@ -15,19 +15,35 @@ use url::Url;
 
 use librespot::core::authentication::{get_credentials, Credentials};
 use librespot::core::cache::Cache;
-use librespot::core::config::{ConnectConfig, DeviceType, SessionConfig, VolumeCtrl};
+use librespot::core::config::{
+    ConnectCapabilities, ConnectConfig, ContentRestriction, DeviceType, SessionConfig,
+    TakeoverPolicy, VolumeCtrl,
+};
 use librespot::core::session::Session;
 use librespot::core::version;
 
 use librespot::connect::discovery::{discovery, DiscoveryStream};
+use librespot::connect::group::{GroupFollower, GroupLeader};
 use librespot::connect::spirc::{Spirc, SpircTask};
 use librespot::playback::audio_backend::{self, Sink, BACKENDS};
 use librespot::playback::config::{Bitrate, PlayerConfig};
 use librespot::playback::mixer::{self, Mixer, MixerConfig};
 use librespot::playback::player::{Player, PlayerEvent};
 
+mod cache_management;
+use crate::cache_management::{add_cache_options, cache_action_requested, run_cache_actions};
+
+mod event_sink;
+use crate::event_sink::EventSink;
+
 mod player_event_handler;
-use crate::player_event_handler::{emit_sink_event, run_program_on_events};
+use crate::player_event_handler::{
+    emit_sink_event, run_program_on_events, run_program_on_events_with_metadata,
+    run_program_on_session_event,
+};
+
+mod scrobbler;
+use crate::scrobbler::{Scrobbler, ScrobblerConfig, ScrobblerService};
 
 fn device_id(name: &str) -> String {
     hex::encode(Sha1::digest(name.as_bytes()))
@@ -88,6 +104,14 @@ struct Setup {
     zeroconf_port: u16,
     player_event_program: Option<String>,
     emit_sink_events: bool,
+    onevent_metadata: bool,
+    event_sink: Option<String>,
+    resume: bool,
+    group_leader: Option<String>,
+    group_follow: Option<String>,
+    group_latency: i64,
+    group_secret: String,
+    scrobbler_config: Option<ScrobblerConfig>,
 }
 
 fn setup(args: &[String]) -> Setup {
@@ -98,7 +122,7 @@ fn setup(args: &[String]) -> Setup {
         "Path to a directory where files will be cached.",
         "CACHE",
     ).optflag("", "disable-audio-cache", "Disable caching of the audio data.")
-        .reqopt("n", "name", "Device name", "NAME")
+        .optopt("n", "name", "Device name", "NAME")
         .optopt("", "device-type", "Displayed device type", "DEVICE_TYPE")
         .optopt(
             "b",
@@ -113,6 +137,17 @@ fn setup(args: &[String]) -> Setup {
             "PROGRAM",
         )
         .optflag("", "emit-sink-events", "Run program set by --onevent before sink is opened and after it is closed.")
+        .optflag(
+            "",
+            "onevent-metadata",
+            "Pass the name, artists, album and cover of the track to the program set by --onevent, with one artist per line in ARTISTS. Also runs it when a track is loading, preloaded, unavailable or ends, and when the session connects or disconnects.",
+        )
+        .optopt(
+            "",
+            "event-sink",
+            "Write player, sink and session events as JSON lines to TARGET: - for stdout, unix:PATH for a Unix socket or the path of a file or FIFO.",
+            "TARGET",
+        )
         .optflag("v", "verbose", "Enable verbose output")
         .optopt("u", "username", "Username to sign in with", "USERNAME")
         .optopt("p", "password", "Password", "PASSWORD")
@@ -155,6 +190,11 @@ fn setup(args: &[String]) -> Setup {
             "mixer-linear-volume",
             "Disable alsa's mapped volume scale (cubic). Default false",
         )
+        .optflag(
+            "",
+            "mixer-watch",
+            "Report volume changes made directly on the alsa mixer to Connect clients",
+        )
         .optopt(
             "",
             "initial-volume",
@@ -181,19 +221,155 @@ fn setup(args: &[String]) -> Setup {
         .optopt(
             "",
             "volume-ctrl",
-            "Volume control type - [linear, log, fixed]. Default is logarithmic",
+            "Volume control type - [linear, log, cubic, fixed]. Default is logarithmic",
             "VOLUME_CTRL"
         )
+        .optopt(
+            "",
+            "volume-steps",
+            "Number of volume steps offered to Connect clients. Default is 64",
+            "STEPS",
+        )
+        .optopt(
+            "",
+            "volume-step-size",
+            "Amount by which volume up and down change the volume, out of 65535. Default is 4096",
+            "SIZE",
+        )
+        .optopt(
+            "",
+            "volume-range",
+            "Range in dB of the log and cubic volume control. Default is 60",
+            "DB",
+        )
         .optflag(
             "",
             "autoplay",
             "autoplay similar songs when your music ends.",
         )
+        .optopt(
+            "",
+            "group-leader",
+            "Play in sync with the instances following this one. Followers connect to the given address",
+            "ADDR",
+        )
+        .optopt(
+            "",
+            "group-follow",
+            "Play in sync with the group leader at the given address instead of being a Connect device. Requires credentials",
+            "ADDR",
+        )
+        .optopt(
+            "",
+            "group-latency",
+            "Milliseconds to play ahead of the group leader, may be negative. Default is 0",
+            "MS",
+        )
+        .optopt(
+            "",
+            "group-secret",
+            "Secret shared by the group leader and its followers, which they authenticate each other with. The connection is not encrypted. Required with --group-leader and --group-follow",
+            "SECRET",
+        )
+        .optopt(
+            "",
+            "scrobble",
+            "Scrobble played tracks to SERVICE. Either lastfm or listenbrainz. Requires --scrobble-url",
+            "SERVICE",
+        )
+        .optopt(
+            "",
+            "scrobble-token",
+            "Session key for Last.fm or user token for ListenBrainz",
+            "TOKEN",
+        )
+        .optopt("", "scrobble-api-key", "Last.fm API key", "KEY")
+        .optopt("", "scrobble-api-secret", "Last.fm API shared secret", "SECRET")
+        .optopt(
+            "",
+            "scrobble-url",
+            "Submit scrobbles to URL, a local proxy which forwards them to the HTTPS endpoint of the service. Must be a loopback address, as the token is sent in plain HTTP",
+            "URL",
+        )
+        .optflag(
+            "",
+            "persist-state",
+            "Store the Connect state in the cache, a local play command resumes it after a restart",
+        )
+        .optflag(
+            "",
+            "resume",
+            "Resume playback of the stored Connect state on startup. Implies --persist-state",
+        )
+        .optopt(
+            "",
+            "context-history",
+            "Number of played tracks of a station or autoplay to keep. Default is 10",
+            "TRACKS",
+        )
+        .optopt(
+            "",
+            "context-fetch-threshold",
+            "Fetch more tracks of a station or autoplay when fewer tracks are left. Default is 5",
+            "TRACKS",
+        )
+        .optopt(
+            "",
+            "takeover-policy",
+            "When to hand over playback to another device - [always, not-while-playing, allow-list]. Default is always. Official clients may keep playing when refused",
+            "POLICY",
+        )
+        .optflag(
+            "",
+            "disable-volume-control",
+            "Do not allow other Connect clients to change the volume",
+        )
+        .optflag(
+            "",
+            "hidden",
+            "Ask Connect clients to hide the device",
+        )
+        .optopt(
+            "",
+            "content",
+            "Content which may be played - [all, music, podcasts]. Default is all",
+            "CONTENT",
+        )
+        .optmulti(
+            "",
+            "supported-type",
+            "Additional type to advertise as supported to Connect clients. Can be given multiple times",
+            "TYPE",
+        )
+        .optmulti(
+            "",
+            "takeover-allow",
+            "Name or id of a device which may take over playback with the allow-list policy. Can be given multiple times",
+            "DEVICE",
+        )
         .optflag(
             "",
             "disable-gapless",
             "disable gapless playback.",
+        )
+        .optopt(
+            "",
+            "prefetch-tracks",
+            "Number of upcoming tracks to download into the cache ahead of time. Requires a cache with the audio cache enabled. Default is 0",
+            "TRACKS",
+        )
+        .optopt(
+            "",
+            "prefetch-bandwidth",
+            "Limit the bandwidth used for prefetching tracks in KB/s. 0 means no limit.",
+            "BANDWIDTH",
+        )
+        .optflag(
+            "",
+            "skip-explicit",
+            "Skip tracks and episodes with explicit content.",
         );
+    add_cache_options(&mut opts);
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -247,6 +423,7 @@ fn setup(args: &[String]) -> Setup {
             .map(|index| index.parse::<u32>().unwrap())
             .unwrap_or(0),
         mapped_volume: !matches.opt_present("mixer-linear-volume"),
+        watch_volume: matches.opt_present("mixer-watch"),
     };
 
     let use_audio_cache = !matches.opt_present("disable-audio-cache");
@@ -255,6 +432,26 @@ fn setup(args: &[String]) -> Setup {
         .opt_str("c")
         .map(|cache_location| Cache::new(PathBuf::from(cache_location), use_audio_cache));
 
+    if cache_action_requested(&matches) {
+        match cache {
+            Some(ref cache) => exit(run_cache_actions(&matches, cache)),
+            None => {
+                eprintln!("error: Cache actions require --cache");
+                exit(1);
+            }
+        }
+    }
+
+    if (matches.opt_present("persist-state") || matches.opt_present("resume")) && cache.is_none() {
+        eprintln!("error: --persist-state and --resume require --cache");
+        exit(1);
+    }
+
+    if matches.opt_present("prefetch-tracks") && !use_audio_cache {
+        eprintln!("error: --prefetch-tracks can't be used with --disable-audio-cache");
+        exit(1);
+    }
+
     let initial_volume = matches
         .opt_str("initial-volume")
         .map(|volume| {
@@ -272,7 +469,16 @@ fn setup(args: &[String]) -> Setup {
         .map(|port| port.parse::<u16>().unwrap())
         .unwrap_or(0);
 
-    let name = matches.opt_str("name").unwrap();
+    let name = match matches.opt_str("name") {
+        Some(name) => name,
+        None => {
+            eprintln!(
+                "error: Required option 'name' missing.\n{}",
+                usage(&args[0], &opts)
+            );
+            exit(1);
+        }
+    };
 
     let credentials = {
         let cached_credentials = cache.as_ref().and_then(Cache::credentials);
@@ -334,6 +540,24 @@ fn setup(args: &[String]) -> Setup {
                 .opt_str("normalisation-pregain")
                 .map(|pregain| pregain.parse::<f32>().expect("Invalid pregain float value"))
                 .unwrap_or(PlayerConfig::default().normalisation_pregain),
+            prefetch_tracks: matches
+                .opt_str("prefetch-tracks")
+                .map(|tracks| {
+                    tracks
+                        .parse::<usize>()
+                        .expect("Invalid number of prefetch tracks")
+                })
+                .unwrap_or(PlayerConfig::default().prefetch_tracks),
+            prefetch_bandwidth: matches
+                .opt_str("prefetch-bandwidth")
+                .map(|bandwidth| {
+                    bandwidth
+                        .parse::<usize>()
+                        .expect("Invalid prefetch bandwidth")
+                        * 1024
+                })
+                .filter(|bandwidth| *bandwidth > 0),
+            skip_explicit: matches.opt_present("skip-explicit"),
         }
     };
 
@@ -349,17 +573,138 @@ fn setup(args: &[String]) -> Setup {
             .as_ref()
             .map(|volume_ctrl| VolumeCtrl::from_str(volume_ctrl).expect("Invalid volume ctrl type"))
             .unwrap_or(VolumeCtrl::default());
+        let volume_ctrl = match matches.opt_str("volume-range") {
+            Some(range) => {
+                let range = range.parse::<f64>().expect("Invalid volume range");
+                if range <= 0.0 {
+                    panic!("Volume range must be positive");
+                }
+                volume_ctrl.with_db_range(range)
+            }
+            None => volume_ctrl,
+        };
+
+        let volume_steps = matches
+            .opt_str("volume-steps")
+            .map(|steps| {
+                let steps = steps
+                    .parse::<u16>()
+                    .expect("Invalid number of volume steps");
+                if steps == 0 {
+                    panic!("Number of volume steps must be at least 1");
+                }
+                steps
+            })
+            .unwrap_or(ConnectConfig::default().volume_steps);
+
+        let volume_step_size = matches
+            .opt_str("volume-step-size")
+            .map(|size| {
+                let size = size.parse::<u16>().expect("Invalid volume step size");
+                if size == 0 {
+                    panic!("Volume step size must be at least 1");
+                }
+                size
+            })
+            .unwrap_or(ConnectConfig::default().volume_step_size);
+
+        let takeover_policy = match matches.opt_str("takeover-policy").as_deref() {
+            None => TakeoverPolicy::default(),
+            Some("always") => TakeoverPolicy::Always,
+            Some("not-while-playing") => TakeoverPolicy::NotWhilePlaying,
+            Some("allow-list") => TakeoverPolicy::AllowList(matches.opt_strs("takeover-allow")),
+            Some(_) => panic!("Invalid takeover policy"),
+        };
+
+        let capabilities = ConnectCapabilities {
+            volume_control: !matches.opt_present("disable-volume-control"),
+            hidden: matches.opt_present("hidden"),
+            content: matches
+                .opt_str("content")
+                .as_ref()
+                .map(|content| ContentRestriction::from_str(content).expect("Invalid content"))
+                .unwrap_or(ContentRestriction::default()),
+            extra_types: matches.opt_strs("supported-type"),
+        };
 
         ConnectConfig {
             name: name,
             device_type: device_type,
             volume: initial_volume,
             volume_ctrl: volume_ctrl,
+            volume_steps,
+            volume_step_size,
             autoplay: matches.opt_present("autoplay"),
+            takeover_policy,
+            capabilities,
+            context_history: matches
+                .opt_str("context-history")
+                .map(|tracks| tracks.parse::<usize>().expect("Invalid context history"))
+                .unwrap_or(ConnectConfig::default().context_history),
+            context_fetch_threshold: matches
+                .opt_str("context-fetch-threshold")
+                .map(|tracks| {
+                    tracks
+                        .parse::<usize>()
+                        .expect("Invalid context fetch threshold")
+                })
+                .unwrap_or(ConnectConfig::default().context_fetch_threshold),
+            persist_state: matches.opt_present("persist-state") || matches.opt_present("resume"),
         }
     };
 
-    let enable_discovery = !matches.opt_present("disable-discovery");
+    let group_follow = matches.opt_str("group-follow");
+    if group_follow.is_some() && credentials.is_none() {
+        eprintln!(
+            "error: Following a group leader requires credentials.\n{}",
+            usage(&args[0], &opts)
+        );
+        exit(1);
+    }
+
+    let group_secret = matches.opt_str("group-secret");
+    if (group_follow.is_some() || matches.opt_present("group-leader")) && group_secret.is_none() {
+        eprintln!(
+            "error: Group playback requires --group-secret.\n{}",
+            usage(&args[0], &opts)
+        );
+        exit(1);
+    }
+
+    let scrobbler_config = matches.opt_str("scrobble").map(|service| {
+        let token = matches
+            .opt_str("scrobble-token")
+            .expect("Scrobbling requires --scrobble-token");
+        let service = match service.as_ref() {
+            "lastfm" => ScrobblerService::LastFm {
+                api_key: matches
+                    .opt_str("scrobble-api-key")
+                    .expect("Scrobbling to Last.fm requires --scrobble-api-key"),
+                api_secret: matches
+                    .opt_str("scrobble-api-secret")
+                    .expect("Scrobbling to Last.fm requires --scrobble-api-secret"),
+                session_key: token,
+            },
+            "listenbrainz" => ScrobblerService::ListenBrainz { token },
+            _ => panic!("Invalid scrobble service {}", service),
+        };
+        let url = matches.opt_str("scrobble-url").unwrap_or_else(|| {
+            panic!(
+                "Scrobbling requires --scrobble-url of a local proxy for {}",
+                service.endpoint()
+            )
+        });
+        match ScrobblerConfig::new(service, &url) {
+            Ok(config) => config,
+            Err(err) => {
+                eprintln!("error: {}", err);
+                exit(1);
+            }
+        }
+    });
+
+    // followers are no Connect devices
+    let enable_discovery = !matches.opt_present("disable-discovery") && group_follow.is_none();
 
     Setup {
         backend: backend,
@@ -375,6 +720,17 @@ fn setup(args: &[String]) -> Setup {
         mixer_config: mixer_config,
         player_event_program: matches.opt_str("onevent"),
         emit_sink_events: matches.opt_present("emit-sink-events"),
+        onevent_metadata: matches.opt_present("onevent-metadata"),
+        event_sink: matches.opt_str("event-sink"),
+        resume: matches.opt_present("resume"),
+        group_leader: matches.opt_str("group-leader"),
+        group_follow,
+        group_secret: group_secret.unwrap_or_default(),
+        group_latency: matches
+            .opt_str("group-latency")
+            .map(|latency| latency.parse::<i64>().expect("Invalid group latency"))
+            .unwrap_or(0),
+        scrobbler_config,
     }
 }
 
@@ -403,10 +759,24 @@ struct Main {
     player_event_channel: Option<UnboundedReceiver<PlayerEvent>>,
     player_event_program: Option<String>,
     emit_sink_events: bool,
+    onevent_metadata: bool,
+    event_sink: Option<EventSink>,
+    resume: bool,
+    session: Option<Session>,
+
+    group_leader: Option<GroupLeader>,
+    group_follow: Option<String>,
+    group_latency: i64,
+    group_secret: String,
+    group_follower: Option<GroupFollower>,
+
+    scrobbler_config: Option<ScrobblerConfig>,
+    scrobbler: Option<Scrobbler>,
 }
 
 impl Main {
     fn new(handle: Handle, setup: Setup) -> Main {
+        let group_secret = setup.group_secret;
         let mut task = Main {
             handle: handle.clone(),
             cache: setup.cache,
@@ -430,6 +800,22 @@ impl Main {
             player_event_channel: None,
             player_event_program: setup.player_event_program,
             emit_sink_events: setup.emit_sink_events,
+            onevent_metadata: setup.onevent_metadata,
+            event_sink: setup.event_sink.map(EventSink::open),
+            resume: setup.resume,
+            session: None,
+
+            group_leader: setup.group_leader.map(|addr| {
+                GroupLeader::bind(&addr as &str, group_secret.clone())
+                    .expect("Unable to listen for group followers")
+            }),
+            group_follow: setup.group_follow,
+            group_latency: setup.group_latency,
+            group_secret,
+            group_follower: None,
+
+            scrobbler_config: setup.scrobbler_config,
+            scrobbler: None,
         };
 
         if setup.enable_discovery {
@@ -456,6 +842,7 @@ impl Main {
 
         self.connect = connection;
         self.spirc = None;
+        self.session_disconnected();
         let task = mem::replace(&mut self.spirc_task, None);
         if let Some(task) = task {
             self.handle.spawn(task);
@@ -463,6 +850,45 @@ impl Main {
     }
 }
 
+impl Main {
+    // Session events are only passed to the program with --onevent-metadata, like the
+    // other events added with it.
+    fn session_connected(&mut self, session: &Session) {
+        match self.player_event_program {
+            Some(ref program) if self.onevent_metadata => {
+                self.handle.spawn(run_program_on_session_event(
+                    true,
+                    session.username(),
+                    program,
+                ));
+            }
+            _ => (),
+        }
+        if let Some(ref event_sink) = self.event_sink {
+            event_sink.session_event(true, session.username());
+        }
+        self.session = Some(session.clone());
+    }
+
+    fn session_disconnected(&mut self) {
+        if let Some(session) = self.session.take() {
+            match self.player_event_program {
+                Some(ref program) if self.onevent_metadata => {
+                    self.handle.spawn(run_program_on_session_event(
+                        false,
+                        session.username(),
+                        program,
+                    ));
+                }
+                _ => (),
+            }
+            if let Some(ref event_sink) = self.event_sink {
+                event_sink.session_event(false, session.username());
+            }
+        }
+    }
+}
+
 impl Future for Main {
     type Item = ();
     type Error = ();
@@ -486,6 +912,7 @@ impl Future for Main {
             match self.connect.poll() {
                 Ok(Async::Ready(session)) => {
                     self.connect = Box::new(futures::future::empty());
+                    self.session_connected(&session);
                     let mixer_config = self.mixer_config.clone();
                     let mixer = (self.mixer)(Some(mixer_config));
                     let player_config = self.player_config.clone();
@@ -499,6 +926,11 @@ impl Future for Main {
                             (backend)(device)
                         });
 
+                    self.scrobbler = self
+                        .scrobbler_config
+                        .clone()
+                        .map(|config| Scrobbler::spawn(config, session.clone()));
+
                     if self.emit_sink_events {
                         if let Some(player_event_program) = &self.player_event_program {
                             let player_event_program = player_event_program.clone();
@@ -508,9 +940,37 @@ impl Future for Main {
                         }
                     }
 
-                    let (spirc, spirc_task) = Spirc::new(connect_config, session, player, mixer);
-                    self.spirc = Some(spirc);
-                    self.spirc_task = Some(spirc_task);
+                    if let Some(ref addr) = self.group_follow {
+                        self.group_follower = match GroupFollower::connect(
+                            addr as &str,
+                            &self.group_secret,
+                            player,
+                            self.group_latency,
+                        ) {
+                            Ok(follower) => Some(follower),
+                            Err(err) => {
+                                error!("Unable to connect to group leader {}: {}", addr, err);
+                                None
+                            }
+                        };
+                    } else {
+                        if let Some(ref leader) = self.group_leader {
+                            leader.follow(
+                                player.get_player_event_channel(),
+                                player.get_shared_position(),
+                            );
+                        }
+
+                        let (spirc, spirc_task) =
+                            Spirc::new(connect_config, session, player, mixer);
+                        if self.resume {
+                            // only resume after startup, not after reconnecting
+                            spirc.play();
+                            self.resume = false;
+                        }
+                        self.spirc = Some(spirc);
+                        self.spirc_task = Some(spirc_task);
+                    }
                     self.player_event_channel = Some(event_channel);
 
                     progress = true;
@@ -552,6 +1012,7 @@ impl Future for Main {
             }
             if drop_spirc_and_try_to_reconnect {
                 self.spirc_task = None;
+                self.session_disconnected();
                 while (!self.auto_connect_times.is_empty())
                     && ((Instant::now() - self.auto_connect_times[0]).as_secs() > 600)
                 {
@@ -571,17 +1032,20 @@ impl Future for Main {
             if let Some(ref mut player_event_channel) = self.player_event_channel {
                 if let Async::Ready(Some(event)) = player_event_channel.poll().unwrap() {
                     progress = true;
+                    if let Some(ref event_sink) = self.event_sink {
+                        event_sink.player_event(&event);
+                    }
+                    if let Some(ref scrobbler) = self.scrobbler {
+                        scrobbler.handle_event(event.clone());
+                    }
                     if let Some(ref program) = self.player_event_program {
-                        if let Some(child) = run_program_on_events(event, program) {
-                            let child = child
-                                .expect("program failed to start")
-                                .map(|status| {
-                                    if !status.success() {
-                                        error!("child exited with status {:?}", status.code());
-                                    }
-                                })
-                                .map_err(|e| error!("failed to wait on child process: {}", e));
-
+                        let child = match self.session {
+                            Some(ref session) if self.onevent_metadata => {
+                                run_program_on_events_with_metadata(event, program, session)
+                            }
+                            _ => run_program_on_events(event, program),
+                        };
+                        if let Some(child) = child {
                             self.handle.spawn(child);
                         }
                     }
@@ -1,14 +1,20 @@
 use librespot::playback::player::PlayerEvent;
-use log::info;
+use log::{error, info, warn};
 use std::collections::HashMap;
 use std::io;
 use std::process::Command;
 use tokio_process::{Child, CommandExt};
 
-use futures::Future;
+use futures::future::{self, Future};
+use librespot::core::mercury::MercuryError;
+use librespot::core::session::Session;
+use librespot::core::spotify_id::{FileId, SpotifyAudioType, SpotifyId};
+use librespot::metadata::{Album, Artist, Episode, Metadata, Show, Track};
 use librespot::playback::player::SinkStatus;
 
-fn run_program(program: &str, env_vars: HashMap<&str, String>) -> io::Result<Child> {
+type EnvVars = HashMap<&'static str, String>;
+
+fn run_program(program: &str, env_vars: EnvVars) -> io::Result<Child> {
     let mut v: Vec<&str> = program.split_whitespace().collect();
     info!("Running {:?} with environment variables {:?}", v, env_vars);
     Command::new(&v.remove(0))
@@ -17,7 +23,166 @@ fn run_program(program: &str, env_vars: HashMap<&str, String>) -> io::Result<Chi
         .spawn_async()
 }
 
-pub fn run_program_on_events(event: PlayerEvent, onevent: &str) -> Option<io::Result<Child>> {
+// Waits for the program to exit, to be spawned on the reactor
+fn wait_for_program(child: io::Result<Child>) -> Box<dyn Future<Item = (), Error = ()>> {
+    Box::new(
+        child
+            .expect("program failed to start")
+            .map(|status| {
+                if !status.success() {
+                    error!("child exited with status {:?}", status.code());
+                }
+            })
+            .map_err(|e| error!("failed to wait on child process: {}", e)),
+    )
+}
+
+pub fn run_program_on_events(
+    event: PlayerEvent,
+    onevent: &str,
+) -> Option<Box<dyn Future<Item = (), Error = ()>>> {
+    match event {
+        // Only passed on together with metadata, as they happen a lot more often than the others
+        PlayerEvent::Loading { .. }
+        | PlayerEvent::TimeToPreloadNextTrack { .. }
+        | PlayerEvent::EndOfTrack { .. }
+        | PlayerEvent::Unavailable { .. } => None,
+        // The program is run for these by emit_sink_event instead, before the sink is started
+        PlayerEvent::SinkStatusChanged { .. } => None,
+        _ => Some(wait_for_program(run_program(
+            onevent,
+            event_env_vars(event),
+        ))),
+    }
+}
+
+// Same as run_program_on_events, but also passes the metadata of the track or episode of the
+// event, e.g. NAME, ARTISTS, ALBUM and COVER_URL, and runs the program for every event.
+pub fn run_program_on_events_with_metadata(
+    event: PlayerEvent,
+    onevent: &str,
+    session: &Session,
+) -> Option<Box<dyn Future<Item = (), Error = ()>>> {
+    // The program is run for these by emit_sink_event instead, before the sink is started
+    if let PlayerEvent::SinkStatusChanged { .. } = event {
+        return None;
+    }
+    let onevent = onevent.to_owned();
+    let track_id = event_track_id(&event);
+    let env_vars = event_env_vars(event);
+    Some(match track_id {
+        Some(track_id) => Box::new(metadata_env_vars(session, track_id).then(move |metadata| {
+            let mut metadata = metadata.unwrap_or_else(|_| {
+                warn!("Unable to get metadata of <{}>", track_id.to_uri());
+                HashMap::new()
+            });
+            metadata.extend(env_vars);
+            wait_for_program(run_program(&onevent, metadata))
+        })),
+        None => wait_for_program(run_program(&onevent, env_vars)),
+    })
+}
+
+pub fn run_program_on_session_event(
+    connected: bool,
+    user_name: String,
+    onevent: &str,
+) -> Box<dyn Future<Item = (), Error = ()>> {
+    let mut env_vars = HashMap::new();
+    if connected {
+        env_vars.insert("PLAYER_EVENT", "session_connected".to_string());
+    } else {
+        env_vars.insert("PLAYER_EVENT", "session_disconnected".to_string());
+    }
+    env_vars.insert("USER_NAME", user_name);
+    wait_for_program(run_program(onevent, env_vars))
+}
+
+fn event_track_id(event: &PlayerEvent) -> Option<SpotifyId> {
+    match *event {
+        PlayerEvent::Changed { new_track_id, .. } => Some(new_track_id),
+        PlayerEvent::Started { track_id, .. }
+        | PlayerEvent::Stopped { track_id, .. }
+        | PlayerEvent::Loading { track_id, .. }
+        | PlayerEvent::Playing { track_id, .. }
+        | PlayerEvent::Paused { track_id, .. }
+        | PlayerEvent::Underrun { track_id, .. }
+        | PlayerEvent::TimeToPreloadNextTrack { track_id, .. }
+        | PlayerEvent::EndOfTrack { track_id, .. }
+        | PlayerEvent::Unavailable { track_id, .. }
+        | PlayerEvent::ExplicitSkipped { track_id, .. } => Some(track_id),
+        PlayerEvent::VolumeSet { .. }
+        | PlayerEvent::Takeover { .. }
+        | PlayerEvent::AutoplayStarted { .. }
+        | PlayerEvent::SinkStatusChanged { .. } => None,
+    }
+}
+
+// Artist names may contain commas and other punctuation, but no line breaks.
+fn join_artists(artists: &[String]) -> String {
+    artists.join("\n")
+}
+
+fn cover_url(file_id: &FileId) -> String {
+    format!("https://i.scdn.co/image/{}", file_id.to_base16())
+}
+
+fn metadata_env_vars(
+    session: &Session,
+    id: SpotifyId,
+) -> Box<dyn Future<Item = EnvVars, Error = MercuryError>> {
+    let session = session.clone();
+    match id.audio_type {
+        SpotifyAudioType::Track => Box::new(Track::get(&session, id).and_then(move |track| {
+            let artists = track
+                .artists
+                .iter()
+                .map(|artist| Artist::get(&session, *artist))
+                .collect::<Vec<_>>();
+            let album = Album::get(&session, track.album);
+            future::join_all(artists)
+                .join(album)
+                .map(move |(artists, album)| {
+                    let artists: Vec<String> =
+                        artists.into_iter().map(|artist| artist.name).collect();
+                    let mut env_vars = HashMap::new();
+                    env_vars.insert("ITEM_TYPE", "track".to_string());
+                    env_vars.insert("URI", id.to_uri());
+                    env_vars.insert("NAME", track.name);
+                    env_vars.insert("ARTISTS", join_artists(&artists));
+                    env_vars.insert("ALBUM", album.name);
+                    env_vars.insert("DURATION_MS", track.duration.to_string());
+                    env_vars.insert("IS_EXPLICIT", track.explicit.to_string());
+                    if let Some(cover) = album.covers.first() {
+                        env_vars.insert("COVER_URL", cover_url(cover));
+                    }
+                    env_vars
+                })
+        })),
+        SpotifyAudioType::Podcast => {
+            Box::new(Episode::get(&session, id).and_then(move |episode| {
+                Show::get(&session, episode.show).map(move |show| {
+                    let mut env_vars = HashMap::new();
+                    if let Some(cover) = episode.covers.first().or_else(|| show.covers.first()) {
+                        env_vars.insert("COVER_URL", cover_url(cover));
+                    }
+                    env_vars.insert("ITEM_TYPE", "episode".to_string());
+                    env_vars.insert("URI", id.to_uri());
+                    env_vars.insert("NAME", episode.name);
+                    env_vars.insert("SHOW", show.name);
+                    env_vars.insert("PUBLISHER", show.publisher);
+                    env_vars.insert("LANGUAGE", episode.language);
+                    env_vars.insert("DURATION_MS", episode.duration.to_string());
+                    env_vars.insert("IS_EXPLICIT", episode.explicit.to_string());
+                    env_vars
+                })
+            }))
+        }
+        SpotifyAudioType::NonPlayable => Box::new(future::ok(HashMap::new())),
+    }
+}
+
+fn event_env_vars(event: PlayerEvent) -> EnvVars {
     let mut env_vars = HashMap::new();
     match event {
         PlayerEvent::Changed {
@@ -58,16 +223,75 @@ pub fn run_program_on_events(event: PlayerEvent, onevent: &str) -> Option<io::Re
             env_vars.insert("DURATION_MS", duration_ms.to_string());
             env_vars.insert("POSITION_MS", position_ms.to_string());
         }
+        PlayerEvent::Loading {
+            track_id,
+            position_ms,
+            ..
+        } => {
+            env_vars.insert("PLAYER_EVENT", "loading".to_string());
+            env_vars.insert("TRACK_ID", track_id.to_base62());
+            env_vars.insert("POSITION_MS", position_ms.to_string());
+        }
+        PlayerEvent::TimeToPreloadNextTrack { track_id, .. } => {
+            env_vars.insert("PLAYER_EVENT", "preload".to_string());
+            env_vars.insert("TRACK_ID", track_id.to_base62());
+        }
+        PlayerEvent::EndOfTrack { track_id, .. } => {
+            env_vars.insert("PLAYER_EVENT", "end_of_track".to_string());
+            env_vars.insert("TRACK_ID", track_id.to_base62());
+        }
+        PlayerEvent::Unavailable { track_id, .. } => {
+            env_vars.insert("PLAYER_EVENT", "unavailable".to_string());
+            env_vars.insert("TRACK_ID", track_id.to_base62());
+        }
+        PlayerEvent::Underrun {
+            track_id,
+            position_ms,
+            underrun_count,
+            ..
+        } => {
+            env_vars.insert("PLAYER_EVENT", "underrun".to_string());
+            env_vars.insert("TRACK_ID", track_id.to_base62());
+            env_vars.insert("POSITION_MS", position_ms.to_string());
+            env_vars.insert("UNDERRUN_COUNT", underrun_count.to_string());
+        }
+        PlayerEvent::ExplicitSkipped { track_id, .. } => {
+            env_vars.insert("PLAYER_EVENT", "explicit_skipped".to_string());
+            env_vars.insert("TRACK_ID", track_id.to_base62());
+        }
         PlayerEvent::VolumeSet { volume } => {
             env_vars.insert("PLAYER_EVENT", "volume_set".to_string());
             env_vars.insert("VOLUME", volume.to_string());
         }
-        _ => return None,
+        PlayerEvent::Takeover {
+            device_ident,
+            device_name,
+            accepted,
+        } => {
+            if accepted {
+                env_vars.insert("PLAYER_EVENT", "takeover".to_string());
+            } else {
+                env_vars.insert("PLAYER_EVENT", "takeover_refused".to_string());
+            }
+            env_vars.insert("DEVICE_ID", device_ident);
+            env_vars.insert("DEVICE_NAME", device_name);
+        }
+        PlayerEvent::AutoplayStarted {
+            context_uri,
+            station_uri,
+        } => {
+            env_vars.insert("PLAYER_EVENT", "autoplay_started".to_string());
+            env_vars.insert("CONTEXT_URI", context_uri);
+            env_vars.insert("STATION_URI", station_uri);
+        }
+        PlayerEvent::SinkStatusChanged { sink_status } => {
+            env_vars = sink_env_vars(sink_status);
+        }
     }
-    Some(run_program(onevent, env_vars))
+    env_vars
 }
 
-pub fn emit_sink_event(sink_status: SinkStatus, onevent: &str) {
+fn sink_env_vars(sink_status: SinkStatus) -> EnvVars {
     let mut env_vars = HashMap::new();
     env_vars.insert("PLAYER_EVENT", "sink".to_string());
     let sink_status = match sink_status {
@@ -76,6 +300,12 @@ pub fn emit_sink_event(sink_status: SinkStatus, onevent: &str) {
         SinkStatus::Closed => "closed",
     };
     env_vars.insert("SINK_STATUS", sink_status.to_string());
+    env_vars
+}
 
-    let _ = run_program(onevent, env_vars).and_then(|child| child.wait());
+pub fn emit_sink_event(sink_status: SinkStatus, onevent: &str) {
+    let _ = run_program(onevent, sink_env_vars(sink_status)).and_then(|child| child.wait());
 }
+
+#[cfg(test)]
+mod tests;
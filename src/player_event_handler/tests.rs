@@ -0,0 +1,79 @@
+use super::*;
+
+fn track_id(n: u8) -> SpotifyId {
+    SpotifyId::from_raw(&[n; 16]).unwrap()
+}
+
+#[test]
+fn playing_event_passes_track_and_position() {
+    let env_vars = event_env_vars(PlayerEvent::Playing {
+        play_request_id: 1,
+        track_id: track_id(1),
+        position_ms: 1_000,
+        duration_ms: 180_000,
+    });
+
+    assert_eq!(env_vars["PLAYER_EVENT"], "playing");
+    assert_eq!(env_vars["TRACK_ID"], track_id(1).to_base62());
+    assert_eq!(env_vars["POSITION_MS"], "1000");
+    assert_eq!(env_vars["DURATION_MS"], "180000");
+    assert_eq!(env_vars.len(), 4);
+}
+
+#[test]
+fn changed_event_passes_both_tracks() {
+    let env_vars = event_env_vars(PlayerEvent::Changed {
+        old_track_id: track_id(1),
+        new_track_id: track_id(2),
+    });
+
+    assert_eq!(env_vars["PLAYER_EVENT"], "change");
+    assert_eq!(env_vars["OLD_TRACK_ID"], track_id(1).to_base62());
+    assert_eq!(env_vars["TRACK_ID"], track_id(2).to_base62());
+}
+
+#[test]
+fn refused_takeover_is_its_own_event() {
+    let env_vars = event_env_vars(PlayerEvent::Takeover {
+        device_ident: String::from("ident"),
+        device_name: String::from("Kitchen"),
+        accepted: false,
+    });
+
+    assert_eq!(env_vars["PLAYER_EVENT"], "takeover_refused");
+    assert_eq!(env_vars["DEVICE_ID"], "ident");
+    assert_eq!(env_vars["DEVICE_NAME"], "Kitchen");
+}
+
+#[test]
+fn detailed_events_are_only_passed_with_metadata() {
+    let end_of_track = PlayerEvent::EndOfTrack {
+        play_request_id: 1,
+        track_id: track_id(1),
+    };
+    let stopped = PlayerEvent::Stopped {
+        play_request_id: 1,
+        track_id: track_id(1),
+    };
+
+    assert!(run_program_on_events(end_of_track, "true").is_none());
+    assert_eq!(event_env_vars(stopped)["PLAYER_EVENT"], "stop");
+}
+
+#[test]
+fn artists_are_passed_one_per_line() {
+    let artists = vec![String::from("Crosby, Stills & Nash"), String::from("Young")];
+
+    assert_eq!(join_artists(&artists), "Crosby, Stills & Nash\nYoung");
+    assert_eq!(join_artists(&artists[..1]), "Crosby, Stills & Nash");
+}
+
+#[test]
+fn sink_events_are_left_to_the_sink_callback() {
+    let sink = PlayerEvent::SinkStatusChanged {
+        sink_status: SinkStatus::Running,
+    };
+
+    assert_eq!(event_env_vars(sink.clone())["SINK_STATUS"], "running");
+    assert!(run_program_on_events(sink, "true").is_none());
+}
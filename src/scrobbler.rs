@@ -0,0 +1,478 @@
+// Submits played tracks to Last.fm or ListenBrainz. A track is scrobbled when it is longer than
+// 30 seconds and was played for half its duration or for 4 minutes, whichever comes first.
+// There is no TLS support, so scrobbles are only sent to a local address, e.g. a proxy which
+// forwards them to the HTTPS endpoint of the service.
+use futures::{Future, Stream};
+use hyper::header::{Authorization, ContentLength, ContentType};
+use hyper::{Client, Method, Request, Uri};
+use log::{debug, info, warn};
+use md5::{Digest, Md5};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio_core::reactor::{Core, Timeout};
+use url::form_urlencoded;
+
+use librespot::core::cache::Cache;
+use librespot::core::session::Session;
+use librespot::core::spotify_id::{SpotifyAudioType, SpotifyId};
+use librespot::metadata::{Album, Artist, Metadata, Track};
+use librespot::playback::player::PlayerEvent;
+
+const MINIMUM_DURATION_MS: u32 = 30_000;
+const MAXIMUM_REQUIRED_PLAY_MS: u64 = 240_000;
+// Scrobbles which failed are retried at this interval
+const RETRY_INTERVAL: Duration = Duration::from_secs(300);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// Scrobbles which could not be submitted are queued in the cache, to retry them later
+const PENDING_SCROBBLES_KEY: &str = "scrobbles.json";
+
+#[derive(Clone, Debug)]
+pub enum ScrobblerService {
+    LastFm {
+        api_key: String,
+        api_secret: String,
+        session_key: String,
+    },
+    ListenBrainz {
+        token: String,
+    },
+}
+
+impl ScrobblerService {
+    // API endpoint of the service, which the proxy at the scrobble url should forward to
+    pub fn endpoint(&self) -> &'static str {
+        match *self {
+            ScrobblerService::LastFm { .. } => "https://ws.audioscrobbler.com/2.0/",
+            ScrobblerService::ListenBrainz { .. } => {
+                "https://api.listenbrainz.org/1/submit-listens"
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ScrobblerConfig {
+    pub service: ScrobblerService,
+    url: Uri,
+}
+
+impl ScrobblerConfig {
+    // The tokens are sent in plain HTTP, so the url must be a loopback address.
+    pub fn new(service: ScrobblerService, url: &str) -> Result<ScrobblerConfig, String> {
+        let url = url
+            .parse::<Uri>()
+            .map_err(|err| format!("Invalid scrobble url {}: {}", url, err))?;
+        if url.scheme() != Some("http") {
+            return Err(format!(
+                "Unable to scrobble to {}, only plain HTTP is supported",
+                url
+            ));
+        }
+        let host = url.host().unwrap_or("");
+        let loopback = host == "localhost"
+            || host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<IpAddr>()
+                .map(|addr| addr.is_loopback())
+                .unwrap_or(false);
+        if !loopback {
+            return Err(format!(
+                "Refusing to send the scrobble token in plain HTTP to {}, use a local proxy",
+                url
+            ));
+        }
+        Ok(ScrobblerConfig { service, url })
+    }
+}
+
+// A play which counts as a listen for Last.fm or ListenBrainz
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct Scrobble {
+    artist: String,
+    track: String,
+    album: String,
+    duration_secs: u32,
+    // unix time at which the track started playing
+    timestamp: u64,
+    track_uri: String,
+}
+
+fn pending_scrobbles(cache: &Cache) -> Vec<Scrobble> {
+    let contents = match cache.data(PENDING_SCROBBLES_KEY) {
+        Some(contents) => contents,
+        None => return Vec::new(),
+    };
+    match serde_json::from_slice(&contents) {
+        Ok(scrobbles) => scrobbles,
+        Err(err) => {
+            warn!("Unable to parse cached scrobbles: {}", err);
+            Vec::new()
+        }
+    }
+}
+
+fn save_pending_scrobbles(cache: &Cache, scrobbles: &[Scrobble]) {
+    match serde_json::to_vec(scrobbles) {
+        Ok(contents) => cache.save_data(PENDING_SCROBBLES_KEY, &contents),
+        Err(err) => warn!("Unable to serialize scrobbles: {}", err),
+    }
+}
+
+// Handle to the scrobbler thread, which stops when this is dropped.
+pub struct Scrobbler {
+    events: Sender<PlayerEvent>,
+}
+
+impl Scrobbler {
+    pub fn spawn(config: ScrobblerConfig, session: Session) -> Scrobbler {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let core = match Core::new() {
+                Ok(core) => core,
+                Err(err) => {
+                    warn!("Unable to start scrobbler: {}", err);
+                    return;
+                }
+            };
+            let internal = ScrobblerInternal {
+                cache: session.cache().cloned(),
+                session,
+                client: ScrobbleClient { config, core },
+                current: None,
+            };
+            internal.run(rx);
+        });
+        Scrobbler { events: tx }
+    }
+
+    pub fn handle_event(&self, event: PlayerEvent) {
+        let _ = self.events.send(event);
+    }
+}
+
+struct Listen {
+    track_id: SpotifyId,
+    duration_ms: u32,
+    timestamp: u64,
+    played_ms: u64,
+    playing_since: Option<Instant>,
+}
+
+impl Listen {
+    fn pause(&mut self) {
+        if let Some(playing_since) = self.playing_since.take() {
+            let played = Instant::now() - playing_since;
+            self.played_ms += played.as_secs() * 1000 + played.subsec_millis() as u64;
+        }
+    }
+
+    fn is_scrobble(&self) -> bool {
+        let required_ms = MAXIMUM_REQUIRED_PLAY_MS.min(self.duration_ms as u64 / 2);
+        self.duration_ms > MINIMUM_DURATION_MS && self.played_ms >= required_ms
+    }
+}
+
+struct ScrobblerInternal {
+    session: Session,
+    cache: Option<Arc<Cache>>,
+    client: ScrobbleClient,
+    current: Option<Listen>,
+}
+
+struct ScrobbleClient {
+    config: ScrobblerConfig,
+    core: Core,
+}
+
+impl ScrobblerInternal {
+    fn run(mut self, events: Receiver<PlayerEvent>) {
+        self.submit_pending();
+        loop {
+            match events.recv_timeout(RETRY_INTERVAL) {
+                Ok(event) => self.handle_event(event),
+                Err(RecvTimeoutError::Timeout) => self.submit_pending(),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        self.finish_listen();
+        debug!("Scrobbler finished.");
+    }
+
+    fn handle_event(&mut self, event: PlayerEvent) {
+        match event {
+            PlayerEvent::Playing {
+                track_id,
+                duration_ms,
+                ..
+            } => {
+                if self.current.as_ref().map(|listen| listen.track_id) != Some(track_id) {
+                    self.finish_listen();
+                    self.start_listen(track_id, duration_ms);
+                }
+                if let Some(ref mut listen) = self.current {
+                    if listen.playing_since.is_none() {
+                        listen.playing_since = Some(Instant::now());
+                    }
+                }
+            }
+            PlayerEvent::Paused { track_id, .. } => {
+                if let Some(ref mut listen) = self.current {
+                    if listen.track_id == track_id {
+                        listen.pause();
+                    }
+                }
+            }
+            PlayerEvent::Changed {
+                old_track_id: track_id,
+                ..
+            }
+            | PlayerEvent::EndOfTrack { track_id, .. }
+            | PlayerEvent::Stopped { track_id, .. }
+                if self.current.as_ref().map(|listen| listen.track_id) == Some(track_id) =>
+            {
+                self.finish_listen();
+            }
+            _ => (),
+        }
+    }
+
+    fn start_listen(&mut self, track_id: SpotifyId, duration_ms: u32) {
+        if track_id.audio_type != SpotifyAudioType::Track {
+            return;
+        }
+        let listen = Listen {
+            track_id,
+            duration_ms,
+            timestamp: unix_time(),
+            played_ms: 0,
+            playing_since: None,
+        };
+
+        if let Some(scrobble) = self.scrobble_for(&listen) {
+            if let Err(err) = self.client.submit(&scrobble, true) {
+                warn!(
+                    "Unable to send now playing <{}>: {}",
+                    scrobble.track_uri, err
+                );
+            }
+        }
+        self.current = Some(listen);
+    }
+
+    fn finish_listen(&mut self) {
+        let mut listen = match self.current.take() {
+            Some(listen) => listen,
+            None => return,
+        };
+        listen.pause();
+        if !listen.is_scrobble() {
+            debug!(
+                "Not scrobbling <{}>, played {} of {} ms",
+                listen.track_id.to_uri(),
+                listen.played_ms,
+                listen.duration_ms
+            );
+            return;
+        }
+
+        if let Some(scrobble) = self.scrobble_for(&listen) {
+            match self.client.submit(&scrobble, false) {
+                Ok(()) => {
+                    info!("Scrobbled <{}>", scrobble.track_uri);
+                    self.submit_pending();
+                }
+                Err(err) => {
+                    warn!("Unable to scrobble <{}>: {}", scrobble.track_uri, err);
+                    if let Some(ref cache) = self.cache {
+                        let mut pending = pending_scrobbles(cache);
+                        pending.push(scrobble);
+                        save_pending_scrobbles(cache, &pending);
+                    }
+                }
+            }
+        }
+    }
+
+    fn submit_pending(&mut self) {
+        if let Some(ref cache) = self.cache {
+            self.client.submit_pending(cache);
+        }
+    }
+
+    fn scrobble_for(&self, listen: &Listen) -> Option<Scrobble> {
+        let track = match Track::get(&self.session, listen.track_id).wait() {
+            Ok(track) => track,
+            Err(_) => {
+                warn!("Unable to get metadata of <{}>", listen.track_id.to_uri());
+                return None;
+            }
+        };
+        let album = Album::get(&self.session, track.album).wait().ok()?;
+        let artist = match track.artists.first() {
+            Some(artist) => Artist::get(&self.session, *artist).wait().ok()?,
+            None => return None,
+        };
+
+        Some(Scrobble {
+            artist: artist.name,
+            track: track.name,
+            album: album.name,
+            duration_secs: listen.duration_ms / 1000,
+            timestamp: listen.timestamp,
+            track_uri: listen.track_id.to_uri(),
+        })
+    }
+}
+
+impl ScrobbleClient {
+    // Retries the scrobbles queued in the cache, in the order they were played
+    fn submit_pending(&mut self, cache: &Cache) {
+        let mut pending = pending_scrobbles(cache);
+        if pending.is_empty() {
+            return;
+        }
+
+        let mut submitted = 0;
+        for scrobble in pending.iter() {
+            if let Err(err) = self.submit(scrobble, false) {
+                warn!("Unable to submit queued scrobbles: {}", err);
+                break;
+            }
+            submitted += 1;
+        }
+        info!(
+            "Submitted {} of {} queued scrobbles",
+            submitted,
+            pending.len()
+        );
+        pending.drain(0..submitted);
+        save_pending_scrobbles(cache, &pending);
+    }
+
+    fn submit(&mut self, scrobble: &Scrobble, now_playing: bool) -> Result<(), String> {
+        let mut request = Request::new(Method::Post, self.config.url.clone());
+
+        let body = match self.config.service {
+            ScrobblerService::LastFm {
+                ref api_key,
+                ref api_secret,
+                ref session_key,
+            } => {
+                let method = if now_playing {
+                    "track.updateNowPlaying"
+                } else {
+                    "track.scrobble"
+                };
+                let mut params = BTreeMap::new();
+                params.insert("method", method.to_owned());
+                params.insert("api_key", api_key.clone());
+                params.insert("sk", session_key.clone());
+                params.insert("artist", scrobble.artist.clone());
+                params.insert("track", scrobble.track.clone());
+                params.insert("album", scrobble.album.clone());
+                params.insert("duration", scrobble.duration_secs.to_string());
+                if !now_playing {
+                    params.insert("timestamp", scrobble.timestamp.to_string());
+                }
+                params.insert("api_sig", lastfm_signature(&params, api_secret));
+                params.insert("format", String::from("json"));
+
+                request.headers_mut().set(ContentType::form_url_encoded());
+                form_urlencoded::Serializer::new(String::new())
+                    .extend_pairs(params.iter())
+                    .finish()
+            }
+            ScrobblerService::ListenBrainz { ref token } => {
+                let mut listen = json!({
+                    "track_metadata": {
+                        "artist_name": scrobble.artist,
+                        "track_name": scrobble.track,
+                        "release_name": scrobble.album,
+                        "additional_info": {
+                            "duration_ms": scrobble.duration_secs * 1000,
+                            "spotify_id": spotify_url(&scrobble.track_uri),
+                            "submission_client": "librespot",
+                        },
+                    },
+                });
+                if !now_playing {
+                    listen["listened_at"] = json!(scrobble.timestamp);
+                }
+                request.headers_mut().set(ContentType::json());
+                request
+                    .headers_mut()
+                    .set(Authorization(format!("Token {}", token)));
+                json!({
+                    "listen_type": if now_playing { "playing_now" } else { "single" },
+                    "payload": [listen],
+                })
+                .to_string()
+            }
+        };
+        // rather than a chunked body, which not every proxy accepts
+        request.headers_mut().set(ContentLength(body.len() as u64));
+        request.set_body(body);
+
+        let client = Client::new(&self.core.handle());
+        let response = client
+            .request(request)
+            .and_then(|response| {
+                let status = response.status();
+                response.body().concat2().map(move |body| (status, body))
+            })
+            .map_err(|err| err.to_string());
+        let timeout = Timeout::new(REQUEST_TIMEOUT, &self.core.handle())
+            .map_err(|err| err.to_string())?
+            .then(|_| Err(String::from("request timed out")));
+        let (status, body) = self
+            .core
+            .run(response.select(timeout))
+            .map(|(response, _)| response)
+            .map_err(|(err, _)| err)?;
+        if !status.is_success() {
+            return Err(format!("{} {}", status, String::from_utf8_lossy(&body)));
+        }
+        // Last.fm reports some errors with a successful status
+        if let Ok(json) = serde_json::from_slice::<serde_json::Value>(&body) {
+            if let Some(error) = json.get("error") {
+                return Err(format!("{} {}", error, json["message"]));
+            }
+        }
+        Ok(())
+    }
+}
+
+// md5 of the parameters sorted by name and the shared secret
+fn lastfm_signature(params: &BTreeMap<&str, String>, api_secret: &str) -> String {
+    let mut data = String::new();
+    for (name, value) in params.iter() {
+        data.push_str(name);
+        data.push_str(value);
+    }
+    data.push_str(api_secret);
+    hex::encode(Md5::digest(data.as_bytes()))
+}
+
+fn spotify_url(uri: &str) -> String {
+    format!(
+        "https://open.spotify.com/track/{}",
+        uri.trim_start_matches("spotify:track:")
+    )
+}
+
+fn unix_time() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(dur) => dur.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,174 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+
+use super::*;
+use librespot::core::cache_storage::MemoryStorage;
+
+// Stand-in for the scrobble service, answering each request with the next of the given
+// responses. Returns its url and the bodies of the requests it received.
+fn serve(responses: Vec<&'static str>) -> (String, Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/submit", listener.local_addr().unwrap());
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for response in responses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end().to_lowercase();
+                if line.is_empty() {
+                    break;
+                }
+                let mut header = line.splitn(2, ':');
+                if let (Some("content-length"), Some(value)) = (header.next(), header.next()) {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            tx.send(String::from_utf8(body).unwrap()).unwrap();
+
+            let (status, body) = match response.find(' ') {
+                Some(index) => (&response[..index], &response[index + 1..]),
+                None => (response, ""),
+            };
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+            .unwrap();
+        }
+    });
+    (url, rx)
+}
+
+fn client(service: ScrobblerService, url: &str) -> ScrobbleClient {
+    ScrobbleClient {
+        config: ScrobblerConfig::new(service, url).unwrap(),
+        core: Core::new().unwrap(),
+    }
+}
+
+fn listenbrainz() -> ScrobblerService {
+    ScrobblerService::ListenBrainz {
+        token: String::from("token"),
+    }
+}
+
+fn scrobble(n: u64) -> Scrobble {
+    Scrobble {
+        artist: String::from("Artist"),
+        track: format!("Track {}", n),
+        album: String::from("Album"),
+        duration_secs: 180,
+        timestamp: 1_600_000_000 + n,
+        track_uri: format!("spotify:track:{}", n),
+    }
+}
+
+#[test]
+fn url_must_be_plain_http_to_loopback() {
+    let url = |url| ScrobblerConfig::new(listenbrainz(), url).is_ok();
+
+    assert!(url("http://127.0.0.1:8080/submit"));
+    assert!(url("http://localhost/submit"));
+    assert!(url("http://[::1]:8080/submit"));
+    assert!(!url("http://api.listenbrainz.org/1/submit-listens"));
+    assert!(!url("http://192.168.1.2/submit"));
+    assert!(!url(ScrobblerService::endpoint(&listenbrainz())));
+    assert!(!url("not a url"));
+}
+
+#[test]
+#[ignore = "opens sockets through the reactor, which some sandboxes don't allow"]
+fn listen_is_submitted() {
+    let (url, requests) = serve(vec!["200 OK {}"]);
+    let mut client = client(listenbrainz(), &url);
+
+    assert_eq!(client.submit(&scrobble(1), false), Ok(()));
+    let body: serde_json::Value = serde_json::from_str(&requests.recv().unwrap()).unwrap();
+    assert_eq!(body["listen_type"], "single");
+    assert_eq!(body["payload"][0]["listened_at"], 1_600_000_001);
+    assert_eq!(
+        body["payload"][0]["track_metadata"]["track_name"],
+        "Track 1"
+    );
+}
+
+#[test]
+#[ignore = "opens sockets through the reactor, which some sandboxes don't allow"]
+fn lastfm_error_with_successful_status_fails() {
+    let (url, _requests) = serve(vec![
+        r#"200 OK {"error": 9, "message": "Invalid session key"}"#,
+    ]);
+    let service = ScrobblerService::LastFm {
+        api_key: String::from("key"),
+        api_secret: String::from("secret"),
+        session_key: String::from("session"),
+    };
+    let mut client = client(service, &url);
+
+    assert!(client.submit(&scrobble(1), false).is_err());
+}
+
+#[test]
+#[ignore = "opens sockets through the reactor, which some sandboxes don't allow"]
+fn queued_scrobbles_are_retried_in_order_until_one_fails() {
+    let cache = Cache::with_storage(Arc::new(MemoryStorage::new()), false);
+    save_pending_scrobbles(&cache, &[scrobble(1), scrobble(2), scrobble(3)]);
+    let (url, requests) = serve(vec!["200 OK {}", "200 OK {}", "503 Service Unavailable"]);
+    let mut client = client(listenbrainz(), &url);
+
+    client.submit_pending(&cache);
+
+    let tracks: Vec<String> = requests
+        .iter()
+        .map(|body| {
+            let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            body["payload"][0]["track_metadata"]["track_name"].to_string()
+        })
+        .collect();
+    assert_eq!(tracks, vec!["\"Track 1\"", "\"Track 2\"", "\"Track 3\""]);
+    assert_eq!(pending_scrobbles(&cache), vec![scrobble(3)]);
+}
+
+#[test]
+fn pending_scrobbles_are_empty_without_queue() {
+    let cache = Cache::with_storage(Arc::new(MemoryStorage::new()), false);
+
+    assert!(pending_scrobbles(&cache).is_empty());
+}
+
+fn listen(duration_ms: u32, played_ms: u64) -> Listen {
+    Listen {
+        track_id: SpotifyId::from_raw(&[1; 16]).unwrap(),
+        duration_ms,
+        timestamp: 1_600_000_000,
+        played_ms,
+        playing_since: None,
+    }
+}
+
+#[test]
+fn short_track_is_not_scrobbled() {
+    assert!(!listen(30_000, 30_000).is_scrobble());
+    assert!(listen(30_001, 15_000).is_scrobble());
+}
+
+#[test]
+fn track_is_scrobbled_after_half_of_it() {
+    assert!(!listen(180_000, 89_999).is_scrobble());
+    assert!(listen(180_000, 90_000).is_scrobble());
+}
+
+#[test]
+fn long_track_is_scrobbled_after_four_minutes() {
+    assert!(!listen(600_000, 239_999).is_scrobble());
+    assert!(listen(600_000, 240_000).is_scrobble());
+}